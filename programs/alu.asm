@@ -0,0 +1,17 @@
+; arithmetic heavy loop over signed and float registers: cargo run --release -- --bench 10 programs/alu.asm
+    MOV R0, 100000u32
+    MOV R1, 1i64
+    MOV R2, 7i64
+    MOV R4, 0.5f64
+    MOV R5, 1.0f64
+loop:
+    MUL R1, R1, R2
+    ADD R1, R1, 3i64
+    MOD R3, R1, 1000003i64
+    DIV R1, R3, 2i64
+    SAR R3, R3, 2u8
+    MAX R1, R1, R3
+    MUL R5, R5, R4
+    ADD R5, R5, 1.0f64
+    DJNZ R0, loop
+    HALT
@@ -0,0 +1,13 @@
+; three operand and register-immediate arithmetic
+    MOV R0, 6i32
+    MOV R1, 7i32
+    MUL R2, R0, R1      ; R2 = 42
+    SUB R3, R2, 2i32    ; R3 = 40
+    SHL R4, R3, 1u8     ; R4 = 80
+    ADD R4, R3          ; stack form, pushes 120
+    VPOP R5
+    PRINTR R2
+    PRINTR R3
+    PRINTR R4
+    PRINTR R5
+    HALT
@@ -0,0 +1,14 @@
+; prints the larger of R0 and R1
+    MOV R0, 10u8
+    MOV R1, 8u8
+    MOV R2, greater
+    MOV R3, otherwise
+    CMP R0, R1
+    JG R2
+    JMP R3
+greater:
+    PRINTR R0
+    HALT
+otherwise:
+    PRINTR R1
+    HALT
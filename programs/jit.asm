@@ -0,0 +1,35 @@
+; loop body the jit compiles, checked against the other modes: cargo run --release --features jit -- --bench 10 programs/jit.asm
+    MOV R0, 3000u32
+    MOV R1, 1i8
+    MOV R2, 7i16
+    MOV R3, 0x1234u16
+    MOV R4, 5i64
+    MOV R5, 1.5f64
+    MOV R6, 77u8
+    MOV R7, 0u8
+loop:
+    MUL R1, R1, 3i8
+    ADD R2, R2, -5i16
+    SAR R2, R2, 1u8
+    ROL R3, R3, 5u8
+    ROR R3, R3, 17u32
+    SHR R3, R3, 1u8
+    XOR R3, R3, 0xa5a5u16
+    MIN R2, R2, 1000i16
+    MAX R2, R2, -1000i16
+    DIV R4, R4, 3i64
+    MUL R4, R4, -7i64
+    ADD R4, R4, 11i64
+    MOD R4, R4, 1000003i64
+    MUL R5, R5, 1.0001f64
+    DIV R5, R5, 1.00005f64
+    ADD R7, R7, 37u8
+    CMP R7, R6
+    BGT skip
+    SUB R7, R7, 3u8
+skip:
+    CBLTI R7, 100u8, small
+    ROL R7, R7, 3u8
+small:
+    DJNZ R0, loop
+    HALT
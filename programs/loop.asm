@@ -0,0 +1,14 @@
+; tight counting loop used by the dispatch benchmarks: cargo run --release -- --bench 10 programs/loop.asm
+    MOV R0, 200000u32
+    MOV R1, 0u32
+    MOV R3, 7u32
+loop:
+    XOR R1, R1, R0
+    ROL R2, R1, 3u8
+    AND R2, R2, 0xffffu32
+    MIN R3, R3, R2
+    CBEQI R2, 0u32, skip
+    OR R1, R1, R3
+skip:
+    DJNZ R0, loop
+    HALT
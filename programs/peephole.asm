@@ -0,0 +1,35 @@
+; patterns the peephole optimizer rewrites: cargo run -- --optimize-test programs/peephole.asm
+    MOV R0, 10u16
+    MOV R1, 0u16
+    NOP
+    NOP
+top:
+    ADD R1, R0          ; ADD and VPOP become ADD3 R1, R1, R0
+    VPOP R1
+    MOV R2, 1u16
+    SUB R0, R2
+    VPOP R0
+    MOV R3, 0u16
+    CMP R0, R3
+    MOV R4, done        ; R4 only holds this target, so this pair is JEA done
+    JE R4
+    BRA next            ; jump to the next instruction
+next:
+    JMPA hop            ; jump to a jump goes straight to top
+hop:
+    BRA top
+done:
+    PRINTR R1
+    VPUSH 3u16
+    VPUSH 4u16
+    SCMP
+    VPUSH small         ; pushed target, this pair is JLEA small
+    SJLE
+    NOP
+    HALT
+small:
+    MOV R6, 1u8
+    SAR R1, R6          ; SARR and VPOP become SAR3 R5, R1, R6
+    VPOP R5
+    PRINTR R5
+    NOP
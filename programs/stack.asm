@@ -0,0 +1,24 @@
+; evaluates (3 + 4) * 5 - 2 using only the operand stack, then
+; stores 33 at [0] if the result is greater than 30, otherwise 0
+    VPUSH 3i32
+    VPUSH 4i32
+    SADD
+    VPUSH 5i32
+    SMUL
+    VPUSH 2i32
+    SSUB
+    SSTORE [1]
+    VLOAD [1]
+    VPUSH 30i32
+    SCMP
+    VPUSH big
+    SJG
+    VPUSH 0i32
+    SSTORE [0]
+    HALT
+big:
+    VPUSH 33i32
+    SSTORE [0]
+    PRINTV [1]
+    PRINTV [0]
+    HALT
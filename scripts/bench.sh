@@ -0,0 +1,35 @@
+#!/bin/sh
+# Compares dispatch times of two revisions over every program in programs/.
+#
+# Builds BASE, any commit, branch or tag, in a temporary worktree and the working tree, both in
+# release mode, and runs `--bench RUNS` (default 10) on each program with both. A program the
+# older build can't assemble is reported and skipped for it.
+#
+#   scripts/bench.sh BASE [RUNS]
+set -e
+
+if [ -z "$1" ]; then
+    echo "usage: scripts/bench.sh BASE [RUNS]" >&2
+    exit 1
+fi
+base=$1
+runs=${2:-10}
+root=$(git rev-parse --show-toplevel)
+work=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$work/base" >/dev/null 2>&1; rm -rf "$work"' EXIT
+
+git -C "$root" worktree add --detach "$work/base" "$base" >/dev/null 2>&1
+cargo build --release --quiet --manifest-path "$work/base/Cargo.toml" --target-dir "$work/target"
+cargo build --release --quiet --manifest-path "$root/Cargo.toml"
+
+for program in "$root"/programs/*.asm; do
+    echo "== $(basename "$program")"
+    for build in "before:$work/target/release/smallvm" "after:$root/target/release/smallvm"; do
+        name=${build%%:*}
+        if out=$("${build#*:}" --bench "$runs" "$program" 2>&1); then
+            echo "$out" | grep -E 'per run|differs' | sed "s/^/$name /"
+        else
+            echo "$name: can't run this program"
+        fi
+    done
+done
@@ -0,0 +1,300 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::process::{self, Command};
+use crate::assembler::DebugInfo;
+use crate::{or_exit, Address, Immediate, Instruction, RegisterFile, VirtualMachine};
+use crate::threaded::{register_alu, stack_alu, Source};
+
+//Ahead-of-time translation to Rust.
+//
+//A verified program is translated to a standalone Rust source file: the same registers, flags,
+//stack and heap, and a loop over a `match` on ip with one arm per code offset. Every arm does
+//what `execute` does for the instruction decoded at that offset, with the same values, helpers
+//and `Word` ALU (the file embeds src/runtime.rs and src/word.rs), so values keep their types and
+//the program prints, halts and fails exactly as the interpreter does with the trace off. Faults
+//name the same source locations, from a table built out of the program's debug info. Offsets in
+//the middle of an instruction get arms too, in case a register or stack jump lands there, and an
+//offset that doesn't decode fails with the decode fault the interpreter would report. CWRITE
+//can't be translated.
+
+//the machine of a translated program. Values, faults and the instruction helpers come from the
+//embedded src/runtime.rs, the ALU from src/word.rs
+const MACHINE: &str = r##"struct Machine {
+    ip: Address,
+    flag_eq: bool,
+    flag_gt: bool,
+    registers: RegisterFile,
+    reg: Vec<Word>,
+    stack: Vec<Immediate>,
+    data: Vec<Immediate>,
+    running: bool,
+    fault: Option<Fault>,
+}
+
+impl Machine {
+    fn new(registers: RegisterFile, heap_capacity: usize) -> Self {
+        let reg = (0..registers.count).map(|r| Word::from(registers.initial(r))).collect();
+        Machine { ip: 0, flag_eq: false, flag_gt: false, registers, reg, stack: Vec::new(), data: vec![Immediate::U8(0); heap_capacity], running: true, fault: None }
+    }
+
+    helpers!();
+}
+"##;
+
+//Rust expression for an immediate, floats by their bits so NaN and infinities survive
+fn immediate(v: Immediate) -> String {
+    match v {
+        Immediate::None() => "Immediate::None()".to_string(),
+        Immediate::F32(f) => format!("Immediate::F32(f32::from_bits({:#x}))", f.to_bits()),
+        Immediate::F64(f) => format!("Immediate::F64(f64::from_bits({:#x}))", f.to_bits()),
+        v => format!("Immediate::{:?}", v),
+    }
+}
+
+fn operand(rhs: Source) -> String {
+    match rhs {
+        Source::Register(reg) => format!("m.reg[{}]", reg),
+        Source::Word(word) => format!("Word::from({})", immediate(word.value())),
+    }
+}
+
+fn value(reg: usize) -> String {
+    format!("m.reg[{}].value()", reg)
+}
+
+//Rust expression running one instruction, false when it fails. None for CWRITE
+fn statement(instr: &Instruction) -> Option<String> {
+    if let Some((op, dst, reg1, rhs)) = register_alu(instr) {
+        return Some(format!("m.store_binary(BinaryOp::{:?}, {}, m.reg[{}], {})", op, dst, reg1, operand(rhs)));
+    }
+    if let Some((op, lhs, rhs)) = stack_alu(instr) {
+        return Some(format!("m.push_binary(BinaryOp::{:?}, {}, {})", op, operand(lhs), operand(rhs)));
+    }
+    let cmov = |condition: &str, reg1: usize, reg2: usize| format!("!({}) || m.set_reg({}, {})", condition, reg1, value(reg2));
+    let branch = |condition: &str, offset: isize| format!("!({}) || m.branch({})", condition, offset);
+    let goto = |condition: &str, addr: Address| format!("{{ if {} {{ m.ip = {}; }} true }}", condition, addr);
+    let compare_branch = |v1: String, v2: String, offset: isize, holds: &str| format!("m.compare_branch({}, {}, {}, |a, b| a {} b)", v1, v2, offset, holds);
+    let unary = |function: &str, reg: usize| format!("m.push({}({}))", function, value(reg));
+    let float = |name: &str, reg: usize| format!("m.push(float({}, f32::{}, f64::{}))", value(reg), name, name);
+    let integer = |method: &str, reg: usize| format!("m.push(integer!({}, |x| x.{}()))", value(reg), method);
+    let bit = |reg1: usize, reg2: usize, result: &str, wrap: &str| format!("bit!({}, {}, |x, bit| {}, {})", value(reg1), value(reg2), result, wrap);
+    Some(match *instr {
+        Instruction::NOP() => "true".to_string(),
+        Instruction::MOV(reg, var) => format!("m.set_reg({}, {})", reg, immediate(var)),
+        Instruction::MOVR(reg1, reg2) => format!("m.set_reg({}, {})", reg1, value(reg2)),
+        Instruction::JMP(reg) => format!("m.jump({})", value(reg)),
+        Instruction::JE(reg) => format!("!m.flag_eq || m.jump({})", value(reg)),
+        Instruction::JNE(reg) => format!("m.flag_eq || m.jump({})", value(reg)),
+        Instruction::JG(reg) => format!("!m.flag_gt || m.jump({})", value(reg)),
+        Instruction::JL(reg) => format!("m.flag_gt || m.jump({})", value(reg)),
+        Instruction::CMP(reg1, reg2) => format!("m.compare({}, {})", value(reg1), value(reg2)),
+        Instruction::PRINTR(reg) => format!("{{ println!(\"Printing: {{:?}}\", {}); true }}", value(reg)),
+        Instruction::PRINTV(addr) => format!("{{ println!(\"Printing: {{:?}}\", m.data[{}]); true }}", addr),
+        Instruction::VSTORE(addr, var) => format!("{{ m.data[{}] = {}; true }}", addr, immediate(var)),
+        Instruction::VLOAD(addr) => format!("m.push(Ok(m.data[{}]))", addr),
+        Instruction::VSTORER(addr, reg) => format!("{{ m.data[{}] = {}; true }}", addr, value(reg)),
+        Instruction::VLOADR(reg, addr) => format!("m.set_reg({}, m.data[{}])", reg, addr),
+        Instruction::VPUSH(var) => format!("m.push(Ok({}))", immediate(var)),
+        Instruction::VPUSHR(reg) => format!("m.push(Ok({}))", value(reg)),
+        Instruction::VPOP(reg) => format!("match m.pop() {{ Some(v) => m.set_reg({}, v), None => false }}", reg),
+        Instruction::CALL(reg) => format!("{{ m.stack.push(Immediate::U16((m.ip as u16).wrapping_sub(1))); m.jump({}) }}", value(reg)),
+        Instruction::RET() => "match m.pop() { Some(v) => m.jump(v), None => false }".to_string(),
+        Instruction::HALT() => "{ m.running = false; true }".to_string(),
+        Instruction::NEG(reg) => unary("negate", reg),
+        Instruction::NOT(reg) => format!("m.push(integer!({}, |x| !x))", value(reg)),
+        Instruction::ABS(reg) => unary("absolute", reg),
+        Instruction::SQRT(reg) => float("sqrt", reg),
+        Instruction::FLOOR(reg) => float("floor", reg),
+        Instruction::CEIL(reg) => float("ceil", reg),
+        Instruction::ROUND(reg) => float("round", reg),
+        Instruction::TRUNC(reg) => float("trunc", reg),
+        Instruction::FMA(reg1, reg2, reg3) => format!("m.push(fma({}, {}, {}))", value(reg1), value(reg2), value(reg3)),
+        Instruction::SIN(reg) => float("sin", reg),
+        Instruction::COS(reg) => float("cos", reg),
+        Instruction::TAN(reg) => float("tan", reg),
+        Instruction::EXP(reg) => float("exp", reg),
+        Instruction::LOG(reg) => float("ln", reg),
+        Instruction::POW(reg1, reg2) => format!("m.push(float_pair({}, {}, f32::powf, f64::powf))", value(reg1), value(reg2)),
+        Instruction::COPYSIGN(reg1, reg2) => format!("m.push(float_pair({}, {}, f32::copysign, f64::copysign))", value(reg1), value(reg2)),
+        Instruction::ISNAN(reg) => format!("m.test(float_test({}, f32::is_nan, f64::is_nan))", value(reg)),
+        Instruction::ISINF(reg) => format!("m.test(float_test({}, f32::is_infinite, f64::is_infinite))", value(reg)),
+        Instruction::POPCNT(reg) => integer("count_ones", reg),
+        Instruction::CLZ(reg) => integer("leading_zeros", reg),
+        Instruction::CTZ(reg) => integer("trailing_zeros", reg),
+        Instruction::BSWAP(reg) => integer("swap_bytes", reg),
+        Instruction::BT(reg1, reg2) => format!("m.test({})", bit(reg1, reg2, "x & bit != 0", "test")),
+        Instruction::BTS(reg1, reg2) => format!("m.push({})", bit(reg1, reg2, "x | bit", "value")),
+        Instruction::BTR(reg1, reg2) => format!("m.push({})", bit(reg1, reg2, "x & !bit", "value")),
+        Instruction::SADD() => "m.pop_binary(BinaryOp::Add)".to_string(),
+        Instruction::SSUB() => "m.pop_binary(BinaryOp::Sub)".to_string(),
+        Instruction::SMUL() => "m.pop_binary(BinaryOp::Mul)".to_string(),
+        Instruction::SDIV() => "m.pop_binary(BinaryOp::Div)".to_string(),
+        Instruction::SMOD() => "m.pop_binary(BinaryOp::Mod)".to_string(),
+        Instruction::SAND() => "m.pop_binary(BinaryOp::And)".to_string(),
+        Instruction::SOR() => "m.pop_binary(BinaryOp::Or)".to_string(),
+        Instruction::SXOR() => "m.pop_binary(BinaryOp::Xor)".to_string(),
+        Instruction::SMIN() => "m.pop_binary(BinaryOp::Min)".to_string(),
+        Instruction::SMAX() => "m.pop_binary(BinaryOp::Max)".to_string(),
+        Instruction::SSHR() => "m.pop_binary(BinaryOp::Shr)".to_string(),
+        Instruction::SSHL() => "m.pop_binary(BinaryOp::Shl)".to_string(),
+        Instruction::SSAR() => "m.pop_binary(BinaryOp::Sar)".to_string(),
+        Instruction::SROL() => "m.pop_binary(BinaryOp::Rol)".to_string(),
+        Instruction::SROR() => "m.pop_binary(BinaryOp::Ror)".to_string(),
+        Instruction::SCMP() => "match m.pop_operands() { Some((v1, v2)) => m.compare(v1, v2), None => m.fault(Fault::StackUnderflow) }".to_string(),
+        Instruction::SJMP() => "m.pop_jump(true)".to_string(),
+        Instruction::SJE() => "m.pop_jump(m.flag_eq)".to_string(),
+        Instruction::SJNE() => "m.pop_jump(!m.flag_eq)".to_string(),
+        Instruction::SJG() => "m.pop_jump(m.flag_gt)".to_string(),
+        Instruction::SJLE() => "m.pop_jump(!m.flag_gt)".to_string(),
+        Instruction::SSTORE(addr) => format!("match m.pop() {{ Some(v) => {{ m.data[{}] = v; true }}, None => false }}", addr),
+        Instruction::DUP() => "m.stack_item(0, false)".to_string(),
+        Instruction::DROP() => "m.pop().is_some()".to_string(),
+        Instruction::SWAP() => "m.stack_item(1, true)".to_string(),
+        Instruction::OVER() => "m.stack_item(1, false)".to_string(),
+        Instruction::ROT() => "m.stack_item(2, true)".to_string(),
+        Instruction::PICK(n) => format!("m.stack_item({}, false)", n),
+        Instruction::ROLL(n) => format!("m.stack_item({}, true)", n),
+        Instruction::DEPTH() => "m.push(Ok(Immediate::U64(m.stack.len() as u64)))".to_string(),
+        Instruction::JMPA(addr) => goto("true", addr),
+        Instruction::JEA(addr) => goto("m.flag_eq", addr),
+        Instruction::JNEA(addr) => goto("!m.flag_eq", addr),
+        Instruction::JGA(addr) => goto("m.flag_gt", addr),
+        Instruction::JLEA(addr) => goto("!m.flag_gt", addr),
+        Instruction::CALLA(addr) => format!("{{ m.stack.push(Immediate::U16((m.ip as u16).wrapping_sub(1))); m.ip = {}; true }}", addr),
+        Instruction::BRA(offset) => format!("m.branch({})", offset),
+        Instruction::BEQ(offset) => branch("m.flag_eq", offset),
+        Instruction::BNE(offset) => branch("!m.flag_eq", offset),
+        Instruction::BGT(offset) => branch("m.flag_gt", offset),
+        Instruction::BLE(offset) => branch("!m.flag_gt", offset),
+        Instruction::BSR(offset) => format!("{{ m.stack.push(Immediate::U16((m.ip as u16).wrapping_sub(1))); m.branch({}) }}", offset),
+        Instruction::CBEQ(reg1, reg2, offset) => compare_branch(value(reg1), value(reg2), offset, "=="),
+        Instruction::CBNE(reg1, reg2, offset) => compare_branch(value(reg1), value(reg2), offset, "!="),
+        Instruction::CBGT(reg1, reg2, offset) => compare_branch(value(reg1), value(reg2), offset, ">"),
+        Instruction::CBLT(reg1, reg2, offset) => compare_branch(value(reg1), value(reg2), offset, "<"),
+        Instruction::CBEQI(reg, var, offset) => compare_branch(value(reg), immediate(var), offset, "=="),
+        Instruction::CBNEI(reg, var, offset) => compare_branch(value(reg), immediate(var), offset, "!="),
+        Instruction::CBGTI(reg, var, offset) => compare_branch(value(reg), immediate(var), offset, ">"),
+        Instruction::CBLTI(reg, var, offset) => compare_branch(value(reg), immediate(var), offset, "<"),
+        Instruction::DJNZ(reg, offset) => format!("m.count_down({}, {})", reg, offset),
+        Instruction::SWITCH(reg, default, ref targets) => format!("m.switch({}, {}, &{:?})", reg, default, targets),
+        Instruction::CMOVE(reg1, reg2) => cmov("m.flag_eq", reg1, reg2),
+        Instruction::CMOVNE(reg1, reg2) => cmov("!m.flag_eq", reg1, reg2),
+        Instruction::CMOVG(reg1, reg2) => cmov("m.flag_gt", reg1, reg2),
+        Instruction::CMOVL(reg1, reg2) => cmov("!m.flag_gt && !m.flag_eq", reg1, reg2),
+        Instruction::CMOVGE(reg1, reg2) => cmov("m.flag_gt || m.flag_eq", reg1, reg2),
+        Instruction::CMOVLE(reg1, reg2) => cmov("!m.flag_gt", reg1, reg2),
+        Instruction::SELECT(dst, cond, reg1, reg2) => format!("{{ let v = if {}.is_true() {{ {} }} else {{ {} }}; m.set_reg({}, v) }}", value(cond), value(reg1), value(reg2), dst),
+        _ => return None,
+    })
+}
+
+impl VirtualMachine {
+    //translates the program to the source of a standalone Rust program. Fails if the program
+    //doesn't pass verification or an instruction on its straight-line decode can't be translated
+    pub(crate) fn translate(&mut self) -> Result<String, String> {
+        self.verified()?;
+        let mut starts = vec![false; self.code.len()];
+        let mut start = 0;
+        while start < self.code.len() {
+            starts[start] = true;
+            match self.decode_at(start) {
+                Ok((_, next)) => start = next,
+                Err(_) => break,
+            }
+        }
+
+        let mut out = String::from("//Translated from smallvm bytecode. Build with: rustc --edition 2021 -O <file>\n");
+        out.push_str("#![allow(dead_code, unused_macros, unused_parens, clippy::all)]\n\ntype Register = usize;\ntype Address = usize;\ntype Offset = isize;\n\n");
+        out.push_str("#[macro_use]\nmod runtime {\n");
+        out.push_str(include_str!("runtime.rs"));
+        out.push_str("}\nuse runtime::*;\n\nmod word {\n");
+        out.push_str(include_str!("word.rs"));
+        out.push_str("}\nuse word::Word;\n\n");
+        out.push_str(MACHINE);
+        out.push_str("\nfn main() {\n");
+        out.push_str(&format!("    let mut m = Machine::new(RegisterFile::banked({}, {}), {});\n", self.registers.count - self.registers.floats, self.registers.floats, self.data.len()));
+        out.push_str("    while m.running {\n        let start = m.ip;\n        let ok = match start {\n");
+        for (offset, &start) in starts.iter().enumerate() {
+            let arm = match self.decode_at(offset) {
+                Ok((instr, next)) => match statement(&instr) {
+                    Some(code) => format!("{{ m.ip = {}; {} }}", next, code),
+                    None if start => return Err(format!("{:?} at ip:{} can't be translated", instr, offset)),
+                    None => format!("panic!(\"{{}}\", {:?})", format!("{:?} at ip:{} can't be translated{}", instr, offset, self.debug.locate(offset))),
+                },
+                Err(fault) => format!("panic!(\"{{}}\", {:?})", format!("{} at ip:{:?}{}", fault, offset, self.debug.locate(offset))),
+            };
+            out.push_str(&format!("            {} => {},\n", offset, arm));
+        }
+        out.push_str("            _ => break,\n        };\n        if !ok {\n            match m.fault.take() {\n");
+        out.push_str("                Some(fault) => panic!(\"{} at ip:{:?}{}\", fault, start, locate(start)),\n                None => panic!(\"Failed to execute instruction at ip:{:?}{}\", start, locate(start)),\n");
+        out.push_str("            }\n        }\n    }\n}\n");
+        //the source location of each instruction, as the interpreter prints it after a fault
+        out.push_str("\nfn locate(ip: usize) -> &'static str {\n    match ip {\n");
+        for &offset in self.debug.lines.keys() {
+            out.push_str(&format!("        {} => {:?},\n", offset, self.debug.locate(offset)));
+        }
+        out.push_str("        _ => \"\",\n    }\n}\n");
+        Ok(out)
+    }
+}
+
+//writes the translation of a program to `path`, with `debug` giving its faults source locations
+pub fn write(code: &[u8], debug: DebugInfo, heap_capacity: usize, registers: RegisterFile, path: &str) {
+    let mut vm = VirtualMachine::new(code.to_vec(), heap_capacity).with_registers(registers).with_debug_info(debug);
+    let source = or_exit(vm.translate());
+    if let Err(e) = fs::write(path, source) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+//runs the interpreter on the program read from `source` with the trace off, passing `options`
+pub(crate) fn interpret(registers: RegisterFile, source: Option<&str>, options: &[&str]) -> io::Result<process::Output> {
+    Command::new(env::current_exe()?).arg("--quiet").arg("--registers").arg((registers.count - registers.floats).to_string())
+        .arg("--float-registers").arg(registers.floats.to_string()).args(options).args(source).output()
+}
+
+//what a run printed, and the message it failed with if it did
+pub(crate) fn outcome(output: process::Output) -> (String, Option<String>) {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lines = stderr.lines();
+    let panic = lines.by_ref().find(|line| line.contains(" panicked at ")).and_then(|_| lines.next()).map(str::to_string);
+    (stdout, panic)
+}
+
+//translates the program read from `source`, builds it with rustc and checks that it prints and fails
+//the same as the interpreter does
+pub fn check(code: &[u8], debug: DebugInfo, heap_capacity: usize, registers: RegisterFile, source: Option<&str>) {
+    let dir = env::temp_dir().join(format!("smallvm-aot-{}", process::id()));
+    let (rust, binary) = (dir.join("main.rs"), dir.join("main"));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("{}: {}", dir.display(), e);
+        process::exit(1);
+    }
+    write(code, debug, heap_capacity, registers, &rust.to_string_lossy());
+    let built = Command::new("rustc").args(["--edition", "2021", "-O", "-o"]).arg(&binary).arg(&rust).status();
+    if !matches!(built, Ok(status) if status.success()) {
+        eprintln!("failed to build {}", rust.display());
+        process::exit(1);
+    }
+    let interpreted = interpret(registers, source, &[]);
+    let compiled = Command::new(&binary).output();
+    let _ = fs::remove_dir_all(&dir);
+    let (interpreted, compiled) = match (interpreted, compiled) {
+        (Ok(i), Ok(c)) => (outcome(i), outcome(c)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("failed to run: {}", e);
+            process::exit(1);
+        }
+    };
+    if interpreted == compiled {
+        println!("translation matches the interpreter");
+        return;
+    }
+    println!("translation differs from the interpreter");
+    println!("interpreter: {:?}", interpreted);
+    println!("translation: {:?}", compiled);
+    process::exit(1);
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use crate::{code_location, code_target, Address, Immediate, Instruction, Offset, Register};
+
+//Text assembler for the VM bytecode.
+//
+//One instruction per line, operands separated by commas, `;` starts a comment:
+//
+//  start:                  label, usable wherever an immediate is expected (encoded as u16)
+//      MOV R0, 10u8        registers are R0..R255, immediates need a type suffix (u8..i64, f32, f64)
+//      ADD R0, R1          stack form, pushes the result
+//      ADD R2, R0, R1      three register form, R2 = R0 + R1
+//      ADD R2, R0, 5u8     register-immediate form, R2 = R0 + 5
+//      VSTORE [3], 1.5f32  heap addresses are written in brackets
+//      MOV R3, start
+//      JMP R3
+
+#[derive(Debug)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(Register),
+    Address(Address),
+    Immediate(Immediate),
+    Label(String),
+    //the code offset of a label
+    Location(Address),
+}
+
+struct Line {
+    number: usize,
+    column: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    //called with CALLA or BSR, or moved into a register that a CALL later uses
+    pub function: bool,
+}
+
+//code offsets to the label defined there, the first one when there are several
+pub type Symbols = BTreeMap<Address, Symbol>;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+//where the code came from
+#[derive(Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub file: String,
+    pub symbols: Symbols,
+    //the offset of each instruction to where its mnemonic is in the source
+    pub lines: BTreeMap<Address, Location>,
+}
+
+impl DebugInfo {
+    //the label at `addr`, or the nearest one before it with the distance
+    pub fn label(&self, addr: Address) -> Option<String> {
+        match self.symbols.range(..=addr).next_back() {
+            Some((&at, symbol)) if at == addr => Some(symbol.name.clone()),
+            Some((&at, symbol)) => Some(format!("{}+{}", symbol.name, addr - at)),
+            None => None,
+        }
+    }
+
+    //the source location of the instruction at `addr` and the function it is in, as a line to go
+    //under a message about it. Empty without debug info
+    pub fn locate(&self, addr: Address) -> String {
+        let location = match self.lines.get(&addr) {
+            Some(location) => location,
+            None => return String::new(),
+        };
+        let function = self.symbols.range(..=addr).rev().find(|(_, symbol)| symbol.function);
+        match function {
+            Some((_, symbol)) => format!("\n  --> {}:{}:{}, in {}", self.file, location.line, location.column, symbol.name),
+            None => format!("\n  --> {}:{}:{}", self.file, location.line, location.column),
+        }
+    }
+}
+
+//assembles a program read from `file`, returning the code and its debug info
+pub fn assemble(source: &str, file: &str) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    let mut labels = HashMap::new();
+    let mut debug = DebugInfo { file: file.to_string(), ..DebugInfo::default() };
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    //first pass: parse every line and lay out the labels
+    for (i, original) in source.lines().enumerate() {
+        let number = i + 1;
+        let error = |message: String| AssembleError { line: number, message };
+        let mut text = match original.find(';') {
+            Some(c) => &original[..c],
+            None => original,
+        }.trim();
+
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), offset).is_some() {
+                return Err(error(format!("label `{}` defined twice", label)));
+            }
+            debug.symbols.entry(offset).or_insert_with(|| Symbol { name: label.to_string(), function: false });
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(s) => (&text[..s], text[s..].trim()),
+            None => (text, ""),
+        };
+        let mut operands = Vec::new();
+        if !rest.is_empty() {
+            for op in rest.split(',') {
+                operands.push(parse_operand(op.trim()).map_err(error)?);
+            }
+        }
+        //the mnemonic's place in the line, which `text` is a slice of
+        let column = original[..text.as_ptr() as usize - original.as_ptr() as usize].chars().count() + 1;
+        let line = Line { number, column, mnemonic: mnemonic.to_uppercase(), operands };
+
+        //labels are always encoded as u16, so a placeholder gives the final size
+        let instr = build(&line.mnemonic, &resolve(&line.operands, None, offset), offset).map_err(error)?;
+        let mut bytes = Vec::new();
+        instr.encode(&mut bytes);
+        offset += bytes.len();
+        lines.push(line);
+    }
+
+    //second pass: encode with the label addresses filled in
+    let mut code = Vec::new();
+    let mut functions = Vec::new();
+    //labels last moved into each register, for finding functions called through one
+    let mut moved = HashMap::new();
+    for line in lines {
+        let error = |message: String| AssembleError { line: line.number, message };
+        for op in &line.operands {
+            if let Operand::Label(name) = op {
+                match labels.get(name) {
+                    Some(&addr) if addr <= u16::MAX as usize => {},
+                    Some(_) => return Err(error(format!("label `{}` is out of u16 range", name))),
+                    None => return Err(error(format!("undefined label `{}`", name))),
+                }
+            }
+        }
+        let here = code.len();
+        let instr = build(&line.mnemonic, &resolve(&line.operands, Some(&labels), here), here).map_err(error)?;
+        debug.lines.insert(here, Location { line: line.number, column: line.column });
+        instr.encode(&mut code);
+        match instr {
+            Instruction::CALLA(addr) => functions.push(addr),
+            Instruction::BSR(offset) => functions.push((code.len() as Offset + offset) as Address),
+            Instruction::CALL(reg) => functions.extend(moved.get(&reg)),
+            Instruction::MOV(reg, _) => match line.operands.get(1) {
+                Some(Operand::Label(name)) => {
+                    moved.insert(reg, labels[name]);
+                },
+                _ => {
+                    moved.remove(&reg);
+                },
+            },
+            _ => {},
+        }
+    }
+    for addr in functions {
+        if let Some(symbol) = debug.symbols.get_mut(&addr) {
+            symbol.function = true;
+        }
+    }
+    Ok((code, debug))
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+//replaces labels with their code offsets, or the current offset as a placeholder when the
+//addresses aren't known yet
+fn resolve(operands: &[Operand], labels: Option<&HashMap<String, usize>>, here: usize) -> Vec<Operand> {
+    operands.iter().map(|op| match op {
+        Operand::Label(name) => Operand::Location(labels.and_then(|l| l.get(name)).copied().unwrap_or(here)),
+        _ => op.clone(),
+    }).collect()
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if text.is_empty() {
+        return Err("missing operand".to_string());
+    }
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = inner.strip_suffix(']').ok_or_else(|| format!("unterminated address `{}`", text))?;
+        return match inner.trim().parse::<u8>() {
+            Ok(a) => Ok(Operand::Address(a as Address)),
+            Err(_) => Err(format!("invalid heap address `{}`", text)),
+        };
+    }
+    if let Some(n) = text.strip_prefix('R').or_else(|| text.strip_prefix('r')) {
+        if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) {
+            return match n.parse::<u8>() {
+                Ok(r) => Ok(Operand::Register(r as Register)),
+                Err(_) => Err(format!("invalid register `{}`", text)),
+            };
+        }
+    }
+    if is_identifier(text) {
+        return Ok(Operand::Label(text.to_string()));
+    }
+    parse_immediate(text).map(Operand::Immediate)
+}
+
+fn parse_immediate(text: &str) -> Result<Immediate, String> {
+    let invalid = || format!("invalid immediate `{}`", text);
+    const SUFFIXES: [&str; 10] = ["u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "f32", "f64"];
+    let suffix = SUFFIXES.iter()
+        .filter(|s| text.ends_with(*s))
+        .max_by_key(|s| s.len())
+        .ok_or_else(|| format!("immediate `{}` needs a type suffix (e.g. 10u8, 1.5f32)", text))?;
+    let value = &text[..text.len() - suffix.len()];
+
+    if suffix.starts_with('f') {
+        return match *suffix {
+            "f32" => value.parse::<f32>().map(Immediate::F32).map_err(|_| invalid()),
+            _ => value.parse::<f64>().map(Immediate::F64).map_err(|_| invalid()),
+        };
+    }
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, value),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => digits.parse::<i128>(),
+    }.map_err(|_| invalid())?;
+    let v = if negative { -magnitude } else { magnitude };
+    let out_of_range = |_| format!("immediate `{}` is out of range", text);
+    Ok(match *suffix {
+        "u8" => Immediate::U8(v.try_into().map_err(out_of_range)?),
+        "i8" => Immediate::I8(v.try_into().map_err(out_of_range)?),
+        "u16" => Immediate::U16(v.try_into().map_err(out_of_range)?),
+        "i16" => Immediate::I16(v.try_into().map_err(out_of_range)?),
+        "u32" => Immediate::U32(v.try_into().map_err(out_of_range)?),
+        "i32" => Immediate::I32(v.try_into().map_err(out_of_range)?),
+        "u64" => Immediate::U64(v.try_into().map_err(out_of_range)?),
+        _ => Immediate::I64(v.try_into().map_err(out_of_range)?),
+    })
+}
+
+type RegisterForm = fn(Register) -> Instruction;
+type RegisterRegisterForm = fn(Register, Register) -> Instruction;
+type RegisterImmediateForm = fn(Register, Immediate) -> Instruction;
+type ThreeRegisterForm = fn(Register, Register, Register) -> Instruction;
+type DestImmediateForm = fn(Register, Register, Immediate) -> Instruction;
+type AddressForm = fn(Address) -> Instruction;
+type OffsetForm = fn(Offset) -> Instruction;
+
+//ALU mnemonics with a stack form, a three register form and a register-immediate form
+fn alu_forms(name: &str) -> Option<(RegisterRegisterForm, ThreeRegisterForm, DestImmediateForm)> {
+    Some(match name {
+        "ADD" => (Instruction::ADD, Instruction::ADD3, Instruction::ADDI),
+        "SUB" => (Instruction::SUB, Instruction::SUB3, Instruction::SUBI),
+        "MUL" => (Instruction::MUL, Instruction::MUL3, Instruction::MULI),
+        "DIV" => (Instruction::DIV, Instruction::DIV3, Instruction::DIVI),
+        "MOD" => (Instruction::MOD, Instruction::MOD3, Instruction::MODI),
+        "AND" => (Instruction::AND, Instruction::AND3, Instruction::ANDI),
+        "OR" => (Instruction::OR, Instruction::OR3, Instruction::ORI),
+        "XOR" => (Instruction::XOR, Instruction::XOR3, Instruction::XORI),
+        "MIN" => (Instruction::MIN, Instruction::MIN3, Instruction::MINI),
+        "MAX" => (Instruction::MAX, Instruction::MAX3, Instruction::MAXI),
+        _ => return None,
+    })
+}
+
+//shift mnemonics: stack form by immediate, stack form by register, three register, register-immediate
+fn shift_forms(name: &str) -> Option<(RegisterImmediateForm, RegisterRegisterForm, ThreeRegisterForm, DestImmediateForm)> {
+    Some(match name {
+        "SHR" => (Instruction::SHR, Instruction::SHRR, Instruction::SHR3, Instruction::SHRI),
+        "SHL" => (Instruction::SHL, Instruction::SHLR, Instruction::SHL3, Instruction::SHLI),
+        "SAR" => (Instruction::SAR, Instruction::SARR, Instruction::SAR3, Instruction::SARI),
+        "ROL" => (Instruction::ROL, Instruction::ROLR, Instruction::ROL3, Instruction::ROLI),
+        "ROR" => (Instruction::ROR, Instruction::RORR, Instruction::ROR3, Instruction::RORI),
+        _ => return None,
+    })
+}
+
+fn no_operand_form(name: &str) -> Option<Instruction> {
+    Some(match name {
+        "NOP" => Instruction::NOP(),
+        "RET" => Instruction::RET(),
+        "HALT" => Instruction::HALT(),
+        "SADD" => Instruction::SADD(),
+        "SSUB" => Instruction::SSUB(),
+        "SMUL" => Instruction::SMUL(),
+        "SDIV" => Instruction::SDIV(),
+        "SMOD" => Instruction::SMOD(),
+        "SAND" => Instruction::SAND(),
+        "SOR" => Instruction::SOR(),
+        "SXOR" => Instruction::SXOR(),
+        "SMIN" => Instruction::SMIN(),
+        "SMAX" => Instruction::SMAX(),
+        "SSHR" => Instruction::SSHR(),
+        "SSHL" => Instruction::SSHL(),
+        "SSAR" => Instruction::SSAR(),
+        "SROL" => Instruction::SROL(),
+        "SROR" => Instruction::SROR(),
+        "SCMP" => Instruction::SCMP(),
+        "SJMP" => Instruction::SJMP(),
+        "SJE" => Instruction::SJE(),
+        "SJNE" => Instruction::SJNE(),
+        "SJG" => Instruction::SJG(),
+        "SJLE" | "SJL" => Instruction::SJLE(),
+        "DUP" => Instruction::DUP(),
+        "DROP" => Instruction::DROP(),
+        "SWAP" => Instruction::SWAP(),
+        "OVER" => Instruction::OVER(),
+        "ROT" => Instruction::ROT(),
+        "DEPTH" => Instruction::DEPTH(),
+        _ => return None,
+    })
+}
+
+fn register_form(name: &str) -> Option<RegisterForm> {
+    Some(match name {
+        "JMP" => Instruction::JMP,
+        "JE" => Instruction::JE,
+        "JNE" => Instruction::JNE,
+        "JG" => Instruction::JG,
+        "JL" => Instruction::JL,
+        "PRINTR" => Instruction::PRINTR,
+        "VPUSHR" | "VPUSH" => Instruction::VPUSHR,
+        "VPOP" => Instruction::VPOP,
+        "CALL" => Instruction::CALL,
+        "NEG" => Instruction::NEG,
+        "NOT" => Instruction::NOT,
+        "ABS" => Instruction::ABS,
+        "SQRT" => Instruction::SQRT,
+        "FLOOR" => Instruction::FLOOR,
+        "CEIL" => Instruction::CEIL,
+        "ROUND" => Instruction::ROUND,
+        "TRUNC" => Instruction::TRUNC,
+        "SIN" => Instruction::SIN,
+        "COS" => Instruction::COS,
+        "TAN" => Instruction::TAN,
+        "EXP" => Instruction::EXP,
+        "LOG" => Instruction::LOG,
+        "ISNAN" => Instruction::ISNAN,
+        "ISINF" => Instruction::ISINF,
+        "POPCNT" => Instruction::POPCNT,
+        "CLZ" => Instruction::CLZ,
+        "CTZ" => Instruction::CTZ,
+        "BSWAP" => Instruction::BSWAP,
+        _ => return None,
+    })
+}
+
+fn register_register_form(name: &str) -> Option<RegisterRegisterForm> {
+    Some(match name {
+        "MOVR" | "MOV" => Instruction::MOVR,
+        "CMP" => Instruction::CMP,
+        "POW" => Instruction::POW,
+        "COPYSIGN" => Instruction::COPYSIGN,
+        "BT" => Instruction::BT,
+        "BTS" => Instruction::BTS,
+        "BTR" => Instruction::BTR,
+        "SHRR" => Instruction::SHRR,
+        "SHLR" => Instruction::SHLR,
+        "SARR" => Instruction::SARR,
+        "ROLR" => Instruction::ROLR,
+        "RORR" => Instruction::RORR,
+        "CMOVE" => Instruction::CMOVE,
+        "CMOVNE" => Instruction::CMOVNE,
+        "CMOVG" => Instruction::CMOVG,
+        "CMOVL" => Instruction::CMOVL,
+        "CMOVGE" => Instruction::CMOVGE,
+        "CMOVLE" => Instruction::CMOVLE,
+        _ => return None,
+    })
+}
+
+fn three_register_form(name: &str) -> Option<ThreeRegisterForm> {
+    Some(match name {
+        "FMA" => Instruction::FMA,
+        "ADD3" => Instruction::ADD3,
+        "SUB3" => Instruction::SUB3,
+        "MUL3" => Instruction::MUL3,
+        "DIV3" => Instruction::DIV3,
+        "MOD3" => Instruction::MOD3,
+        "AND3" => Instruction::AND3,
+        "OR3" => Instruction::OR3,
+        "XOR3" => Instruction::XOR3,
+        "MIN3" => Instruction::MIN3,
+        "MAX3" => Instruction::MAX3,
+        "SHR3" => Instruction::SHR3,
+        "SHL3" => Instruction::SHL3,
+        "SAR3" => Instruction::SAR3,
+        "ROL3" => Instruction::ROL3,
+        "ROR3" => Instruction::ROR3,
+        _ => return None,
+    })
+}
+
+fn dest_immediate_form(name: &str) -> Option<DestImmediateForm> {
+    Some(match name {
+        "ADDI" => Instruction::ADDI,
+        "SUBI" => Instruction::SUBI,
+        "MULI" => Instruction::MULI,
+        "DIVI" => Instruction::DIVI,
+        "MODI" => Instruction::MODI,
+        "ANDI" => Instruction::ANDI,
+        "ORI" => Instruction::ORI,
+        "XORI" => Instruction::XORI,
+        "MINI" => Instruction::MINI,
+        "MAXI" => Instruction::MAXI,
+        "SHRI" => Instruction::SHRI,
+        "SHLI" => Instruction::SHLI,
+        "SARI" => Instruction::SARI,
+        "ROLI" => Instruction::ROLI,
+        "RORI" => Instruction::RORI,
+        _ => return None,
+    })
+}
+
+//jumps and calls to a label or u16 immediate embed the absolute location
+fn address_form(name: &str) -> Option<AddressForm> {
+    Some(match name {
+        "JMP" | "JMPA" => Instruction::JMPA,
+        "JE" | "JEA" => Instruction::JEA,
+        "JNE" | "JNEA" => Instruction::JNEA,
+        "JG" | "JGA" => Instruction::JGA,
+        "JL" | "JLEA" | "JLA" => Instruction::JLEA,
+        "CALL" | "CALLA" => Instruction::CALLA,
+        _ => return None,
+    })
+}
+
+//branches embed an offset from the next instruction
+fn offset_form(name: &str) -> Option<OffsetForm> {
+    Some(match name {
+        "BRA" => Instruction::BRA,
+        "BEQ" => Instruction::BEQ,
+        "BNE" => Instruction::BNE,
+        "BGT" => Instruction::BGT,
+        "BLE" | "BLT" => Instruction::BLE,
+        "BSR" => Instruction::BSR,
+        _ => return None,
+    })
+}
+
+//the instruction a jump goes to: a label's, or that after a u8 or u16 location, which like a
+//value in a register names the byte before it
+fn target(op: &Operand) -> Option<Address> {
+    match *op {
+        Operand::Location(addr) => Some(addr),
+        Operand::Immediate(Immediate::U8(t)) => Some(t as Address + 1),
+        Operand::Immediate(Immediate::U16(t)) => Some(code_target(t)),
+        _ => None,
+    }
+}
+
+//the code byte CWRITE writes: a label's first byte, or a u8 or u16 offset
+fn byte_address(op: &Operand) -> Option<Address> {
+    match *op {
+        Operand::Location(addr) => Some(addr),
+        Operand::Immediate(Immediate::U8(t)) => Some(t as Address),
+        Operand::Immediate(Immediate::U16(t)) => Some(t as Address),
+        _ => None,
+    }
+}
+
+//a label as a value, the location jumps through a register or the stack take
+fn value(op: &Operand) -> Operand {
+    match *op {
+        Operand::Location(addr) => Operand::Immediate(Immediate::U16(code_location(addr))),
+        _ => op.clone(),
+    }
+}
+
+//builds a branch from the instruction at `here` to a label or u16 target. An i16 immediate
+//is taken as the raw offset
+fn relative(here: usize, t: &Operand, make: impl Fn(Offset) -> Instruction) -> Result<Instruction, String> {
+    if let Operand::Immediate(Immediate::I16(offset)) = *t {
+        return Ok(make(offset as Offset));
+    }
+    let target = target(t).ok_or_else(|| format!("branch target {:?} is not a label, u16 or i16 offset", t))?;
+    let mut bytes = Vec::new();
+    make(0).encode(&mut bytes);
+    let offset = target as Offset - (here + bytes.len()) as Offset;
+    if offset < i16::MIN as Offset || offset > i16::MAX as Offset {
+        return Err(format!("branch target {} is out of range", target));
+    }
+    Ok(make(offset))
+}
+
+//PICK and ROLL encode their stack index as a single byte
+fn stack_index(n: &Immediate) -> Option<usize> {
+    match *n {
+        Immediate::U8(n) => Some(n as usize),
+        _ => None,
+    }
+}
+
+fn build(name: &str, operands: &[Operand], here: usize) -> Result<Instruction, String> {
+    use Operand::*;
+
+    if let [t @ (Immediate(_) | Location(_))] = operands {
+        if let Some(jump) = address_form(name) {
+            return target(t).map(jump).ok_or_else(|| format!("`{}` needs a label or u16 location", name));
+        }
+        if let Some(branch) = offset_form(name) {
+            return relative(here, t, branch);
+        }
+    }
+    match (name, operands) {
+        ("CBEQ", [Register(a), Register(b), t]) => return relative(here, t, |o| Instruction::CBEQ(*a, *b, o)),
+        ("CBNE", [Register(a), Register(b), t]) => return relative(here, t, |o| Instruction::CBNE(*a, *b, o)),
+        ("CBGT", [Register(a), Register(b), t]) => return relative(here, t, |o| Instruction::CBGT(*a, *b, o)),
+        ("CBLT", [Register(a), Register(b), t]) => return relative(here, t, |o| Instruction::CBLT(*a, *b, o)),
+        ("CBEQ", [Register(a), Immediate(v), t]) | ("CBEQI", [Register(a), Immediate(v), t]) => return relative(here, t, |o| Instruction::CBEQI(*a, *v, o)),
+        ("CBNE", [Register(a), Immediate(v), t]) | ("CBNEI", [Register(a), Immediate(v), t]) => return relative(here, t, |o| Instruction::CBNEI(*a, *v, o)),
+        ("CBGT", [Register(a), Immediate(v), t]) | ("CBGTI", [Register(a), Immediate(v), t]) => return relative(here, t, |o| Instruction::CBGTI(*a, *v, o)),
+        ("CBLT", [Register(a), Immediate(v), t]) | ("CBLTI", [Register(a), Immediate(v), t]) => return relative(here, t, |o| Instruction::CBLTI(*a, *v, o)),
+        ("DJNZ", [Register(a), t]) => return relative(here, t, |o| Instruction::DJNZ(*a, o)),
+        ("SELECT", [Register(d), Register(c), Register(a), Register(b)]) => return Ok(Instruction::SELECT(*d, *c, *a, *b)),
+        ("CWRITE", [t, Register(r)]) => {
+            return byte_address(t).map(|addr| Instruction::CWRITE(addr, *r)).ok_or_else(|| "`CWRITE` needs a label or u16 location".to_string());
+        },
+        ("SWITCH", [Register(r), locations @ ..]) if !locations.is_empty() => {
+            let mut addresses = locations.iter().map(target).collect::<Option<Vec<_>>>().ok_or("`SWITCH` needs label or u16 locations")?;
+            if addresses.len() > u16::MAX as usize {
+                return Err("switch table has too many targets".to_string());
+            }
+            let default = addresses.remove(0);
+            let targets = addresses;
+            return Ok(Instruction::SWITCH(*r, default, targets));
+        },
+        _ => {},
+    }
+    let operands: Vec<Operand> = operands.iter().map(value).collect();
+    let operands = operands.as_slice();
+
+    if let Some((stack, three, dest_imm)) = alu_forms(name) {
+        match operands {
+            [Register(a), Register(b)] => return Ok(stack(*a, *b)),
+            [Register(d), Register(a), Register(b)] => return Ok(three(*d, *a, *b)),
+            [Register(d), Register(a), Immediate(v)] => return Ok(dest_imm(*d, *a, *v)),
+            _ => {},
+        }
+    }
+    if let Some((by_imm, by_reg, three, dest_imm)) = shift_forms(name) {
+        match operands {
+            [Register(a), Immediate(v)] => return Ok(by_imm(*a, *v)),
+            [Register(a), Register(b)] => return Ok(by_reg(*a, *b)),
+            [Register(d), Register(a), Register(b)] => return Ok(three(*d, *a, *b)),
+            [Register(d), Register(a), Immediate(v)] => return Ok(dest_imm(*d, *a, *v)),
+            _ => {},
+        }
+    }
+
+    let instr = match (name, operands) {
+        (_, []) => no_operand_form(name),
+        ("MOV", [Register(r), Immediate(v)]) => Some(Instruction::MOV(*r, *v)),
+        ("VPUSH", [Immediate(v)]) => Some(Instruction::VPUSH(*v)),
+        ("PRINTV", [Address(a)]) => Some(Instruction::PRINTV(*a)),
+        ("VLOAD", [Address(a)]) => Some(Instruction::VLOAD(*a)),
+        ("SSTORE", [Address(a)]) => Some(Instruction::SSTORE(*a)),
+        ("PICK", [Immediate(n)]) => stack_index(n).map(Instruction::PICK),
+        ("ROLL", [Immediate(n)]) => stack_index(n).map(Instruction::ROLL),
+        ("VLOADR", [Register(r), Address(a)]) | ("VLOAD", [Register(r), Address(a)]) => Some(Instruction::VLOADR(*r, *a)),
+        ("VSTORE", [Address(a), Immediate(v)]) => Some(Instruction::VSTORE(*a, *v)),
+        ("VSTORER", [Address(a), Register(r)]) | ("VSTORE", [Address(a), Register(r)]) => Some(Instruction::VSTORER(*a, *r)),
+        (_, [Register(a)]) => register_form(name).map(|f| f(*a)),
+        (_, [Register(a), Register(b)]) => register_register_form(name).map(|f| f(*a, *b)),
+        (_, [Register(a), Register(b), Register(c)]) => three_register_form(name).map(|f| f(*a, *b, *c)),
+        (_, [Register(a), Register(b), Immediate(v)]) => dest_immediate_form(name).map(|f| f(*a, *b, *v)),
+        _ => None,
+    };
+    instr.ok_or_else(|| format!("no form of `{}` takes operands {:?}", name, operands))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::VirtualMachine;
+    use super::assemble;
+
+    //the assembled instructions as their debug forms
+    fn instructions(source: &str) -> Vec<String> {
+        let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+        let len = code.len();
+        let mut vm = VirtualMachine::new(code, 16);
+        let mut out = Vec::new();
+        let mut at = 0;
+        while at < len {
+            let (instr, next) = vm.decode_at(at).expect("assembled code decodes");
+            out.push(format!("{:?}", instr));
+            at = next;
+        }
+        out
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source, "test.asm").expect_err("test program is rejected").to_string()
+    }
+
+    //a label as a value is the location of its instruction, the byte before it
+    #[test]
+    fn label_values() {
+        //f is the HALT at 11
+        assert_eq!(instructions("
+            MOV R0, f
+            VPUSH f
+            JMP R0
+        f:
+            HALT
+        "), vec!["MOV(0, U16(10))", "VPUSH(U16(10))", "JMP(0)", "HALT"]);
+    }
+
+    #[test]
+    fn forward_references() {
+        //end is the HALT at 13, branches count from the next instruction
+        assert_eq!(instructions("
+            JMP end
+            BRA end
+            CBEQ R0, 1u8, end
+            NOP
+        end:
+            HALT
+        "), vec!["JMPA(13)", "BRA(7)", "CBEQI(0, U8(1), 1)", "NOP", "HALT"]);
+    }
+
+    #[test]
+    fn forms() {
+        assert_eq!(instructions("
+            ADD R0, R1
+            ADD R2, R0, R1
+            ADD R2, R0, 5u8
+            SHR R0, 2u8
+            SHR R0, R1
+            SHR R2, R0, R1
+            SHR R2, R0, 1u8
+            MOV R0, 10u8
+            MOV R0, R1
+            VSTORE [3], 1.5f32
+            VSTORE [3], R1
+        "), vec![
+            "ADD(0, 1)", "ADD3(2, 0, 1)", "ADDI(2, 0, U8(5))",
+            "SHR(0, U8(2))", "SHRR(0, 1)", "SHR3(2, 0, 1)", "SHRI(2, 0, U8(1))",
+            "MOV(0, U8(10))", "MOVR(0, 1)", "VSTORE(3, F32(1.5))", "VSTORER(3, 1)",
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("NOP\nFOO R0"), "line 2: no form of `FOO` takes operands [Register(0)]");
+        //found in the second pass, still on its own line
+        assert_eq!(error("NOP\n\nJMP nowhere"), "line 3: undefined label `nowhere`");
+        assert_eq!(error("a:\na:\nNOP"), "line 2: label `a` defined twice");
+        assert_eq!(error("MOV R0, 10"), "line 1: immediate `10` needs a type suffix (e.g. 10u8, 1.5f32)");
+        assert_eq!(error("MOV R0, 300u8"), "line 1: immediate `300u8` is out of range");
+        assert_eq!(error("VLOAD R0, [300]"), "line 1: invalid heap address `[300]`");
+        assert_eq!(error("MOV R0, R256"), "line 1: invalid register `R256`");
+    }
+
+    //the names from before the rename assemble to the same opcodes
+    #[test]
+    fn old_names() {
+        assert_eq!(instructions("JLA end\nBLT end\nend:\nHALT"), instructions("JLEA end\nBLE end\nend:\nHALT"));
+        assert_eq!(instructions("SJL"), instructions("SJLE"));
+    }
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, Instant};
+use crate::{RegisterFile, VirtualMachine};
+
+//Dispatch benchmarks.
+//
+//Runs a program a number of times in every dispatch mode, with tracing off, and prints the mean
+//time per run. Each mode has to leave the machine in the same state as plain decoding does,
+//otherwise the difference is reported.
+
+type Setup = fn(VirtualMachine) -> VirtualMachine;
+
+//ways of setting up a machine, the first is the reference the others are checked against
+const MODES: &[(&str, Setup)] = &[
+    ("decode", |vm| vm),
+    ("cached", VirtualMachine::with_decode_cache),
+    ("threaded", VirtualMachine::with_threaded_code),
+    #[cfg(feature = "jit")]
+    ("jit", VirtualMachine::with_jit),
+];
+
+pub fn run(code: &[u8], heap_capacity: usize, registers: RegisterFile, runs: u32) {
+    let mut reference = None;
+    for (name, setup) in MODES {
+        let mut elapsed = Duration::default();
+        let mut state = String::new();
+        for _ in 0..runs {
+            let mut vm = setup(VirtualMachine::new(code.to_vec(), heap_capacity).with_registers(registers));
+            vm.trace = false;
+            let start = Instant::now();
+            vm.cpu();
+            elapsed += start.elapsed();
+            state = format!("{:?} {:?} {:?}", vm.reg, vm.stack, vm.data);
+        }
+        println!("{:>8}: {:?} per run", name, elapsed / runs.max(1));
+        match reference {
+            None => reference = Some(state),
+            Some(ref expected) if *expected != state => println!("{:>8}: final state differs from {}", name, MODES[0].0),
+            Some(_) => {},
+        }
+    }
+}
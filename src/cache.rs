@@ -0,0 +1,129 @@
+use crate::{Address, Instruction, VirtualMachine};
+
+//Pre-decoded instruction cache.
+//
+//The program is decoded once before it runs and `cpu` executes from the decoded instructions
+//instead of parsing bytes on every step. Offsets the linear decode never reached, such as a jump
+//into the middle of an instruction, are decoded and cached the first time they run. A write to
+//the code drops every cached instruction whose bytes it touches, so self-modifying code is
+//decoded again from the new bytes.
+
+#[derive(Debug, Default)]
+pub struct DecodedCode {
+    //decoded instructions with the offset of the instruction after each
+    instructions: Vec<(Instruction, Address)>,
+    //code offset to the instruction starting there
+    index: Vec<Option<usize>>,
+}
+
+impl DecodedCode {
+    fn get(&self, offset: Address) -> Option<&(Instruction, Address)> {
+        self.index.get(offset).copied().flatten().map(|i| &self.instructions[i])
+    }
+
+    fn insert(&mut self, offset: Address, instr: Instruction, next: Address) {
+        self.index[offset] = Some(self.instructions.len());
+        self.instructions.push((instr, next));
+    }
+
+    //forgets every instruction covering `offset`
+    fn invalidate(&mut self, offset: Address) {
+        for start in 0..=offset.min(self.index.len().saturating_sub(1)) {
+            if let Some(i) = self.index[start] {
+                if self.instructions[i].1 > offset {
+                    self.index[start] = None;
+                }
+            }
+        }
+    }
+}
+
+impl VirtualMachine {
+    //runs programs from decoded instructions instead of decoding every step
+    pub(crate) fn with_decode_cache(mut self) -> Self {
+        self.decoded = Some(DecodedCode::default());
+        self
+    }
+
+    //fills the cache with every instruction from the start of the code, if caching is on
+    pub(crate) fn predecode(&mut self) {
+        if self.decoded.is_none() {
+            return;
+        }
+        let saved = self.ip;
+        let mut decoded = DecodedCode { instructions: Vec::new(), index: vec![None; self.code.len()] };
+        self.ip = 0;
+        while self.ip < self.code.len() {
+            let start = self.ip;
+            let instr = self.decode();
+            self.ip += 1;
+            if self.fault.take().is_none() {
+                decoded.insert(start, instr, self.ip);
+            }
+        }
+        self.ip = saved;
+        self.decoded = Some(decoded);
+    }
+
+    //same as `decode`, leaving ip on the last byte of the instruction, but served from the cache
+    pub(crate) fn cached_decode(&mut self) -> Instruction {
+        let start = self.ip;
+        if let Some((instr, next)) = self.decoded.as_ref().and_then(|d| d.get(start)) {
+            self.ip = next - 1;
+            return instr.clone();
+        }
+        let instr = self.decode();
+        if self.fault.is_none() {
+            if let Some(decoded) = self.decoded.as_mut() {
+                decoded.insert(start, instr.clone(), self.ip + 1);
+            }
+        }
+        instr
+    }
+
+    //writes one byte of code, dropping the cached and compiled instructions it changes
+    pub(crate) fn write_code(&mut self, offset: Address, byte: u8) {
+        self.code[offset] = byte;
+        if let Some(decoded) = self.decoded.as_mut() {
+            decoded.invalidate(offset);
+        }
+        #[cfg(feature = "jit")]
+        self.jit_invalidate(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::{Immediate, VirtualMachine};
+
+    //a loop that rewrites the third register of its own ADD3 after the first pass, so it adds 1
+    //once and then 10
+    const PATCHED_LOOP: &str = "
+        MOV R0, 3u8
+        MOV R1, 0u8
+        MOV R2, 1u8
+        MOV R3, 10u8
+    top:
+        ADD R1, R1, R2
+        MOV R4, 3u8
+        CWRITE 19u16, R4
+        DJNZ R0, top
+        HALT
+    ";
+
+    fn run(configure: fn(VirtualMachine) -> VirtualMachine) -> Immediate {
+        let (code, _) = assemble(PATCHED_LOOP, "patched.asm").expect("test program assembles");
+        let mut vm = configure(VirtualMachine::new(code, 0));
+        vm.trace = false;
+        vm.cpu();
+        vm.reg[1].value()
+    }
+
+    #[test]
+    fn cwrite_in_cached_loop() {
+        assert_eq!(run(|vm| vm), Immediate::U8(21));
+        assert_eq!(run(VirtualMachine::with_decode_cache), Immediate::U8(21));
+        assert_eq!(run(VirtualMachine::with_threaded_code), Immediate::U8(21));
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::process;
+use crate::{or_exit, Address, Instruction, RegisterFile, VirtualMachine};
+
+//Control flow graph.
+//
+//Built on the type checker's abstract interpretation, which knows the constant a register or
+//stack slot holds when every path reaching a jump moved or pushed the same one, so `MOV R0, 12u8`
+//followed by `JMP R0` gives an edge to the instruction at 13. A `RET` whose return address isn't known returns to
+//every call site. Instructions are grouped into basic blocks, straight-line runs entered only at
+//the first instruction and left only after the last.
+//
+//The graph is exported as Graphviz DOT and checked for code no path reaches, jumps whose target
+//isn't known, and loops with no way out. Unreachable code is shown without edges. A loop counts as
+//having no way out when no instruction in it halts, fails on every path, leaves the code or jumps
+//somewhere unknown, and no edge leaves it; flags are not tracked, so a loop whose exit depends
+//on a comparison that never comes out that way is not found.
+
+pub struct Block {
+    start: Address,
+    end: Address,
+    instrs: Vec<(Address, Instruction)>,
+    successors: Vec<usize>,
+    //halts, fails on every path or runs past the end of the code
+    exits: bool,
+    unresolved: bool,
+    reachable: bool,
+}
+
+pub struct Cfg {
+    blocks: Vec<Block>,
+}
+
+pub enum Finding {
+    Unreachable(Address, Address),
+    Unresolved(Address),
+    EndlessLoop(Address),
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Finding::Unreachable(start, end) => write!(f, "Unreachable code at ip:{}..{}", start, end),
+            Finding::Unresolved(at) => write!(f, "Jump target not known at ip:{}", at),
+            Finding::EndlessLoop(at) => write!(f, "Loop without an exit at ip:{}", at),
+        }
+    }
+}
+
+//escapes text for a quoted DOT string
+fn quote(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Cfg {
+    pub fn edges(&self) -> usize {
+        self.blocks.iter().map(|b| b.successors.len()).sum()
+    }
+
+    //strongly connected components of the reachable blocks, by Tarjan's algorithm
+    fn components(&self) -> Vec<Vec<usize>> {
+        struct Search<'a> {
+            cfg: &'a Cfg,
+            index: Vec<Option<usize>>,
+            low: Vec<usize>,
+            on_stack: Vec<bool>,
+            stack: Vec<usize>,
+            next: usize,
+            components: Vec<Vec<usize>>,
+        }
+        impl Search<'_> {
+            fn visit(&mut self, b: usize) {
+                self.index[b] = Some(self.next);
+                self.low[b] = self.next;
+                self.next += 1;
+                self.stack.push(b);
+                self.on_stack[b] = true;
+                for &s in &self.cfg.blocks[b].successors {
+                    match self.index[s] {
+                        None => {
+                            self.visit(s);
+                            self.low[b] = self.low[b].min(self.low[s]);
+                        },
+                        Some(i) if self.on_stack[s] => self.low[b] = self.low[b].min(i),
+                        Some(_) => {},
+                    }
+                }
+                if Some(self.low[b]) == self.index[b] {
+                    let mut component = Vec::new();
+                    while let Some(top) = self.stack.pop() {
+                        self.on_stack[top] = false;
+                        component.push(top);
+                        if top == b {
+                            break;
+                        }
+                    }
+                    self.components.push(component);
+                }
+            }
+        }
+        let n = self.blocks.len();
+        let mut search = Search { cfg: self, index: vec![None; n], low: vec![0; n], on_stack: vec![false; n], stack: Vec::new(), next: 0, components: Vec::new() };
+        for b in 0..n {
+            if self.blocks[b].reachable && search.index[b].is_none() {
+                search.visit(b);
+            }
+        }
+        search.components
+    }
+
+    //everything worth a look, ordered by offset
+    pub fn findings(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        for block in &self.blocks {
+            if !block.reachable {
+                findings.push((block.start, Finding::Unreachable(block.start, block.end)));
+            } else if block.unresolved {
+                let last = block.instrs.last().map_or(block.start, |&(start, _)| start);
+                findings.push((last, Finding::Unresolved(last)));
+            }
+        }
+        for component in self.components() {
+            let cyclic = component.len() > 1 || self.blocks[component[0]].successors.contains(&component[0]);
+            let closed = component.iter().all(|&b| {
+                let block = &self.blocks[b];
+                !block.exits && !block.unresolved && block.successors.iter().all(|s| component.contains(s))
+            });
+            if cyclic && closed {
+                let start = component.iter().map(|&b| self.blocks[b].start).min().expect("components are never empty");
+                findings.push((start, Finding::EndlessLoop(start)));
+            }
+        }
+        findings.sort_by_key(|&(at, _)| at);
+        findings.into_iter().map(|(_, finding)| finding).collect()
+    }
+
+    //the graph in Graphviz DOT. Unreachable blocks are dashed, jumps to unknown targets go to `?`
+    pub fn dot(&self) -> String {
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        let mut exits = false;
+        let mut unknown = false;
+        for block in &self.blocks {
+            let label: String = block.instrs.iter().map(|(start, instr)| format!("{}: {}\\l", start, quote(&format!("{:?}", instr)))).collect();
+            let style = if block.reachable { "" } else { ", style=dashed, color=gray" };
+            out.push_str(&format!("    ip{} [label=\"{}\"{}];\n", block.start, label, style));
+        }
+        for block in &self.blocks {
+            for &s in &block.successors {
+                out.push_str(&format!("    ip{} -> ip{};\n", block.start, self.blocks[s].start));
+            }
+            if block.exits && block.reachable {
+                out.push_str(&format!("    ip{} -> exit;\n", block.start));
+                exits = true;
+            }
+            if block.unresolved {
+                out.push_str(&format!("    ip{} -> unknown [style=dashed];\n", block.start));
+                unknown = true;
+            }
+        }
+        if exits {
+            out.push_str("    exit [shape=oval];\n");
+        }
+        if unknown {
+            out.push_str("    unknown [shape=diamond, label=\"?\"];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl VirtualMachine {
+    //builds the control flow graph of the program from ip. Fails if it doesn't pass verification
+    pub(crate) fn cfg(&mut self) -> Result<Cfg, String> {
+        self.verified()?;
+        let len = self.code.len();
+        let mut flows = self.flow();
+        let index: HashMap<Address, usize> = flows.iter().enumerate().map(|(i, flow)| (flow.start, i)).collect();
+
+        //returns without a known address go back to every call site
+        let returns: Vec<Address> = flows.iter()
+            .filter(|flow| flow.successors.is_some() && matches!(flow.instr, Instruction::CALL(_) | Instruction::CALLA(_) | Instruction::BSR(_)))
+            .map(|flow| flow.next).filter(|&next| next < len).collect();
+        for flow in &mut flows {
+            if let (Instruction::RET(), Some(successors)) = (&flow.instr, &mut flow.successors) {
+                if flow.unresolved && !returns.is_empty() {
+                    successors.extend(&returns);
+                    flow.unresolved = false;
+                }
+            }
+        }
+        //a jump into the middle of an instruction can't be followed either
+        for flow in &mut flows {
+            if let Some(successors) = &mut flow.successors {
+                successors.sort_unstable();
+                successors.dedup();
+                if successors.iter().any(|s| *s < len && !index.contains_key(s)) {
+                    successors.retain(|s| *s >= len || index.contains_key(s));
+                    flow.unresolved = true;
+                }
+            }
+        }
+
+        let mut predecessors = vec![0; flows.len()];
+        for flow in &flows {
+            for s in flow.successors.iter().flatten() {
+                if let Some(&i) = index.get(s) {
+                    predecessors[i] += 1;
+                }
+            }
+        }
+        //an instruction carries on the block of the one before it when that one only goes on to
+        //it and nothing else comes in
+        let continues = |i: usize| {
+            let (prev, flow) = (&flows[i - 1], &flows[i]);
+            match (&prev.successors, &flow.successors) {
+                (Some(successors), Some(_)) => !prev.unresolved && successors == &[flow.start] && predecessors[i] == 1 && flow.start != self.ip,
+                (None, None) => true,
+                _ => false,
+            }
+        };
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut block_of = vec![0; flows.len()];
+        for i in 0..flows.len() {
+            if i == 0 || !continues(i) {
+                blocks.push(Block { start: flows[i].start, end: flows[i].start, instrs: Vec::new(), successors: Vec::new(), exits: false, unresolved: false, reachable: flows[i].successors.is_some() });
+            }
+            let block = blocks.last_mut().expect("the first instruction starts a block");
+            block.instrs.push((flows[i].start, flows[i].instr.clone()));
+            block.end = flows[i].next;
+            block_of[i] = blocks.len() - 1;
+        }
+        for (i, flow) in flows.iter().enumerate() {
+            let next_in_block = i + 1 < flows.len() && block_of[i + 1] == block_of[i];
+            let block = &mut blocks[block_of[i]];
+            if let Some(successors) = &flow.successors {
+                block.unresolved |= flow.unresolved;
+                block.exits |= successors.is_empty() || successors.iter().any(|&s| s >= len);
+                if !next_in_block {
+                    block.successors.extend(successors.iter().filter_map(|s| index.get(s)).map(|&s| block_of[s]));
+                }
+            }
+        }
+        Ok(Cfg { blocks })
+    }
+}
+
+fn build(code: &[u8], heap_capacity: usize, registers: RegisterFile) -> Cfg {
+    let mut vm = VirtualMachine::new(code.to_vec(), heap_capacity).with_registers(registers);
+    or_exit(vm.cfg())
+}
+
+//prints the size of the graph and everything found in it
+pub fn report(code: &[u8], heap_capacity: usize, registers: RegisterFile) {
+    let cfg = build(code, heap_capacity, registers);
+    let reachable = cfg.blocks.iter().filter(|b| b.reachable).count();
+    println!("{} blocks, {} reachable, {} edges", cfg.blocks.len(), reachable, cfg.edges());
+    let findings = cfg.findings();
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let unresolved = findings.iter().any(|f| matches!(f, Finding::Unresolved(_)));
+    if unresolved && findings.iter().any(|f| matches!(f, Finding::Unreachable(..))) {
+        println!("code reported unreachable may still be reached by jumps with unknown targets");
+    }
+}
+
+//writes the graph to `path` as Graphviz DOT
+pub fn write_dot(code: &[u8], heap_capacity: usize, registers: RegisterFile, path: &str) {
+    let cfg = build(code, heap_capacity, registers);
+    if let Err(e) = fs::write(path, cfg.dot()) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::{Address, VirtualMachine};
+    use super::Cfg;
+
+    fn graph(source: &str) -> Cfg {
+        let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+        VirtualMachine::new(code, 16).cfg().expect("test program verifies")
+    }
+
+    //every edge as the offsets of the blocks it joins
+    fn edges(cfg: &Cfg) -> Vec<(Address, Address)> {
+        cfg.blocks.iter().flat_map(|b| b.successors.iter().map(move |&s| (b.start, cfg.blocks[s].start))).collect()
+    }
+
+    fn findings(cfg: &Cfg) -> Vec<String> {
+        cfg.findings().iter().map(|f| f.to_string()).collect()
+    }
+
+    //the location moved into R0 is followed by the JMP through it
+    #[test]
+    fn constant_jump() {
+        let cfg = graph("
+            MOV R0, target
+            JMP R0
+            HALT
+        target:
+            HALT
+        ");
+        assert_eq!(edges(&cfg), vec![(0, 8)]);
+        assert_eq!(findings(&cfg), vec!["Unreachable code at ip:7..8"]);
+    }
+
+    //a function called from two places returns to both
+    #[test]
+    fn return_to_every_call_site() {
+        let cfg = graph("
+            BSR f
+            BSR f
+            HALT
+        f:
+            RET
+        ");
+        assert_eq!(edges(&cfg), vec![(0, 3), (0, 7), (3, 6), (3, 7), (7, 3), (7, 6)]);
+        assert!(findings(&cfg).is_empty());
+    }
+
+    //the sum is only known by its type
+    #[test]
+    fn unresolved_jump() {
+        let cfg = graph("
+            MOV R0, 1u8
+            ADD R1, R0, R0
+            JMP R1
+            HALT
+        ");
+        assert!(cfg.blocks[0].unresolved && cfg.blocks[0].exits);
+        assert_eq!(findings(&cfg), vec!["Jump target not known at ip:8", "Unreachable code at ip:10..11"]);
+    }
+
+    #[test]
+    fn endless_loop() {
+        let cfg = graph("
+            NOP
+        top:
+            NOP
+            BRA top
+            HALT
+        ");
+        assert_eq!(edges(&cfg), vec![(0, 1), (1, 1)]);
+        assert_eq!(findings(&cfg), vec!["Loop without an exit at ip:1", "Unreachable code at ip:5..6"]);
+    }
+
+    //a jump past the last instruction leaves the program
+    #[test]
+    fn loop_exits_by_jumping_to_the_end() {
+        let cfg = graph("
+            MOV R0, 3u8
+        top:
+            CBEQI R0, 0u8, end
+            SUB R0, R0, 1u8
+            BRA top
+        end:
+        ");
+        assert_eq!(edges(&cfg), vec![(0, 4), (4, 10), (10, 4)]);
+        assert!(cfg.blocks[1].exits);
+        assert!(findings(&cfg).is_empty());
+    }
+
+    #[test]
+    fn dot() {
+        let cfg = graph("
+            MOV R0, target
+            JMP R0
+            HALT
+        target:
+            HALT
+        ");
+        assert_eq!(cfg.dot(), concat!(
+            "digraph cfg {\n",
+            "    node [shape=box, fontname=\"monospace\"];\n",
+            "    ip0 [label=\"0: MOV(0, U16(7))\\l5: JMP(0)\\l\"];\n",
+            "    ip7 [label=\"7: HALT\\l\", style=dashed, color=gray];\n",
+            "    ip8 [label=\"8: HALT\\l\"];\n",
+            "    ip0 -> ip8;\n",
+            "    ip8 -> exit;\n",
+            "    exit [shape=oval];\n",
+            "}\n",
+        ));
+        let unknown = graph("
+            MOV R0, 1u8
+            ADD R1, R0, R0
+            JMP R1
+        ").dot();
+        assert!(unknown.contains("    ip0 -> unknown [style=dashed];\n"));
+        assert!(unknown.contains("    unknown [shape=diamond, label=\"?\"];\n"));
+    }
+}
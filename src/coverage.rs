@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
+use std::process;
+use crate::assembler::DebugInfo;
+use crate::{Address, Instruction, VirtualMachine};
+
+//Code coverage.
+//
+//Counts the runs of the instruction at each code offset and, for every conditional jump or
+//branch, how often it was taken and how often it fell through. A conditional jump to the next
+//instruction counts as falling through. Like profiling, coverage runs the decode loop.
+//
+//Reports go back to the source through the assembler's line table: an annotated listing that
+//marks each line with its count, `#####` for code that never ran and the branch counts after
+//conditional jumps, and an lcov tracefile with the lines and both directions of every branch.
+
+#[derive(Default)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    //taken and not taken counts per conditional jump
+    branches: BTreeMap<Address, (u64, u64)>,
+}
+
+//whether an instruction jumps only sometimes
+pub(crate) fn conditional(instr: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(instr, JE(_) | JNE(_) | JG(_) | JL(_) | SJE() | SJNE() | SJG() | SJLE()
+        | JEA(_) | JNEA(_) | JGA(_) | JLEA(_) | BEQ(_) | BNE(_) | BGT(_) | BLE(_)
+        | CBEQ(..) | CBNE(..) | CBGT(..) | CBLT(..) | CBEQI(..) | CBNEI(..) | CBGTI(..) | CBLTI(..) | DJNZ(..))
+}
+
+impl Coverage {
+    //records one run of the instruction at `start`, which went on to `ip` instead of `next` if
+    //it jumped
+    pub(crate) fn record(&mut self, start: Address, branch: bool, next: Address, ip: Address) {
+        if self.hits.len() <= start {
+            self.hits.resize(start + 1, 0);
+        }
+        self.hits[start] += 1;
+        if branch {
+            let counts = self.branches.entry(start).or_default();
+            if ip != next {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    fn hits(&self, offset: Address) -> u64 {
+        self.hits.get(offset).copied().unwrap_or(0)
+    }
+}
+
+//every instruction of the program with the line it came from, and whether it is a conditional jump
+fn instructions(vm: &mut VirtualMachine, debug: &DebugInfo) -> Vec<(Address, usize, bool)> {
+    debug.lines.iter().map(|(&offset, location)| {
+        let branch = vm.decode_at(offset).is_ok_and(|(instr, _)| conditional(&instr));
+        (offset, location.line, branch)
+    }).collect()
+}
+
+//counts per source line: runs of its instructions, and the branch counts of its conditional
+//jumps, `None` for branches whose line never ran
+struct Line {
+    hits: u64,
+    branches: Vec<Option<(u64, u64)>>,
+}
+
+fn lines(vm: &mut VirtualMachine, coverage: &Coverage, debug: &DebugInfo) -> BTreeMap<usize, Line> {
+    let mut lines: BTreeMap<usize, Line> = BTreeMap::new();
+    for (offset, number, branch) in instructions(vm, debug) {
+        let hits = coverage.hits(offset);
+        let line = lines.entry(number).or_insert(Line { hits, branches: Vec::new() });
+        line.hits = line.hits.max(hits);
+        if branch {
+            line.branches.push(coverage.branches.get(&offset).copied().filter(|_| hits > 0));
+        }
+    }
+    lines
+}
+
+//the source with every line that has code marked with its count
+pub fn listing(vm: &mut VirtualMachine, coverage: &Coverage, debug: &DebugInfo, source: &str) -> String {
+    let lines = lines(vm, coverage, debug);
+    let mut out = String::new();
+    for (i, text) in source.lines().enumerate() {
+        let count = match lines.get(&(i + 1)) {
+            Some(line) if line.hits == 0 => "#####".to_string(),
+            Some(line) => line.hits.to_string(),
+            None => "-".to_string(),
+        };
+        out.push_str(&format!("{:>9}:{:>5}: {}", count, i + 1, text));
+        if let Some(line) = lines.get(&(i + 1)) {
+            for (taken, not_taken) in line.branches.iter().flatten() {
+                out.push_str(&format!("    ; taken {}, not taken {}", taken, not_taken));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+//an lcov tracefile for `path`
+pub fn lcov(vm: &mut VirtualMachine, coverage: &Coverage, debug: &DebugInfo, path: &str) -> String {
+    let lines = lines(vm, coverage, debug);
+    let mut out = format!("TN:\nSF:{}\n", path);
+    let (mut found, mut hit) = (0, 0);
+    for (number, line) in &lines {
+        for (block, counts) in line.branches.iter().enumerate() {
+            for (direction, count) in [counts.map(|c| c.0), counts.map(|c| c.1)].iter().enumerate() {
+                match count {
+                    Some(count) => out.push_str(&format!("BRDA:{},{},{},{}\n", number, block, direction, count)),
+                    None => out.push_str(&format!("BRDA:{},{},{},-\n", number, block, direction)),
+                }
+                found += 1;
+                hit += matches!(count, Some(c) if *c > 0) as usize;
+            }
+        }
+    }
+    out.push_str(&format!("BRF:{}\nBRH:{}\n", found, hit));
+    for (number, line) in &lines {
+        out.push_str(&format!("DA:{},{}\n", number, line.hits));
+    }
+    out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines.values().filter(|l| l.hits > 0).count()));
+    out
+}
+
+impl VirtualMachine {
+    //records which instructions run and which way conditional jumps go
+    pub(crate) fn with_coverage(mut self) -> Self {
+        self.coverage = Some(Box::new(Coverage::default()));
+        self
+    }
+}
+
+//prints the annotated listing of a finished run and writes the lcov tracefile to `path`
+pub fn write(vm: &mut VirtualMachine, source: &str, source_path: &str, path: &str) {
+    let coverage = match vm.coverage.take() {
+        Some(coverage) => coverage,
+        None => return,
+    };
+    let debug = mem::take(&mut vm.debug);
+    print!("{}", listing(vm, &coverage, &debug, source));
+    let lcov = lcov(vm, &coverage, &debug, source_path);
+    vm.debug = debug;
+    if let Err(e) = fs::write(path, lcov) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::VirtualMachine;
+    use super::{lcov, listing};
+
+    //a loop, a branch that is only ever skipped and code after the HALT that never runs
+    const SOURCE: &str = "    MOV R0, 3u8
+top:
+    DJNZ R0, top
+    CBEQI R0, 1u8, skip
+    HALT
+skip:
+    NOP
+    JE skip
+";
+
+    #[test]
+    fn reports() {
+        let (code, debug) = assemble(SOURCE, "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 0).with_coverage();
+        vm.trace = false;
+        vm.cpu();
+        let coverage = vm.coverage.take().expect("coverage is on");
+        assert_eq!(listing(&mut vm, &coverage, &debug, SOURCE), concat!(
+            "        1:    1:     MOV R0, 3u8\n",
+            "        -:    2: top:\n",
+            "        3:    3:     DJNZ R0, top    ; taken 2, not taken 1\n",
+            "        1:    4:     CBEQI R0, 1u8, skip    ; taken 0, not taken 1\n",
+            "        1:    5:     HALT\n",
+            "        -:    6: skip:\n",
+            "    #####:    7:     NOP\n",
+            "    #####:    8:     JE skip\n",
+        ));
+        //the JE never ran, so neither direction has a count
+        assert_eq!(lcov(&mut vm, &coverage, &debug, "test.asm"), concat!(
+            "TN:\nSF:test.asm\n",
+            "BRDA:3,0,0,2\nBRDA:3,0,1,1\n",
+            "BRDA:4,0,0,0\nBRDA:4,0,1,1\n",
+            "BRDA:8,0,0,-\nBRDA:8,0,1,-\n",
+            "BRF:6\nBRH:3\n",
+            "DA:1,1\nDA:3,3\nDA:4,1\nDA:5,1\nDA:7,0\nDA:8,0\n",
+            "LF:6\nLH:4\nend_of_record\n",
+        ));
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use crate::{Address, BinaryOp, Immediate, Instruction, Register, VirtualMachine};
+use crate::threaded::{register_alu, Source};
+use crate::word::Word;
+
+//Hot loop compiler for x86-64, built with `--features jit`.
+//
+//The interpreter counts the backward jumps taken to every code offset. Once a loop has jumped
+//back to its first instruction `HOT` times, the instructions from there to the jump are compiled
+//to machine code, provided every one of them is supported: register moves, integer and float
+//ALU instructions in register destination form, CMP, embedded jumps and branches,
+//compare-and-branch and DJNZ. The loop's registers must keep their type through the body, so
+//the compiled code only checks the type tags once when it is entered and then works on the raw
+//64-bit payloads in place. If a tag doesn't match (the type guard fails) the loop is dropped and
+//interpreted again. Anything that would fault, such as a zero divisor, leaves the compiled code
+//at that instruction so the interpreter reports it, as does any jump out of the loop.
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs an x86-64 unix target");
+
+//back edges taken to a loop before it is compiled
+const HOT: u32 = 64;
+
+//returned by compiled code when a type guard fails
+const GUARD_FAILED: u64 = u64::MAX;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+//registers, flag_eq and flag_gt, returning the code offset to continue at
+type Entry = unsafe extern "sysv64" fn(*mut Word, *mut bool, *mut bool) -> u64;
+
+//machine code in its own executable mapping
+struct Native {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Native {
+    fn new(code: &[u8]) -> Option<Native> {
+        unsafe {
+            let ptr = mmap(ptr::null_mut(), code.len(), PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if ptr as isize == -1 {
+                return None;
+            }
+            let native = Native { ptr, len: code.len() };
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+            if mprotect(ptr, code.len(), PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(native)
+        }
+    }
+
+    //runs the loop, None if a type guard failed
+    fn run(&self, reg: &mut [Word], flag_eq: &mut bool, flag_gt: &mut bool) -> Option<Address> {
+        let ip = unsafe {
+            let entry: Entry = mem::transmute(self.ptr);
+            entry(reg.as_mut_ptr(), flag_eq, flag_gt)
+        };
+        if ip == GUARD_FAILED {
+            None
+        } else {
+            Some(ip as Address)
+        }
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Int { width: u32, signed: bool },
+    F32,
+    F64,
+}
+
+fn kind(v: Immediate) -> Option<Kind> {
+    let int = |width, signed| Some(Kind::Int { width, signed });
+    match v {
+        Immediate::U8(_) => int(8, false),
+        Immediate::I8(_) => int(8, true),
+        Immediate::U16(_) => int(16, false),
+        Immediate::I16(_) => int(16, true),
+        Immediate::U32(_) => int(32, false),
+        Immediate::I32(_) => int(32, true),
+        Immediate::U64(_) => int(64, false),
+        Immediate::I64(_) => int(64, true),
+        Immediate::F32(_) => Some(Kind::F32),
+        Immediate::F64(_) => Some(Kind::F64),
+        Immediate::None() => None,
+    }
+}
+
+fn same_type(a: Immediate, b: Immediate) -> bool {
+    mem::discriminant(&a) == mem::discriminant(&b)
+}
+
+//general purpose registers used by the generated code. rdi points at the VM registers, rsi at
+//flag_eq and r8 at flag_gt
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+//x86 condition codes
+const CC_B: u8 = 0x2;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_A: u8 = 0x7;
+const CC_L: u8 = 0xc;
+const CC_G: u8 = 0xf;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum Dest {
+    //an instruction of the loop, or an exit to the interpreter at that offset
+    Code(Address),
+    //always an exit to the interpreter, to run the instruction at that offset itself
+    Exit(Address),
+    GuardFailed,
+}
+
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    //loop instructions by code offset
+    labels: HashMap<Address, usize>,
+    //rel32 operands to patch
+    fixups: Vec<(usize, Dest)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    //[rdi + disp32] addressing of a VM register's payload or tag
+    fn register(&mut self, opcode: &[u8], modrm_reg: u8, reg: Register, offset: usize) {
+        self.emit(opcode);
+        self.code.push(0x80 | (modrm_reg << 3) | 7);
+        self.emit(&((reg * mem::size_of::<Word>() + offset) as u32).to_le_bytes());
+    }
+
+    fn load(&mut self, gpr: u8, reg: Register) {
+        self.register(&[0x48, 0x8b], gpr, reg, 0);
+    }
+
+    fn store_rax(&mut self, reg: Register) {
+        self.register(&[0x48, 0x89], RAX, reg, 0);
+    }
+
+    fn mov_imm(&mut self, gpr: u8, bits: u64) {
+        self.emit(&[0x48, 0xb8 + gpr]);
+        self.emit(&bits.to_le_bytes());
+    }
+
+    //loads the right operand into rcx
+    fn operand(&mut self, rhs: Source) {
+        match rhs {
+            Source::Register(reg) => self.load(RCX, reg),
+            Source::Word(word) => self.mov_imm(RCX, word.bits()),
+        }
+    }
+
+    //zero extends the low `width` bits of rax
+    fn zero_extend(&mut self, width: u32) {
+        match width {
+            8 => self.emit(&[0x0f, 0xb6, 0xc0]),
+            16 => self.emit(&[0x0f, 0xb7, 0xc0]),
+            32 => self.emit(&[0x89, 0xc0]),
+            _ => {},
+        }
+    }
+
+    //truncates rax to the width of the type and extends it back, like `Word` does
+    fn canonical(&mut self, width: u32, signed: bool) {
+        match (width, signed) {
+            (8, true) => self.emit(&[0x48, 0x0f, 0xbe, 0xc0]),
+            (16, true) => self.emit(&[0x48, 0x0f, 0xbf, 0xc0]),
+            (32, true) => self.emit(&[0x48, 0x63, 0xc0]),
+            (width, _) => self.zero_extend(width),
+        }
+    }
+
+    fn jump(&mut self, cc: Option<u8>, dest: Dest) {
+        match cc {
+            Some(cc) => self.emit(&[0x0f, 0x80 + cc]),
+            None => self.emit(&[0xe9]),
+        }
+        self.fixups.push((self.code.len(), dest));
+        self.emit(&[0; 4]);
+    }
+
+    //appends exit stubs for every jump that leaves the code and patches the jumps
+    fn link(mut self) -> Vec<u8> {
+        let mut stubs: HashMap<Dest, usize> = HashMap::new();
+        for (at, dest) in mem::take(&mut self.fixups) {
+            let target = match dest {
+                Dest::Code(addr) if self.labels.contains_key(&addr) => self.labels[&addr],
+                _ => *stubs.entry(dest).or_insert_with(|| {
+                    let stub = self.code.len();
+                    let ip = match dest {
+                        Dest::Code(addr) | Dest::Exit(addr) => addr as u64,
+                        Dest::GuardFailed => GUARD_FAILED,
+                    };
+                    self.code.extend_from_slice(&[0x48, 0xb8]);
+                    self.code.extend_from_slice(&ip.to_le_bytes());
+                    self.code.push(0xc3);
+                    stub
+                }),
+            };
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+}
+
+//translates a loop body, assuming every register keeps the type it has when compiled
+struct Compiler<'a> {
+    types: &'a [Immediate],
+    //registers whose type tag is checked on entry
+    guards: Vec<Register>,
+    asm: Assembler,
+}
+
+impl<'a> Compiler<'a> {
+    fn ty(&mut self, reg: Register) -> Immediate {
+        if !self.guards.contains(&reg) {
+            self.guards.push(reg);
+        }
+        self.types[reg]
+    }
+
+    fn source_type(&mut self, rhs: Source) -> Immediate {
+        match rhs {
+            Source::Register(reg) => self.ty(reg),
+            Source::Word(word) => word.value(),
+        }
+    }
+
+    //integer type shared by both operands of a comparison
+    fn compared(&mut self, reg: Register, rhs: Source) -> Option<bool> {
+        let (left, right) = (self.ty(reg), self.source_type(rhs));
+        match kind(left)? {
+            Kind::Int { signed, .. } if same_type(left, right) => Some(signed),
+            _ => None,
+        }
+    }
+
+    //jumps to `dest` when the flag at [rsi] (flag_eq) or [r8] (flag_gt) is set or clear
+    fn flag_jump(&mut self, greater: bool, set: bool, dest: Dest) {
+        if greater {
+            self.asm.emit(&[0x41, 0x80, 0x38, 0x00]);
+        } else {
+            self.asm.emit(&[0x80, 0x3e, 0x00]);
+        }
+        self.asm.jump(Some(if set { CC_NE } else { CC_E }), dest);
+    }
+
+    fn instruction(&mut self, instr: &Instruction, start: Address, next: Address) -> Option<()> {
+        let branch = |offset: isize| -> Option<Dest> {
+            let target = next as isize + offset;
+            if target < 0 {
+                return None;
+            }
+            Some(Dest::Code(target as Address))
+        };
+        if let Some((op, dst, reg1, rhs)) = register_alu(instr) {
+            return self.alu(op, dst, reg1, rhs, start);
+        }
+        match *instr {
+            Instruction::NOP() => {},
+            Instruction::MOV(reg, var) => {
+                if kind(var).is_none() || !same_type(self.ty(reg), var) {
+                    return None;
+                }
+                self.asm.mov_imm(RAX, Word::from(var).bits());
+                self.asm.store_rax(reg);
+            },
+            Instruction::MOVR(reg1, reg2) => {
+                if kind(self.ty(reg1)).is_none() || !same_type(self.ty(reg1), self.ty(reg2)) {
+                    return None;
+                }
+                self.asm.load(RAX, reg2);
+                self.asm.store_rax(reg1);
+            },
+            Instruction::CMP(reg1, reg2) => {
+                let signed = self.compared(reg1, Source::Register(reg2))?;
+                self.asm.load(RAX, reg1);
+                self.asm.load(RCX, reg2);
+                //cmp rax, rcx; sete al; mov [rsi], al; setg/seta al; mov [r8], al
+                self.asm.emit(&[0x48, 0x39, 0xc8, 0x0f, 0x94, 0xc0, 0x88, 0x06]);
+                self.asm.emit(&[0x0f, 0x90 + if signed { CC_G } else { CC_A }, 0xc0, 0x41, 0x88, 0x00]);
+            },
+            Instruction::JMPA(addr) => self.asm.jump(None, Dest::Code(addr)),
+            Instruction::JEA(addr) => self.flag_jump(false, true, Dest::Code(addr)),
+            Instruction::JNEA(addr) => self.flag_jump(false, false, Dest::Code(addr)),
+            Instruction::JGA(addr) => self.flag_jump(true, true, Dest::Code(addr)),
+            Instruction::JLEA(addr) => self.flag_jump(true, false, Dest::Code(addr)),
+            Instruction::BRA(offset) => self.asm.jump(None, branch(offset)?),
+            Instruction::BEQ(offset) => self.flag_jump(false, true, branch(offset)?),
+            Instruction::BNE(offset) => self.flag_jump(false, false, branch(offset)?),
+            Instruction::BGT(offset) => self.flag_jump(true, true, branch(offset)?),
+            Instruction::BLE(offset) => self.flag_jump(true, false, branch(offset)?),
+            Instruction::CBEQ(reg1, reg2, offset) => self.compare_branch(reg1, Source::Register(reg2), CC_E, CC_E, branch(offset)?)?,
+            Instruction::CBNE(reg1, reg2, offset) => self.compare_branch(reg1, Source::Register(reg2), CC_NE, CC_NE, branch(offset)?)?,
+            Instruction::CBGT(reg1, reg2, offset) => self.compare_branch(reg1, Source::Register(reg2), CC_G, CC_A, branch(offset)?)?,
+            Instruction::CBLT(reg1, reg2, offset) => self.compare_branch(reg1, Source::Register(reg2), CC_L, CC_B, branch(offset)?)?,
+            Instruction::CBEQI(reg, var, offset) => self.compare_branch(reg, Source::Word(Word::from(var)), CC_E, CC_E, branch(offset)?)?,
+            Instruction::CBNEI(reg, var, offset) => self.compare_branch(reg, Source::Word(Word::from(var)), CC_NE, CC_NE, branch(offset)?)?,
+            Instruction::CBGTI(reg, var, offset) => self.compare_branch(reg, Source::Word(Word::from(var)), CC_G, CC_A, branch(offset)?)?,
+            Instruction::CBLTI(reg, var, offset) => self.compare_branch(reg, Source::Word(Word::from(var)), CC_L, CC_B, branch(offset)?)?,
+            Instruction::DJNZ(reg, offset) => {
+                let (width, signed) = match kind(self.ty(reg))? {
+                    Kind::Int { width, signed } => (width, signed),
+                    _ => return None,
+                };
+                let dest = branch(offset)?;
+                self.asm.load(RAX, reg);
+                //mov rcx, rax; sub rax, 1
+                self.asm.emit(&[0x48, 0x89, 0xc1, 0x48, 0x83, 0xe8, 0x01]);
+                self.asm.canonical(width, signed);
+                self.asm.store_rax(reg);
+                //cmp rcx, 1
+                self.asm.emit(&[0x48, 0x83, 0xf9, 0x01]);
+                self.asm.jump(Some(CC_NE), dest);
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn compare_branch(&mut self, reg: Register, rhs: Source, signed_cc: u8, unsigned_cc: u8, dest: Dest) -> Option<()> {
+        let signed = self.compared(reg, rhs)?;
+        self.asm.load(RAX, reg);
+        self.asm.operand(rhs);
+        //cmp rax, rcx
+        self.asm.emit(&[0x48, 0x39, 0xc8]);
+        self.asm.jump(Some(if signed { signed_cc } else { unsigned_cc }), dest);
+        Some(())
+    }
+
+    fn alu(&mut self, op: BinaryOp, dst: Register, reg1: Register, rhs: Source, start: Address) -> Option<()> {
+        let left = self.ty(reg1);
+        if !same_type(self.ty(dst), left) {
+            return None;
+        }
+        let right = self.source_type(rhs);
+        let (width, signed) = match kind(left)? {
+            Kind::F32 => return self.float(op, dst, reg1, rhs, right, 0xf3),
+            Kind::F64 => return self.float(op, dst, reg1, rhs, right, 0xf2),
+            Kind::Int { width, signed } => (width, signed),
+        };
+        match op {
+            BinaryOp::Shr | BinaryOp::Shl | BinaryOp::Sar | BinaryOp::Rol | BinaryOp::Ror => {
+                //only constant counts, a count that always faults is left to the interpreter
+                let amount = match rhs {
+                    Source::Word(word) => word.value().as_count()?,
+                    Source::Register(_) => return None,
+                };
+                let rotate = matches!(op, BinaryOp::Rol | BinaryOp::Ror);
+                if !rotate && amount >= width {
+                    return None;
+                }
+                self.asm.load(RAX, reg1);
+                let n = (amount % width) as u8;
+                match op {
+                    BinaryOp::Shl => self.asm.emit(&[0x48, 0xc1, 0xe0, n]),
+                    BinaryOp::Sar if signed => self.asm.emit(&[0x48, 0xc1, 0xf8, n]),
+                    BinaryOp::Shr | BinaryOp::Sar => {
+                        self.asm.zero_extend(width);
+                        self.asm.emit(&[0x48, 0xc1, 0xe8, n]);
+                    },
+                    _ => {
+                        let modrm = if op == BinaryOp::Rol { 0xc0 } else { 0xc8 };
+                        match width {
+                            8 => self.asm.emit(&[0xc0, modrm, n]),
+                            16 => self.asm.emit(&[0x66, 0xc1, modrm, n]),
+                            32 => self.asm.emit(&[0xc1, modrm, n]),
+                            _ => self.asm.emit(&[0x48, 0xc1, modrm, n]),
+                        }
+                    },
+                }
+            },
+            _ => {
+                if !same_type(left, right) {
+                    return None;
+                }
+                self.asm.load(RAX, reg1);
+                self.asm.operand(rhs);
+                match op {
+                    BinaryOp::Add => self.asm.emit(&[0x48, 0x01, 0xc8]),
+                    BinaryOp::Sub => self.asm.emit(&[0x48, 0x29, 0xc8]),
+                    BinaryOp::Mul => self.asm.emit(&[0x48, 0x0f, 0xaf, 0xc1]),
+                    BinaryOp::And => self.asm.emit(&[0x48, 0x21, 0xc8]),
+                    BinaryOp::Or => self.asm.emit(&[0x48, 0x09, 0xc8]),
+                    BinaryOp::Xor => self.asm.emit(&[0x48, 0x31, 0xc8]),
+                    BinaryOp::Min | BinaryOp::Max => {
+                        //cmp rax, rcx; cmovcc rax, rcx
+                        let cc = match (op == BinaryOp::Min, signed) {
+                            (true, true) => CC_G,
+                            (true, false) => CC_A,
+                            (false, true) => CC_L,
+                            (false, false) => CC_B,
+                        };
+                        self.asm.emit(&[0x48, 0x39, 0xc8, 0x48, 0x0f, 0x40 + cc, 0xc1]);
+                    },
+                    BinaryOp::Div | BinaryOp::Mod => {
+                        //a zero divisor faults and i64::MIN / -1 traps, both go to the interpreter
+                        self.asm.emit(&[0x48, 0x85, 0xc9]);
+                        self.asm.jump(Some(CC_E), Dest::Exit(start));
+                        if signed && width == 64 {
+                            self.asm.emit(&[0x48, 0x83, 0xf9, 0xff]);
+                            self.asm.jump(Some(CC_E), Dest::Exit(start));
+                        }
+                        if signed {
+                            //cqo; idiv rcx
+                            self.asm.emit(&[0x48, 0x99, 0x48, 0xf7, 0xf9]);
+                        } else {
+                            //xor edx, edx; div rcx
+                            self.asm.emit(&[0x31, 0xd2, 0x48, 0xf7, 0xf1]);
+                        }
+                        if op == BinaryOp::Mod {
+                            //mov rax, rdx
+                            self.asm.emit(&[0x48, 0x89, 0xd0]);
+                        }
+                    },
+                    _ => return None,
+                }
+            },
+        }
+        self.asm.canonical(width, signed);
+        self.asm.store_rax(dst);
+        Some(())
+    }
+
+    //scalar SSE arithmetic, `prefix` selects single (f3) or double (f2) precision
+    fn float(&mut self, op: BinaryOp, dst: Register, reg1: Register, rhs: Source, right: Immediate, prefix: u8) -> Option<()> {
+        if !same_type(self.types[reg1], right) {
+            return None;
+        }
+        let opcode = match op {
+            BinaryOp::Add => 0x58,
+            BinaryOp::Sub => 0x5c,
+            BinaryOp::Mul => 0x59,
+            BinaryOp::Div => 0x5e,
+            _ => return None,
+        };
+        //movs[sd] xmm0, [reg1]
+        self.asm.register(&[prefix, 0x0f, 0x10], 0, reg1, 0);
+        match rhs {
+            Source::Register(reg) => self.asm.register(&[prefix, 0x0f, 0x10], 1, reg, 0),
+            Source::Word(word) => {
+                //mov rcx, bits; movq xmm1, rcx
+                self.asm.mov_imm(RCX, word.bits());
+                self.asm.emit(&[0x66, 0x48, 0x0f, 0x6e, 0xc9]);
+            },
+        }
+        //op xmm0, xmm1; movs[sd] [dst], xmm0
+        self.asm.emit(&[prefix, 0x0f, opcode, 0xc1]);
+        self.asm.register(&[prefix, 0x0f, 0x11], 0, dst, 0);
+        Some(())
+    }
+}
+
+pub(crate) struct Jit {
+    //backward jumps taken to each code offset
+    counters: Vec<u32>,
+    //compiled loops by the offset of their first instruction, with the offset after their last
+    loops: Vec<Option<(Native, Address)>>,
+}
+
+impl VirtualMachine {
+    //compiles hot loops to machine code while the trace is off
+    pub(crate) fn with_jit(mut self) -> Self {
+        self.jit = Some(Jit { counters: vec![0; self.code.len()], loops: (0..self.code.len()).map(|_| None).collect() });
+        self
+    }
+
+    //called by cpu after a jump back to ip from the instruction ending at `next`. Runs the
+    //compiled loop starting at ip, or counts the jump and compiles the loop once it is hot
+    pub(crate) fn back_edge(&mut self, next: Address) {
+        let header = self.ip;
+        let jit = match self.jit.as_mut() {
+            Some(jit) if !self.trace && header < jit.loops.len() => jit,
+            _ => return,
+        };
+        if let Some((native, _)) = &jit.loops[header] {
+            match native.run(&mut self.reg, &mut self.flag_eq, &mut self.flag_gt) {
+                Some(ip) => self.ip = ip,
+                None => {
+                    jit.loops[header] = None;
+                    jit.counters[header] = 0;
+                },
+            }
+            return;
+        }
+        jit.counters[header] = jit.counters[header].saturating_add(1);
+        if jit.counters[header] != HOT {
+            return;
+        }
+        if let Some(native) = self.compile_loop(header, next) {
+            if let Some(jit) = self.jit.as_mut() {
+                jit.loops[header] = Some((native, next));
+            }
+        }
+    }
+
+    fn compile_loop(&mut self, header: Address, end: Address) -> Option<Native> {
+        let types: Vec<Immediate> = self.reg.iter().map(|word| word.value()).collect();
+        let mut compiler = Compiler { types: &types, guards: Vec::new(), asm: Assembler::default() };
+        let mut start = header;
+        while start < end {
+            let (instr, next) = self.decode_at(start).ok()?;
+            compiler.asm.labels.insert(start, compiler.asm.code.len());
+            compiler.instruction(&instr, start, next)?;
+            start = next;
+        }
+        if start != end {
+            return None;
+        }
+        compiler.asm.jump(None, Dest::Code(end));
+
+        //entry: mov r8, rdx, then the type guards ahead of the body
+        let mut asm = Assembler::default();
+        asm.emit(&[0x49, 0x89, 0xd0]);
+        for &reg in &compiler.guards {
+            //cmp byte [rdi + tag], type
+            asm.register(&[0x80], 7, reg, 8);
+            asm.code.push(Word::from(types[reg]).tag_byte());
+            asm.jump(Some(CC_NE), Dest::GuardFailed);
+        }
+        let base = asm.code.len();
+        asm.code.extend_from_slice(&compiler.asm.code);
+        asm.labels.extend(compiler.asm.labels.iter().map(|(&addr, &at)| (addr, at + base)));
+        asm.fixups.extend(compiler.asm.fixups.iter().map(|&(at, dest)| (at + base, dest)));
+        Native::new(&asm.link())
+    }
+
+    //drops compiled loops covering a code offset that was written
+    pub(crate) fn jit_invalidate(&mut self, offset: Address) {
+        if let Some(jit) = self.jit.as_mut() {
+            for header in 0..=offset.min(jit.loops.len().saturating_sub(1)) {
+                if matches!(jit.loops[header], Some((_, end)) if end > offset) {
+                    jit.loops[header] = None;
+                    jit.counters[header] = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "jit"))]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use crate::assembler::assemble;
+    use crate::VirtualMachine;
+
+    //how a run ended: the fault it stopped with, the registers, stack and heap, and whether a
+    //loop was left compiled
+    #[derive(Debug, PartialEq)]
+    struct Outcome {
+        fault: Option<String>,
+        state: String,
+    }
+
+    fn run(source: &str, jit: bool) -> (Outcome, bool) {
+        let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 16);
+        if jit {
+            vm = vm.with_jit();
+        }
+        vm.trace = false;
+        //faults panic with their message
+        let fault = panic::catch_unwind(AssertUnwindSafe(|| vm.cpu())).err().map(|e| match e.downcast::<String>() {
+            Ok(message) => *message,
+            Err(_) => "panic".to_string(),
+        });
+        let compiled = vm.jit.as_ref().is_some_and(|jit| jit.loops.iter().any(Option::is_some));
+        (Outcome { fault, state: format!("{:?} {:?} {:?}", vm.reg, vm.stack, vm.data) }, compiled)
+    }
+
+    //the JIT has to compile the loop and end up where the decode loop does
+    fn same(source: &str) -> Outcome {
+        let (expected, _) = run(source, false);
+        let (outcome, compiled) = run(source, true);
+        assert!(compiled, "no loop was compiled");
+        assert_eq!(outcome, expected);
+        outcome
+    }
+
+    #[test]
+    fn supported_instructions() {
+        assert_eq!(same(include_str!("../programs/jit.asm")).fault, None);
+    }
+
+    //the benchmark loop over every integer type
+    #[test]
+    fn loop_variants() {
+        let source = include_str!("../programs/loop.asm").replace("200000u32", "300u32");
+        assert_eq!(same(&source).fault, None);
+        for ty in &["u16", "i16", "i32", "u64", "i64"] {
+            let source = source.replace("0xffffu32", &format!("0x7fff{}", ty)).replace("u32", ty);
+            assert_eq!(same(&source).fault, None);
+        }
+    }
+
+    //the second time round the inner loop its registers hold other types, so the compiled loop's
+    //type guard fails and it is interpreted and compiled again
+    #[test]
+    fn guard_failure() {
+        let outcome = same("
+            MOV R0, 2u8
+            MOV R1, 1u32
+            MOV R3, 3u32
+        outer:
+            MOV R2, 100u8
+        inner:
+            ADD R1, R1, R3
+            DJNZ R2, inner
+            VSTORER [0], R1
+            VPUSHR R1
+            MOV R1, -1i64
+            MOV R3, -3i64
+            DJNZ R0, outer
+            HALT
+        ");
+        assert_eq!(outcome.fault, None);
+    }
+
+    //a divisor that reaches zero in the compiled loop leaves it for the interpreter to report
+    #[test]
+    fn divide_by_zero() {
+        let outcome = same("
+            MOV R0, 200u32
+            MOV R1, 1000i32
+            MOV R2, 100i32
+            MOV R3, 1i32
+        top:
+            SUB R2, R2, R3
+            DIV R4, R1, R2
+            DJNZ R0, top
+            HALT
+        ");
+        assert!(outcome.fault.unwrap().starts_with("Integer division by zero"));
+    }
+
+    //i64::MIN / -1 would trap in the compiled code, so it is left to the interpreter, which wraps.
+    //The dividend counts down to the minimum on the last pass
+    #[test]
+    fn overflowing_division() {
+        for &(ty, start) in &[("i64", "-9223372036854775608i64"), ("i32", "-2147483448i32")] {
+            let source = "
+                MOV R0, 200u32
+                MOV R1, START
+                MOV R2, -1ty
+                MOV R3, 1ty
+            top:
+                SUB R1, R1, R3
+                DIV R4, R1, R2
+                MOD R5, R1, R2
+                DJNZ R0, top
+                HALT
+            ".replace("START", start).replace("ty", ty);
+            assert_eq!(same(&source).fault, None);
+        }
+    }
+}
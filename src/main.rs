@@ -1,26 +1,84 @@
-use std::mem;
-use std::convert::TryInto;
-use derive_more::*;
-
-#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Add, Sub)]
-enum Immediate {
-    None(),
-    U8(u8),
-    I8(i8),
-    U16(u16),
-    I16(i16),
-    U32(u32),
-    I32(i32),
-    U64(u64),
-    I64(i64),
-    F32(f32),
-    F64(f64)
+#![allow(clippy::upper_case_acronyms)]
+
+use std::env;
+use std::fs;
+use std::process;
+use std::panic;
+use std::time::Instant;
+use cache::DecodedCode;
+use word::Word;
+use profile::Profiler;
+use coverage::Coverage;
+use assembler::DebugInfo;
+use runtime::{absolute, code_location, code_target, decrement, float, float_pair, float_test, fma, negate, BinaryOp, Fault, Immediate, RegisterFile};
+
+#[macro_use]
+mod runtime;
+mod assembler;
+mod verifier;
+mod typecheck;
+mod cache;
+mod bench;
+mod word;
+mod threaded;
+mod aot;
+mod peephole;
+mod cfg;
+mod profile;
+mod coverage;
+mod snapshot;
+mod program;
+mod reader;
+#[cfg(feature = "jit")]
+mod jit;
+
+impl Immediate {
+    fn encode(&self, code: &mut Vec<u8>) {
+        match *self {
+            Immediate::U8(v) => code.extend_from_slice(&[0, v]),
+            Immediate::I8(v) => code.extend_from_slice(&[1, v as u8]),
+            Immediate::U16(v) => {
+                code.push(2);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::I16(v) => {
+                code.push(3);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::U32(v) => {
+                code.push(4);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::I32(v) => {
+                code.push(5);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::U64(v) => {
+                code.push(6);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::I64(v) => {
+                code.push(7);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::F32(v) => {
+                code.push(8);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::F64(v) => {
+                code.push(9);
+                code.extend_from_slice(&v.to_le_bytes());
+            },
+            Immediate::None() => code.push(0xff),
+        }
+    }
 }
 
 type Register = usize;
 type Address = usize;
+type Offset = isize;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 enum Instruction {
     NOP(),                          //do nothing
     MOV(Register, Immediate),       //mov immediate to reg
@@ -29,7 +87,7 @@ enum Instruction {
     JE(Register),                   //Jump if equal to location
     JNE(Register),                  //Jump if not equal to location
     JG(Register),                   //Jump if greater than
-    JL(Register),                   //Jump if less than
+    JL(Register),                   //Jump if less than or equal (greater flag clear)
     CMP(Register, Register),        //Compares two registers
     PRINTR(Register),               //print contents of register
     PRINTV(Address),                //print contents of immediate at address
@@ -40,205 +98,653 @@ enum Instruction {
     ADD(Register, Register),        //Add 2 registers and pushes result on stack
     SUB(Register, Register),        //Subtract 2 registers and pushes result on stack
     MUL(Register, Register),        //Multiple 2 registers and pushes result on stack
-    DIV(Register, Register),        //Divide 2 registers and pushes result on stack
+    DIV(Register, Register),        //Divides second register by first and pushes result on stack
     AND(Register, Register),        //Bitwise AND on 2 registers. pushes result on stack
     OR(Register, Register),         //Bitwise OR on 2 registers. pushes result on stack
     XOR(Register, Register),        //Bitwise XOR on 2 registers. pushes result on stack
-    SHR(Register, Immediate),       //Shifts register to the right by (immediate)
-    SHL(Register, Immediate),       //Shifts register to the left by (immediate)
+    SHR(Register, Immediate),       //Logical shift of (immediate) to the right by register
+    SHL(Register, Immediate),       //Shifts (immediate) to the left by register
     VPUSH(Immediate),               //Push immediate on to the stack
     VPUSHR(Register),               //Push register contents on the stack
     VPOP(Register),                 //pops immediate from stack to register
     CALL(Register),                 //call functon at address in register
     RET(),                          //return from routine
     HALT(),                         //bye bye
+    MOD(Register, Register),        //Remainder of second register divided by first, pushed on stack
+    NEG(Register),                  //Negates register and pushes result on stack
+    NOT(Register),                  //Bitwise NOT on register. pushes result on stack
+    ABS(Register),                  //Absolute value of register. pushes result on stack
+    MIN(Register, Register),        //Smaller of 2 registers. pushes result on stack
+    MAX(Register, Register),        //Larger of 2 registers. pushes result on stack
+    ROL(Register, Immediate),       //Rotates (immediate) to the left by register
+    ROR(Register, Immediate),       //Rotates (immediate) to the right by register
+    SAR(Register, Immediate),       //Arithmetic shift of (immediate) to the right by register
+    SQRT(Register),                 //Square root of float register. pushes result on stack
+    FLOOR(Register),                //Rounds float register down. pushes result on stack
+    CEIL(Register),                 //Rounds float register up. pushes result on stack
+    ROUND(Register),                //Rounds float register half away from zero. pushes result on stack
+    TRUNC(Register),                //Rounds float register towards zero. pushes result on stack
+    FMA(Register, Register, Register), //Fused multiply-add (reg1 * reg2 + reg3). pushes result on stack
+    SIN(Register),                  //Sine of float register (radians). pushes result on stack
+    COS(Register),                  //Cosine of float register (radians). pushes result on stack
+    TAN(Register),                  //Tangent of float register (radians). pushes result on stack
+    EXP(Register),                  //e raised to float register. pushes result on stack
+    LOG(Register),                  //Natural logarithm of float register. pushes result on stack
+    POW(Register, Register),        //reg1 raised to the power of reg2. pushes result on stack
+    COPYSIGN(Register, Register),   //Magnitude of reg1 with the sign of reg2. pushes result on stack
+    ISNAN(Register),                //Sets equal flag if float register is NaN
+    ISINF(Register),                //Sets equal flag if float register is infinite
+    POPCNT(Register),               //Counts set bits in register. pushes result on stack
+    CLZ(Register),                  //Counts leading zero bits in register. pushes result on stack
+    CTZ(Register),                  //Counts trailing zero bits in register. pushes result on stack
+    BSWAP(Register),                //Reverses byte order of register. pushes result on stack
+    BT(Register, Register),         //Sets equal flag if bit (reg2) of reg1 is set
+    BTS(Register, Register),        //Sets bit (reg2) of reg1. pushes result on stack
+    BTR(Register, Register),        //Clears bit (reg2) of reg1. pushes result on stack
+    SHRR(Register, Register),       //Logical shift of reg1 to the right by reg2
+    SHLR(Register, Register),       //Shifts reg1 to the left by reg2
+    SARR(Register, Register),       //Arithmetic shift of reg1 to the right by reg2
+    ROLR(Register, Register),       //Rotates reg1 to the left by reg2
+    RORR(Register, Register),       //Rotates reg1 to the right by reg2
+    ADD3(Register, Register, Register),  //reg1 = reg2 + reg3
+    ADDI(Register, Register, Immediate), //reg1 = reg2 + (immediate)
+    SUB3(Register, Register, Register),  //reg1 = reg2 - reg3
+    SUBI(Register, Register, Immediate), //reg1 = reg2 - (immediate)
+    MUL3(Register, Register, Register),  //reg1 = reg2 * reg3
+    MULI(Register, Register, Immediate), //reg1 = reg2 * (immediate)
+    DIV3(Register, Register, Register),  //reg1 = reg2 / reg3
+    DIVI(Register, Register, Immediate), //reg1 = reg2 / (immediate)
+    MOD3(Register, Register, Register),  //reg1 = reg2 % reg3
+    MODI(Register, Register, Immediate), //reg1 = reg2 % (immediate)
+    AND3(Register, Register, Register),  //reg1 = reg2 & reg3
+    ANDI(Register, Register, Immediate), //reg1 = reg2 & (immediate)
+    OR3(Register, Register, Register),   //reg1 = reg2 | reg3
+    ORI(Register, Register, Immediate),  //reg1 = reg2 | (immediate)
+    XOR3(Register, Register, Register),  //reg1 = reg2 ^ reg3
+    XORI(Register, Register, Immediate), //reg1 = reg2 ^ (immediate)
+    MIN3(Register, Register, Register),  //reg1 = min(reg2, reg3)
+    MINI(Register, Register, Immediate), //reg1 = min(reg2, (immediate))
+    MAX3(Register, Register, Register),  //reg1 = max(reg2, reg3)
+    MAXI(Register, Register, Immediate), //reg1 = max(reg2, (immediate))
+    SHR3(Register, Register, Register),  //reg1 = reg2 >> reg3 (logical)
+    SHRI(Register, Register, Immediate), //reg1 = reg2 >> (immediate) (logical)
+    SHL3(Register, Register, Register),  //reg1 = reg2 << reg3
+    SHLI(Register, Register, Immediate), //reg1 = reg2 << (immediate)
+    SAR3(Register, Register, Register),  //reg1 = reg2 >> reg3 (arithmetic)
+    SARI(Register, Register, Immediate), //reg1 = reg2 >> (immediate) (arithmetic)
+    ROL3(Register, Register, Register),  //reg1 = reg2 rotated left by reg3
+    ROLI(Register, Register, Immediate), //reg1 = reg2 rotated left by (immediate)
+    ROR3(Register, Register, Register),  //reg1 = reg2 rotated right by reg3
+    RORI(Register, Register, Immediate), //reg1 = reg2 rotated right by (immediate)
+    SADD(),                              //pops b then a, pushes a + b
+    SSUB(),                              //pops b then a, pushes a - b
+    SMUL(),                              //pops b then a, pushes a * b
+    SDIV(),                              //pops b then a, pushes a / b
+    SMOD(),                              //pops b then a, pushes a % b
+    SAND(),                              //pops b then a, pushes a & b
+    SOR(),                               //pops b then a, pushes a | b
+    SXOR(),                              //pops b then a, pushes a ^ b
+    SMIN(),                              //pops b then a, pushes min(a, b)
+    SMAX(),                              //pops b then a, pushes max(a, b)
+    SSHR(),                              //pops b then a, pushes a >> b (logical)
+    SSHL(),                              //pops b then a, pushes a << b
+    SSAR(),                              //pops b then a, pushes a >> b (arithmetic)
+    SROL(),                              //pops b then a, pushes a rotated left by b
+    SROR(),                              //pops b then a, pushes a rotated right by b
+    SCMP(),                              //pops b then a, compares a with b
+    SJMP(),                              //pops location and jumps to it
+    SJE(),                               //pops location, jumps to it if equal
+    SJNE(),                              //pops location, jumps to it if not equal
+    SJG(),                               //pops location, jumps to it if greater than
+    SJLE(),                              //pops location, jumps to it if less or equal
+    SSTORE(Address),                     //pops immediate from stack into VMHeap at address
+    DUP(),                               //pushes a copy of the top of the stack
+    DROP(),                              //discards the top of the stack
+    SWAP(),                              //swaps the top 2 stack items
+    OVER(),                              //pushes a copy of the second stack item
+    ROT(),                               //moves the third stack item to the top
+    PICK(usize),                         //pushes a copy of the nth stack item (0 is the top)
+    ROLL(usize),                         //moves the nth stack item to the top (0 is the top)
+    DEPTH(),                             //pushes the number of stack items as U64
+    JMPA(Address),                       //jump to embedded location
+    JEA(Address),                        //jump to embedded location if equal
+    JNEA(Address),                       //jump to embedded location if not equal
+    JGA(Address),                        //jump to embedded location if greater than
+    JLEA(Address),                       //jump to embedded location if less or equal
+    CALLA(Address),                      //call function at embedded location
+    BRA(Offset),                         //branch by offset from the next instruction
+    BEQ(Offset),                         //branch by offset if equal
+    BNE(Offset),                         //branch by offset if not equal
+    BGT(Offset),                         //branch by offset if greater than
+    BLE(Offset),                         //branch by offset if less or equal
+    BSR(Offset),                         //call function at offset from the next instruction
+    CBEQ(Register, Register, Offset),    //branch by offset if reg1 == reg2
+    CBNE(Register, Register, Offset),    //branch by offset if reg1 != reg2
+    CBGT(Register, Register, Offset),    //branch by offset if reg1 > reg2
+    CBLT(Register, Register, Offset),    //branch by offset if reg1 < reg2
+    CBEQI(Register, Immediate, Offset),  //branch by offset if reg == (immediate)
+    CBNEI(Register, Immediate, Offset),  //branch by offset if reg != (immediate)
+    CBGTI(Register, Immediate, Offset),  //branch by offset if reg > (immediate)
+    CBLTI(Register, Immediate, Offset),  //branch by offset if reg < (immediate)
+    DJNZ(Register, Offset),              //decrements reg, branch by offset if it is not zero
+    SWITCH(Register, Address, Vec<Address>), //jump to the location indexed by reg, or the default if out of range
+    CMOVE(Register, Register),           //dst = src if flag_eq is set
+    CMOVNE(Register, Register),          //dst = src if flag_eq is clear
+    CMOVG(Register, Register),           //dst = src if flag_gt is set
+    CMOVL(Register, Register),           //dst = src if neither flag is set
+    CMOVGE(Register, Register),          //dst = src if flag_gt or flag_eq is set
+    CMOVLE(Register, Register),          //dst = src if flag_gt is clear
+    SELECT(Register, Register, Register, Register), //dst = reg1 if cond is nonzero, reg2 otherwise
+    CWRITE(Address, Register),           //write the u8 in reg to the code byte at address
+}
+
+impl Instruction {
+    //appends the bytecode for this instruction, the inverse of VirtualMachine::decode
+    fn encode(&self, code: &mut Vec<u8>) {
+        match *self {
+            Instruction::NOP() => code.push(0),
+            Instruction::MOV(reg, var) => {
+                code.extend_from_slice(&[1, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MOVR(reg1, reg2) => code.extend_from_slice(&[2, reg1 as u8, reg2 as u8]),
+            Instruction::JMP(reg) => code.extend_from_slice(&[3, reg as u8]),
+            Instruction::JE(reg) => code.extend_from_slice(&[4, reg as u8]),
+            Instruction::JNE(reg) => code.extend_from_slice(&[5, reg as u8]),
+            Instruction::CMP(reg1, reg2) => code.extend_from_slice(&[6, reg1 as u8, reg2 as u8]),
+            Instruction::PRINTR(reg) => code.extend_from_slice(&[7, reg as u8]),
+            Instruction::PRINTV(addr) => code.extend_from_slice(&[8, addr as u8]),
+            Instruction::VSTORE(addr, var) => {
+                code.extend_from_slice(&[9, addr as u8]);
+                var.encode(code);
+            },
+            Instruction::VLOAD(addr) => code.extend_from_slice(&[10, addr as u8]),
+            Instruction::ADD(reg1, reg2) => code.extend_from_slice(&[11, reg1 as u8, reg2 as u8]),
+            Instruction::SUB(reg1, reg2) => code.extend_from_slice(&[12, reg1 as u8, reg2 as u8]),
+            Instruction::MUL(reg1, reg2) => code.extend_from_slice(&[13, reg1 as u8, reg2 as u8]),
+            Instruction::DIV(reg1, reg2) => code.extend_from_slice(&[14, reg1 as u8, reg2 as u8]),
+            Instruction::VSTORER(addr, reg) => code.extend_from_slice(&[15, addr as u8, reg as u8]),
+            Instruction::VLOADR(reg, addr) => code.extend_from_slice(&[16, reg as u8, addr as u8]),
+            Instruction::VPUSH(var) => {
+                code.push(17);
+                var.encode(code);
+            },
+            Instruction::VPUSHR(reg) => code.extend_from_slice(&[18, reg as u8]),
+            Instruction::VPOP(reg) => code.extend_from_slice(&[19, reg as u8]),
+            Instruction::CALL(reg) => code.extend_from_slice(&[20, reg as u8]),
+            Instruction::RET() => code.push(21),
+            Instruction::HALT() => code.push(22),
+            Instruction::JG(reg) => code.extend_from_slice(&[23, reg as u8]),
+            Instruction::JL(reg) => code.extend_from_slice(&[24, reg as u8]),
+            Instruction::AND(reg1, reg2) => code.extend_from_slice(&[25, reg1 as u8, reg2 as u8]),
+            Instruction::OR(reg1, reg2) => code.extend_from_slice(&[26, reg1 as u8, reg2 as u8]),
+            Instruction::XOR(reg1, reg2) => code.extend_from_slice(&[27, reg1 as u8, reg2 as u8]),
+            Instruction::SHR(reg, var) => {
+                code.extend_from_slice(&[28, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SHL(reg, var) => {
+                code.extend_from_slice(&[29, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MOD(reg1, reg2) => code.extend_from_slice(&[30, reg1 as u8, reg2 as u8]),
+            Instruction::NEG(reg) => code.extend_from_slice(&[31, reg as u8]),
+            Instruction::NOT(reg) => code.extend_from_slice(&[32, reg as u8]),
+            Instruction::ABS(reg) => code.extend_from_slice(&[33, reg as u8]),
+            Instruction::MIN(reg1, reg2) => code.extend_from_slice(&[34, reg1 as u8, reg2 as u8]),
+            Instruction::MAX(reg1, reg2) => code.extend_from_slice(&[35, reg1 as u8, reg2 as u8]),
+            Instruction::ROL(reg, var) => {
+                code.extend_from_slice(&[36, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::ROR(reg, var) => {
+                code.extend_from_slice(&[37, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SAR(reg, var) => {
+                code.extend_from_slice(&[38, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SQRT(reg) => code.extend_from_slice(&[39, reg as u8]),
+            Instruction::FLOOR(reg) => code.extend_from_slice(&[40, reg as u8]),
+            Instruction::CEIL(reg) => code.extend_from_slice(&[41, reg as u8]),
+            Instruction::ROUND(reg) => code.extend_from_slice(&[42, reg as u8]),
+            Instruction::TRUNC(reg) => code.extend_from_slice(&[43, reg as u8]),
+            Instruction::FMA(reg1, reg2, reg3) => code.extend_from_slice(&[44, reg1 as u8, reg2 as u8, reg3 as u8]),
+            Instruction::SIN(reg) => code.extend_from_slice(&[45, reg as u8]),
+            Instruction::COS(reg) => code.extend_from_slice(&[46, reg as u8]),
+            Instruction::TAN(reg) => code.extend_from_slice(&[47, reg as u8]),
+            Instruction::EXP(reg) => code.extend_from_slice(&[48, reg as u8]),
+            Instruction::LOG(reg) => code.extend_from_slice(&[49, reg as u8]),
+            Instruction::POW(reg1, reg2) => code.extend_from_slice(&[50, reg1 as u8, reg2 as u8]),
+            Instruction::COPYSIGN(reg1, reg2) => code.extend_from_slice(&[51, reg1 as u8, reg2 as u8]),
+            Instruction::ISNAN(reg) => code.extend_from_slice(&[52, reg as u8]),
+            Instruction::ISINF(reg) => code.extend_from_slice(&[53, reg as u8]),
+            Instruction::POPCNT(reg) => code.extend_from_slice(&[54, reg as u8]),
+            Instruction::CLZ(reg) => code.extend_from_slice(&[55, reg as u8]),
+            Instruction::CTZ(reg) => code.extend_from_slice(&[56, reg as u8]),
+            Instruction::BSWAP(reg) => code.extend_from_slice(&[57, reg as u8]),
+            Instruction::BT(reg1, reg2) => code.extend_from_slice(&[58, reg1 as u8, reg2 as u8]),
+            Instruction::BTS(reg1, reg2) => code.extend_from_slice(&[59, reg1 as u8, reg2 as u8]),
+            Instruction::BTR(reg1, reg2) => code.extend_from_slice(&[60, reg1 as u8, reg2 as u8]),
+            Instruction::SHRR(reg1, reg2) => code.extend_from_slice(&[61, reg1 as u8, reg2 as u8]),
+            Instruction::SHLR(reg1, reg2) => code.extend_from_slice(&[62, reg1 as u8, reg2 as u8]),
+            Instruction::SARR(reg1, reg2) => code.extend_from_slice(&[63, reg1 as u8, reg2 as u8]),
+            Instruction::ROLR(reg1, reg2) => code.extend_from_slice(&[64, reg1 as u8, reg2 as u8]),
+            Instruction::RORR(reg1, reg2) => code.extend_from_slice(&[65, reg1 as u8, reg2 as u8]),
+            Instruction::ADD3(dst, reg1, reg2) => code.extend_from_slice(&[66, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::ADDI(dst, reg, var) => {
+                code.extend_from_slice(&[67, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SUB3(dst, reg1, reg2) => code.extend_from_slice(&[68, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::SUBI(dst, reg, var) => {
+                code.extend_from_slice(&[69, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MUL3(dst, reg1, reg2) => code.extend_from_slice(&[70, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::MULI(dst, reg, var) => {
+                code.extend_from_slice(&[71, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::DIV3(dst, reg1, reg2) => code.extend_from_slice(&[72, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::DIVI(dst, reg, var) => {
+                code.extend_from_slice(&[73, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MOD3(dst, reg1, reg2) => code.extend_from_slice(&[74, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::MODI(dst, reg, var) => {
+                code.extend_from_slice(&[75, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::AND3(dst, reg1, reg2) => code.extend_from_slice(&[76, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::ANDI(dst, reg, var) => {
+                code.extend_from_slice(&[77, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::OR3(dst, reg1, reg2) => code.extend_from_slice(&[78, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::ORI(dst, reg, var) => {
+                code.extend_from_slice(&[79, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::XOR3(dst, reg1, reg2) => code.extend_from_slice(&[80, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::XORI(dst, reg, var) => {
+                code.extend_from_slice(&[81, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MIN3(dst, reg1, reg2) => code.extend_from_slice(&[82, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::MINI(dst, reg, var) => {
+                code.extend_from_slice(&[83, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::MAX3(dst, reg1, reg2) => code.extend_from_slice(&[84, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::MAXI(dst, reg, var) => {
+                code.extend_from_slice(&[85, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SHR3(dst, reg1, reg2) => code.extend_from_slice(&[86, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::SHRI(dst, reg, var) => {
+                code.extend_from_slice(&[87, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SHL3(dst, reg1, reg2) => code.extend_from_slice(&[88, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::SHLI(dst, reg, var) => {
+                code.extend_from_slice(&[89, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SAR3(dst, reg1, reg2) => code.extend_from_slice(&[90, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::SARI(dst, reg, var) => {
+                code.extend_from_slice(&[91, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::ROL3(dst, reg1, reg2) => code.extend_from_slice(&[92, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::ROLI(dst, reg, var) => {
+                code.extend_from_slice(&[93, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::ROR3(dst, reg1, reg2) => code.extend_from_slice(&[94, dst as u8, reg1 as u8, reg2 as u8]),
+            Instruction::RORI(dst, reg, var) => {
+                code.extend_from_slice(&[95, dst as u8, reg as u8]);
+                var.encode(code);
+            },
+            Instruction::SADD() => code.push(96),
+            Instruction::SSUB() => code.push(97),
+            Instruction::SMUL() => code.push(98),
+            Instruction::SDIV() => code.push(99),
+            Instruction::SMOD() => code.push(100),
+            Instruction::SAND() => code.push(101),
+            Instruction::SOR() => code.push(102),
+            Instruction::SXOR() => code.push(103),
+            Instruction::SMIN() => code.push(104),
+            Instruction::SMAX() => code.push(105),
+            Instruction::SSHR() => code.push(106),
+            Instruction::SSHL() => code.push(107),
+            Instruction::SSAR() => code.push(108),
+            Instruction::SROL() => code.push(109),
+            Instruction::SROR() => code.push(110),
+            Instruction::SCMP() => code.push(111),
+            Instruction::SJMP() => code.push(112),
+            Instruction::SJE() => code.push(113),
+            Instruction::SJNE() => code.push(114),
+            Instruction::SJG() => code.push(115),
+            Instruction::SJLE() => code.push(116),
+            Instruction::SSTORE(addr) => code.extend_from_slice(&[117, addr as u8]),
+            Instruction::DUP() => code.push(118),
+            Instruction::DROP() => code.push(119),
+            Instruction::SWAP() => code.push(120),
+            Instruction::OVER() => code.push(121),
+            Instruction::ROT() => code.push(122),
+            Instruction::PICK(n) => code.extend_from_slice(&[123, n as u8]),
+            Instruction::ROLL(n) => code.extend_from_slice(&[124, n as u8]),
+            Instruction::DEPTH() => code.push(125),
+            Instruction::JMPA(addr) => {
+                code.push(126);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::JEA(addr) => {
+                code.push(127);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::JNEA(addr) => {
+                code.push(128);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::JGA(addr) => {
+                code.push(129);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::JLEA(addr) => {
+                code.push(130);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::CALLA(addr) => {
+                code.push(131);
+                code.extend_from_slice(&code_location(addr).to_le_bytes());
+            },
+            Instruction::BRA(offset) => {
+                code.push(132);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::BEQ(offset) => {
+                code.push(133);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::BNE(offset) => {
+                code.push(134);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::BGT(offset) => {
+                code.push(135);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::BLE(offset) => {
+                code.push(136);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::BSR(offset) => {
+                code.push(137);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBEQ(reg1, reg2, offset) => {
+                code.extend_from_slice(&[138, reg1 as u8, reg2 as u8]);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBNE(reg1, reg2, offset) => {
+                code.extend_from_slice(&[139, reg1 as u8, reg2 as u8]);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBGT(reg1, reg2, offset) => {
+                code.extend_from_slice(&[140, reg1 as u8, reg2 as u8]);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBLT(reg1, reg2, offset) => {
+                code.extend_from_slice(&[141, reg1 as u8, reg2 as u8]);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBEQI(reg, var, offset) => {
+                code.extend_from_slice(&[142, reg as u8]);
+                var.encode(code);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBNEI(reg, var, offset) => {
+                code.extend_from_slice(&[143, reg as u8]);
+                var.encode(code);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBGTI(reg, var, offset) => {
+                code.extend_from_slice(&[144, reg as u8]);
+                var.encode(code);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::CBLTI(reg, var, offset) => {
+                code.extend_from_slice(&[145, reg as u8]);
+                var.encode(code);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::DJNZ(reg, offset) => {
+                code.extend_from_slice(&[146, reg as u8]);
+                code.extend_from_slice(&(offset as i16).to_le_bytes());
+            },
+            Instruction::SWITCH(reg, default, ref targets) => {
+                code.extend_from_slice(&[147, reg as u8]);
+                code.extend_from_slice(&code_location(default).to_le_bytes());
+                code.extend_from_slice(&(targets.len() as u16).to_le_bytes());
+                for &target in targets {
+                    code.extend_from_slice(&code_location(target).to_le_bytes());
+                }
+            },
+            Instruction::CMOVE(reg1, reg2) => code.extend_from_slice(&[148, reg1 as u8, reg2 as u8]),
+            Instruction::CMOVNE(reg1, reg2) => code.extend_from_slice(&[149, reg1 as u8, reg2 as u8]),
+            Instruction::CMOVG(reg1, reg2) => code.extend_from_slice(&[150, reg1 as u8, reg2 as u8]),
+            Instruction::CMOVL(reg1, reg2) => code.extend_from_slice(&[151, reg1 as u8, reg2 as u8]),
+            Instruction::CMOVGE(reg1, reg2) => code.extend_from_slice(&[152, reg1 as u8, reg2 as u8]),
+            Instruction::CMOVLE(reg1, reg2) => code.extend_from_slice(&[153, reg1 as u8, reg2 as u8]),
+            Instruction::SELECT(dst, cond, reg1, reg2) => code.extend_from_slice(&[154, dst as u8, cond as u8, reg1 as u8, reg2 as u8]),
+            Instruction::CWRITE(addr, reg) => {
+                code.push(155);
+                code.extend_from_slice(&(addr as u16).to_le_bytes());
+                code.push(reg as u8);
+            },
+        }
+    }
 }
 
 struct VirtualMachine {
     ip : Address,
     flag_eq : bool,
     flag_gt: bool,
-    reg : [Immediate; 8],
+    registers : RegisterFile,
+    reg : Vec<Word>,
     code : Vec<u8>,
     stack : Vec<Immediate>,
     data : Vec<Immediate>,
     is_executing : bool,
+    trace : bool,
+    decoded : Option<DecodedCode>,
+    threaded : bool,
+    #[cfg(feature = "jit")]
+    jit : Option<jit::Jit>,
+    profiler : Option<Box<Profiler>>,
+    coverage : Option<Box<Coverage>>,
+    debug : DebugInfo,
+    //instructions left to run before pausing
+    pause_after : Option<u64>,
+    paused : bool,
+    fault : Option<Fault>,
 }
 
 impl VirtualMachine {
     fn new(c : Vec<u8>, heap_capacity: usize) -> Self {
-        VirtualMachine { ip: 0, flag_eq: false, flag_gt: false, reg: [Immediate::U8(0); 8], code: c, stack: Vec::new(), data: vec![Immediate::U8(0); heap_capacity], is_executing: false } 
+        let registers = RegisterFile::default();
+        VirtualMachine { ip: 0, flag_eq: false, flag_gt: false, registers, reg: vec![Word::from(Immediate::U8(0)); registers.count], code: c, stack: Vec::new(), data: vec![Immediate::U8(0); heap_capacity], is_executing: false, trace: true, decoded: None, threaded: false, #[cfg(feature = "jit")] jit: None, profiler: None, coverage: None, debug: DebugInfo::default(), pause_after: None, paused: false, fault: None }
     }
-    fn decode_immediate(&mut self) -> Immediate {
+    //replaces the register file, float bank registers start out as 0.0
+    fn with_registers(mut self, registers: RegisterFile) -> Self {
+        assert!(registers.is_valid(), "register file must have 1 to {} registers", RegisterFile::MAX);
+        self.reg = (0..registers.count).map(|r| Word::from(registers.initial(r))).collect();
+        self.registers = registers;
+        self
+    }
+    //attaches the assembler's or a program file's debug info, so faults and traces name source locations
+    fn with_debug_info(mut self, debug: DebugInfo) -> Self {
+        self.debug = debug;
+        self
+    }
+    //starts the trace line of the instruction at `start` with its source location, if known
+    fn trace_location(&self, start: Address) {
+        if let Some(location) = self.debug.lines.get(&start) {
+            print!("{}:{}:{}: ", self.debug.file, location.line, location.column);
+        }
+    }
+    //records a problem with the instruction being decoded, keeping the first one
+    fn decode_fault(&mut self, fault: Fault) {
+        self.fault.get_or_insert(fault);
+    }
+    //reads the next operand byte. Reading past the end of the code records a fault and yields 0
+    fn decode_byte(&mut self) -> u8 {
         self.ip += 1;
-        match self.code[self.ip] {
-            0 => {
-                self.ip += 1;
-                Immediate::U8(self.code[self.ip] as u8)
-            },
-            1 => {
-                self.ip += 1;
-                Immediate::I8(self.code[self.ip] as i8)
-            },
-            2 => {
-                self.ip += 1;
-                let size = mem::size_of::<u16>();
-                let value = u16::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U16(value)
-            },
-            3 => {
-                self.ip += 1;
-                let size = mem::size_of::<i16>();
-                let value = i16::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I16(value)
-            },
-            4 => {
-                self.ip += 1;
-                let size = mem::size_of::<u32>();
-                let value = u32::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U32(value)
-            },
-            5 => {
-                self.ip += 1;
-                let size = mem::size_of::<i32>();
-                let value = i32::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I32(value)
-            },
-            6 => {
-                self.ip += 1;
-                let size = mem::size_of::<u64>();
-                let value = u64::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U64(value)
-            },
-            7 => {
-                self.ip += 1;
-                let size = mem::size_of::<i64>();
-                let value = i64::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I64(value)
-            },
-            8 => {
-                self.ip += 1;
-                let size = mem::size_of::<f32>();
-                let value = f32::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::F32(value)
-            },
-            9 => {
-                self.ip += 1;
-                let size = mem::size_of::<f64>();
-                let value = f64::from_le_bytes(self.code[self.ip..][..size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::F64(value)
-            },
-            _ => Immediate::None()
+        match self.code.get(self.ip) {
+            Some(&byte) => byte,
+            None => {
+                self.decode_fault(Fault::Truncated);
+                0
+            }
+        }
+    }
+    fn decode_bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.decode_byte();
+        }
+        bytes
+    }
+    //reads a register operand. A register outside the register file records a fault that stops the
+    //machine before the instruction runs
+    fn decode_register(&mut self) -> Register {
+        let reg = self.decode_byte() as Register;
+        if reg >= self.reg.len() {
+            self.decode_fault(Fault::BadRegister);
         }
+        reg
+    }
+    //reads a one byte heap address, which must be inside the heap
+    fn decode_heap_address(&mut self) -> Address {
+        let addr = self.decode_byte() as Address;
+        if addr >= self.data.len() {
+            self.decode_fault(Fault::BadHeapAddress);
+        }
+        addr
+    }
+    fn decode_immediate(&mut self) -> Immediate {
+        match self.decode_byte() {
+            0 => Immediate::U8(u8::from_le_bytes(self.decode_bytes())),
+            1 => Immediate::I8(i8::from_le_bytes(self.decode_bytes())),
+            2 => Immediate::U16(u16::from_le_bytes(self.decode_bytes())),
+            3 => Immediate::I16(i16::from_le_bytes(self.decode_bytes())),
+            4 => Immediate::U32(u32::from_le_bytes(self.decode_bytes())),
+            5 => Immediate::I32(i32::from_le_bytes(self.decode_bytes())),
+            6 => Immediate::U64(u64::from_le_bytes(self.decode_bytes())),
+            7 => Immediate::I64(i64::from_le_bytes(self.decode_bytes())),
+            8 => Immediate::F32(f32::from_le_bytes(self.decode_bytes())),
+            9 => Immediate::F64(f64::from_le_bytes(self.decode_bytes())),
+            0xff => Immediate::None(),
+            _ => {
+                self.decode_fault(Fault::UnknownImmediate);
+                Immediate::None()
+            }
+        }
+    }
+
+    //reads a little endian u16 code address
+    fn decode_address(&mut self) -> Address {
+        u16::from_le_bytes(self.decode_bytes()) as Address
+    }
+
+    //reads a jump target, a code location naming the byte before the instruction
+    fn decode_target(&mut self) -> Address {
+        code_target(u16::from_le_bytes(self.decode_bytes()))
+    }
+
+    //reads a little endian i16 jump offset
+    fn decode_offset(&mut self) -> Offset {
+        i16::from_le_bytes(self.decode_bytes()) as Offset
     }
 
     fn decode(&mut self) -> Instruction {
         match self.code[self.ip] {
             0 => Instruction::NOP(),
             1 => {
-                self.ip += 1;
-                let register = self.code[self.ip] as Register;
+                let register = self.decode_register();
                 let var = self.decode_immediate();
                 Instruction::MOV(register, var)
             },
             2 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::MOVR(reg1, reg2)
             },
             3 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::JMP(reg)
             },
             4 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::JE(reg)
             },
             5 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::JNE(reg)
             },
             6 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::CMP(reg1, reg2)
             },
             7 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::PRINTR(reg)
             },
             8 => {
-                self.ip += 1;
-                let addr = self.code[self.ip] as Address;
+                let addr = self.decode_heap_address();
                 Instruction::PRINTV(addr)
             },
             9 => {
-                self.ip += 1;
-                let addr = self.code[self.ip] as Address;
+                let addr = self.decode_heap_address();
                 let var = self.decode_immediate();
                 Instruction::VSTORE(addr, var)
             },
             10 => {
-                self.ip += 1;
-                let addr = self.code[self.ip] as Address;
+                let addr = self.decode_heap_address();
                 Instruction::VLOAD(addr)
             },
             11 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::ADD(reg1, reg2)
             },
             12 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::SUB(reg1, reg2)
             },
             13 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::MUL(reg1, reg2)
             },
             14 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::DIV(reg1, reg2)
             },
             15 => {
-                self.ip += 1;
-                let addr = self.code[self.ip] as Address;
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let addr = self.decode_heap_address();
+                let reg = self.decode_register();
                 Instruction::VSTORER(addr, reg)
             },
             16 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
-                self.ip += 1;
-                let addr = self.code[self.ip] as Address;
+                let reg = self.decode_register();
+                let addr = self.decode_heap_address();
                 Instruction::VLOADR(reg, addr)
             },
             17 => {
@@ -246,93 +752,610 @@ impl VirtualMachine {
                 Instruction::VPUSH(var)
             },
             18 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::VPUSHR(reg)
             },
             19 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::VPOP(reg)
             },
             20 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::CALL(reg)
             },
             21 => Instruction::RET(),
             22 => Instruction::HALT(),
             23 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::JG(reg)
             },
             24 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 Instruction::JL(reg)
             },
             25 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::AND(reg1, reg2)
             },
             26 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::OR(reg1, reg2)
             },
             27 => {
-                self.ip += 1;
-                let reg1 = self.code[self.ip] as Register;
-                self.ip += 1;
-                let reg2 = self.code[self.ip] as Register;
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
                 Instruction::XOR(reg1, reg2)
             },
             28 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 let var = self.decode_immediate();
                 Instruction::SHR(reg, var)
             },
             29 => {
-                self.ip += 1;
-                let reg = self.code[self.ip] as Register;
+                let reg = self.decode_register();
                 let var = self.decode_immediate();
                 Instruction::SHL(reg, var)
             },
-            _ => Instruction::NOP(),
+            30 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MOD(reg1, reg2)
+            },
+            31 => {
+                let reg = self.decode_register();
+                Instruction::NEG(reg)
+            },
+            32 => {
+                let reg = self.decode_register();
+                Instruction::NOT(reg)
+            },
+            33 => {
+                let reg = self.decode_register();
+                Instruction::ABS(reg)
+            },
+            34 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MIN(reg1, reg2)
+            },
+            35 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MAX(reg1, reg2)
+            },
+            36 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ROL(reg, var)
+            },
+            37 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ROR(reg, var)
+            },
+            38 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::SAR(reg, var)
+            },
+            39 => {
+                let reg = self.decode_register();
+                Instruction::SQRT(reg)
+            },
+            40 => {
+                let reg = self.decode_register();
+                Instruction::FLOOR(reg)
+            },
+            41 => {
+                let reg = self.decode_register();
+                Instruction::CEIL(reg)
+            },
+            42 => {
+                let reg = self.decode_register();
+                Instruction::ROUND(reg)
+            },
+            43 => {
+                let reg = self.decode_register();
+                Instruction::TRUNC(reg)
+            },
+            44 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                let reg3 = self.decode_register();
+                Instruction::FMA(reg1, reg2, reg3)
+            },
+            45 => {
+                let reg = self.decode_register();
+                Instruction::SIN(reg)
+            },
+            46 => {
+                let reg = self.decode_register();
+                Instruction::COS(reg)
+            },
+            47 => {
+                let reg = self.decode_register();
+                Instruction::TAN(reg)
+            },
+            48 => {
+                let reg = self.decode_register();
+                Instruction::EXP(reg)
+            },
+            49 => {
+                let reg = self.decode_register();
+                Instruction::LOG(reg)
+            },
+            50 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::POW(reg1, reg2)
+            },
+            51 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::COPYSIGN(reg1, reg2)
+            },
+            52 => {
+                let reg = self.decode_register();
+                Instruction::ISNAN(reg)
+            },
+            53 => {
+                let reg = self.decode_register();
+                Instruction::ISINF(reg)
+            },
+            54 => {
+                let reg = self.decode_register();
+                Instruction::POPCNT(reg)
+            },
+            55 => {
+                let reg = self.decode_register();
+                Instruction::CLZ(reg)
+            },
+            56 => {
+                let reg = self.decode_register();
+                Instruction::CTZ(reg)
+            },
+            57 => {
+                let reg = self.decode_register();
+                Instruction::BSWAP(reg)
+            },
+            58 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::BT(reg1, reg2)
+            },
+            59 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::BTS(reg1, reg2)
+            },
+            60 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::BTR(reg1, reg2)
+            },
+            61 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SHRR(reg1, reg2)
+            },
+            62 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SHLR(reg1, reg2)
+            },
+            63 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SARR(reg1, reg2)
+            },
+            64 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::ROLR(reg1, reg2)
+            },
+            65 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::RORR(reg1, reg2)
+            },
+            66 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::ADD3(dst, reg1, reg2)
+            },
+            67 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ADDI(dst, reg, var)
+            },
+            68 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SUB3(dst, reg1, reg2)
+            },
+            69 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::SUBI(dst, reg, var)
+            },
+            70 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MUL3(dst, reg1, reg2)
+            },
+            71 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::MULI(dst, reg, var)
+            },
+            72 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::DIV3(dst, reg1, reg2)
+            },
+            73 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::DIVI(dst, reg, var)
+            },
+            74 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MOD3(dst, reg1, reg2)
+            },
+            75 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::MODI(dst, reg, var)
+            },
+            76 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::AND3(dst, reg1, reg2)
+            },
+            77 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ANDI(dst, reg, var)
+            },
+            78 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::OR3(dst, reg1, reg2)
+            },
+            79 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ORI(dst, reg, var)
+            },
+            80 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::XOR3(dst, reg1, reg2)
+            },
+            81 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::XORI(dst, reg, var)
+            },
+            82 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MIN3(dst, reg1, reg2)
+            },
+            83 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::MINI(dst, reg, var)
+            },
+            84 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::MAX3(dst, reg1, reg2)
+            },
+            85 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::MAXI(dst, reg, var)
+            },
+            86 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SHR3(dst, reg1, reg2)
+            },
+            87 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::SHRI(dst, reg, var)
+            },
+            88 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SHL3(dst, reg1, reg2)
+            },
+            89 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::SHLI(dst, reg, var)
+            },
+            90 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SAR3(dst, reg1, reg2)
+            },
+            91 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::SARI(dst, reg, var)
+            },
+            92 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::ROL3(dst, reg1, reg2)
+            },
+            93 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::ROLI(dst, reg, var)
+            },
+            94 => {
+                let dst = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::ROR3(dst, reg1, reg2)
+            },
+            95 => {
+                let dst = self.decode_register();
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                Instruction::RORI(dst, reg, var)
+            },
+            96 => Instruction::SADD(),
+            97 => Instruction::SSUB(),
+            98 => Instruction::SMUL(),
+            99 => Instruction::SDIV(),
+            100 => Instruction::SMOD(),
+            101 => Instruction::SAND(),
+            102 => Instruction::SOR(),
+            103 => Instruction::SXOR(),
+            104 => Instruction::SMIN(),
+            105 => Instruction::SMAX(),
+            106 => Instruction::SSHR(),
+            107 => Instruction::SSHL(),
+            108 => Instruction::SSAR(),
+            109 => Instruction::SROL(),
+            110 => Instruction::SROR(),
+            111 => Instruction::SCMP(),
+            112 => Instruction::SJMP(),
+            113 => Instruction::SJE(),
+            114 => Instruction::SJNE(),
+            115 => Instruction::SJG(),
+            116 => Instruction::SJLE(),
+            117 => {
+                let addr = self.decode_heap_address();
+                Instruction::SSTORE(addr)
+            },
+            118 => Instruction::DUP(),
+            119 => Instruction::DROP(),
+            120 => Instruction::SWAP(),
+            121 => Instruction::OVER(),
+            122 => Instruction::ROT(),
+            123 => {
+                let n = self.decode_byte() as usize;
+                Instruction::PICK(n)
+            },
+            124 => {
+                let n = self.decode_byte() as usize;
+                Instruction::ROLL(n)
+            },
+            125 => Instruction::DEPTH(),
+            126 => {
+                let addr = self.decode_target();
+                Instruction::JMPA(addr)
+            },
+            127 => {
+                let addr = self.decode_target();
+                Instruction::JEA(addr)
+            },
+            128 => {
+                let addr = self.decode_target();
+                Instruction::JNEA(addr)
+            },
+            129 => {
+                let addr = self.decode_target();
+                Instruction::JGA(addr)
+            },
+            130 => {
+                let addr = self.decode_target();
+                Instruction::JLEA(addr)
+            },
+            131 => {
+                let addr = self.decode_target();
+                Instruction::CALLA(addr)
+            },
+            132 => {
+                let offset = self.decode_offset();
+                Instruction::BRA(offset)
+            },
+            133 => {
+                let offset = self.decode_offset();
+                Instruction::BEQ(offset)
+            },
+            134 => {
+                let offset = self.decode_offset();
+                Instruction::BNE(offset)
+            },
+            135 => {
+                let offset = self.decode_offset();
+                Instruction::BGT(offset)
+            },
+            136 => {
+                let offset = self.decode_offset();
+                Instruction::BLE(offset)
+            },
+            137 => {
+                let offset = self.decode_offset();
+                Instruction::BSR(offset)
+            },
+            138 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                let offset = self.decode_offset();
+                Instruction::CBEQ(reg1, reg2, offset)
+            },
+            139 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                let offset = self.decode_offset();
+                Instruction::CBNE(reg1, reg2, offset)
+            },
+            140 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                let offset = self.decode_offset();
+                Instruction::CBGT(reg1, reg2, offset)
+            },
+            141 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                let offset = self.decode_offset();
+                Instruction::CBLT(reg1, reg2, offset)
+            },
+            142 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                let offset = self.decode_offset();
+                Instruction::CBEQI(reg, var, offset)
+            },
+            143 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                let offset = self.decode_offset();
+                Instruction::CBNEI(reg, var, offset)
+            },
+            144 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                let offset = self.decode_offset();
+                Instruction::CBGTI(reg, var, offset)
+            },
+            145 => {
+                let reg = self.decode_register();
+                let var = self.decode_immediate();
+                let offset = self.decode_offset();
+                Instruction::CBLTI(reg, var, offset)
+            },
+            146 => {
+                let reg = self.decode_register();
+                let offset = self.decode_offset();
+                Instruction::DJNZ(reg, offset)
+            },
+            147 => {
+                let reg = self.decode_register();
+                let default = self.decode_target();
+                let count = self.decode_address();
+                let mut targets = Vec::with_capacity(count);
+                for _ in 0..count {
+                    targets.push(self.decode_target());
+                }
+                Instruction::SWITCH(reg, default, targets)
+            },
+            148 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVE(reg1, reg2)
+            },
+            149 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVNE(reg1, reg2)
+            },
+            150 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVG(reg1, reg2)
+            },
+            151 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVL(reg1, reg2)
+            },
+            152 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVGE(reg1, reg2)
+            },
+            153 => {
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::CMOVLE(reg1, reg2)
+            },
+            154 => {
+                let dst = self.decode_register();
+                let cond = self.decode_register();
+                let reg1 = self.decode_register();
+                let reg2 = self.decode_register();
+                Instruction::SELECT(dst, cond, reg1, reg2)
+            },
+            155 => {
+                let addr = self.decode_address();
+                let reg = self.decode_register();
+                Instruction::CWRITE(addr, reg)
+            },
+            _ => {
+                self.decode_fault(Fault::UnknownOpcode);
+                Instruction::NOP()
+            },
         }
     }
+    //records why the current instruction failed
+    helpers!();
+
     fn execute(&mut self, instr: Instruction) -> bool
     {
-        println!("Executing: {:?} \t  current ip: {:?}", instr, self.ip + 1);
+        if self.trace {
+            println!("Executing: {:?} \t  current ip: {:?}", instr, self.ip);
+        }
         match instr {
             Instruction::NOP() => true,
             Instruction::MOV(reg, var) => {
-                self.reg[reg] = var;
-                true
+                self.set_reg(reg, var)
             },
             Instruction::MOVR(reg1, reg2) => {
-                self.reg[reg1] = self.reg[reg2];
-                true
+                self.set_reg(reg1, self.reg[reg2].value())
             },
             Instruction::JMP(reg) => {
-                match self.reg[reg] {
-                    Immediate::U8(v) => {
-                        self.ip = v as Address;
-                    true
-                    }
-                    Immediate::U16(v) => {
-                        self.ip = v as Address;
-                        true
-                    }
-                    _ => false
-                }
+                self.jump(self.reg[reg].value())
             },
             Instruction::JE(reg) => {
                 if !self.flag_eq {
@@ -359,14 +1382,10 @@ impl VirtualMachine {
                 self.execute(Instruction::JMP(reg))
             },
             Instruction::CMP(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                self.flag_eq = v1 == v2;
-                self.flag_gt = v1 > v2;
-                true
+                self.compare(self.reg[reg1].value(), self.reg[reg2].value())
             },
             Instruction::PRINTR(reg) => {
-                let val = &self.reg[reg];
+                let val = &self.reg[reg].value();
                 println!("Printing: {:?}", val);
                 true
             },
@@ -384,304 +1403,503 @@ impl VirtualMachine {
                 true
             },
             Instruction::VSTORER(addr, reg) => {
-                self.data[addr] = self.reg[reg];
+                self.data[addr] = self.reg[reg].value();
                 true
             },
             Instruction::VLOADR(reg, addr) => {
-                self.reg[reg] = self.data[addr];
-                true
+                self.set_reg(reg, self.data[addr])
             },
             Instruction::ADD(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                if let Ok(r) = v1 + v2 {
-                    self.stack.push(r);
-                    true
-                } else {
-                    false
-                }
+                self.push_binary(BinaryOp::Add, self.reg[reg1], self.reg[reg2])
             },
             Instruction::SUB(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                if let Ok(r) = v1 - v2 {
-                    self.stack.push(r);
-                    true
-                } else {
-                    false
-                }
+                self.push_binary(BinaryOp::Sub, self.reg[reg1], self.reg[reg2])
             },
             Instruction::MUL(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u*v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u*v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u*v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u*v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u*v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u*v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u*v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u*v));
-                    },
-                    (Immediate::F32(v), Immediate::F32(u)) => {
-                        self.stack.push(Immediate::F32(u*v));
-                    },
-                    (Immediate::F64(v), Immediate::F64(u)) => {
-                        self.stack.push(Immediate::F64(u*v));
-                    },
-                    _ => {return false;}
-                }
-                true
+                self.push_binary(BinaryOp::Mul, self.reg[reg1], self.reg[reg2])
             },
             Instruction::DIV(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u/v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u/v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u/v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u/v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u/v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u/v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u/v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u/v));
-                    },
-                    (Immediate::F32(v), Immediate::F32(u)) => {
-                        self.stack.push(Immediate::F32(u/v));
-                    },
-                    (Immediate::F64(v), Immediate::F64(u)) => {
-                        self.stack.push(Immediate::F64(u/v));
-                    },
-                    _ => {return false;}
-                }
-                true
+                self.push_binary(BinaryOp::Div, self.reg[reg2], self.reg[reg1])
             },
             Instruction::VPUSH(var) => {
                 self.stack.push(var);
                 true
             },
             Instruction::VPUSHR(reg) => {
-                self.stack.push(self.reg[reg]);
+                self.stack.push(self.reg[reg].value());
                 true
             },
             Instruction::VPOP(reg) => {
                 match self.stack.pop() {
-                    Some(v) => {
-                        self.reg[reg] = v;
-                        true
-                    },
-                    _ => false
+                    Some(v) => self.set_reg(reg, v),
+                    None => self.fault(Fault::StackUnderflow)
                 }
             },
             Instruction::CALL(reg) => {
-                self.stack.push(Immediate::U16(self.ip as u16 + 1));
+                self.stack.push(Immediate::U16(code_location(self.ip)));
                 self.execute(Instruction::JMP(reg))
             },
             Instruction::OR(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u|v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u|v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u|v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u|v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u|v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u|v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u|v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u|v));
+                self.push_binary(BinaryOp::Or, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::XOR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Xor, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SHR(reg1, v2) => {
+                self.push_binary(BinaryOp::Shr, Word::from(v2), self.reg[reg1])
+            },
+            Instruction::SHL(reg1, v2) => {
+                self.push_binary(BinaryOp::Shl, Word::from(v2), self.reg[reg1])
+            },
+            Instruction::AND(reg1, reg2) => {
+                self.push_binary(BinaryOp::And, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MOD(reg1, reg2) => {
+                self.push_binary(BinaryOp::Mod, self.reg[reg2], self.reg[reg1])
+            },
+            Instruction::NEG(reg) => {
+                self.push(negate(self.reg[reg].value()))
+            },
+            Instruction::NOT(reg) => {
+                self.push(integer!(self.reg[reg].value(), |x| !x))
+            },
+            Instruction::ABS(reg) => {
+                self.push(absolute(self.reg[reg].value()))
+            },
+            Instruction::MIN(reg1, reg2) => {
+                self.push_binary(BinaryOp::Min, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MAX(reg1, reg2) => {
+                self.push_binary(BinaryOp::Max, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ROL(reg1, v2) => {
+                self.push_binary(BinaryOp::Rol, Word::from(v2), self.reg[reg1])
+            },
+            Instruction::ROR(reg1, v2) => {
+                self.push_binary(BinaryOp::Ror, Word::from(v2), self.reg[reg1])
+            },
+            Instruction::SAR(reg1, v2) => {
+                self.push_binary(BinaryOp::Sar, Word::from(v2), self.reg[reg1])
+            },
+            Instruction::SQRT(reg) => {
+                self.push(float(self.reg[reg].value(), f32::sqrt, f64::sqrt))
+            },
+            Instruction::FLOOR(reg) => {
+                self.push(float(self.reg[reg].value(), f32::floor, f64::floor))
+            },
+            Instruction::CEIL(reg) => {
+                self.push(float(self.reg[reg].value(), f32::ceil, f64::ceil))
+            },
+            Instruction::ROUND(reg) => {
+                self.push(float(self.reg[reg].value(), f32::round, f64::round))
+            },
+            Instruction::TRUNC(reg) => {
+                self.push(float(self.reg[reg].value(), f32::trunc, f64::trunc))
+            },
+            Instruction::FMA(reg1, reg2, reg3) => {
+                self.push(fma(self.reg[reg1].value(), self.reg[reg2].value(), self.reg[reg3].value()))
+            },
+            Instruction::SIN(reg) => {
+                self.push(float(self.reg[reg].value(), f32::sin, f64::sin))
+            },
+            Instruction::COS(reg) => {
+                self.push(float(self.reg[reg].value(), f32::cos, f64::cos))
+            },
+            Instruction::TAN(reg) => {
+                self.push(float(self.reg[reg].value(), f32::tan, f64::tan))
+            },
+            Instruction::EXP(reg) => {
+                self.push(float(self.reg[reg].value(), f32::exp, f64::exp))
+            },
+            Instruction::LOG(reg) => {
+                self.push(float(self.reg[reg].value(), f32::ln, f64::ln))
+            },
+            Instruction::POW(reg1, reg2) => {
+                self.push(float_pair(self.reg[reg1].value(), self.reg[reg2].value(), f32::powf, f64::powf))
+            },
+            Instruction::COPYSIGN(reg1, reg2) => {
+                self.push(float_pair(self.reg[reg1].value(), self.reg[reg2].value(), f32::copysign, f64::copysign))
+            },
+            Instruction::ISNAN(reg) => {
+                self.test(float_test(self.reg[reg].value(), f32::is_nan, f64::is_nan))
+            },
+            Instruction::ISINF(reg) => {
+                self.test(float_test(self.reg[reg].value(), f32::is_infinite, f64::is_infinite))
+            },
+            Instruction::POPCNT(reg) => {
+                self.push(integer!(self.reg[reg].value(), |x| x.count_ones()))
+            },
+            Instruction::CLZ(reg) => {
+                self.push(integer!(self.reg[reg].value(), |x| x.leading_zeros()))
+            },
+            Instruction::CTZ(reg) => {
+                self.push(integer!(self.reg[reg].value(), |x| x.trailing_zeros()))
+            },
+            Instruction::BSWAP(reg) => {
+                self.push(integer!(self.reg[reg].value(), |x| x.swap_bytes()))
+            },
+            Instruction::BT(reg1, reg2) => {
+                self.test(bit!(self.reg[reg1].value(), self.reg[reg2].value(), |x, bit| x & bit != 0, test))
+            },
+            Instruction::BTS(reg1, reg2) => {
+                self.push(bit!(self.reg[reg1].value(), self.reg[reg2].value(), |x, bit| x | bit, value))
+            },
+            Instruction::BTR(reg1, reg2) => {
+                self.push(bit!(self.reg[reg1].value(), self.reg[reg2].value(), |x, bit| x & !bit, value))
+            },
+            Instruction::SHRR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Shr, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SHLR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Shl, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SARR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Sar, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ROLR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Rol, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::RORR(reg1, reg2) => {
+                self.push_binary(BinaryOp::Ror, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ADD3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Add, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ADDI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Add, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::SUB3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Sub, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SUBI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Sub, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::MUL3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Mul, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MULI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Mul, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::DIV3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Div, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::DIVI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Div, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::MOD3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Mod, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MODI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Mod, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::AND3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::And, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ANDI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::And, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::OR3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Or, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ORI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Or, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::XOR3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Xor, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::XORI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Xor, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::MIN3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Min, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MINI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Min, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::MAX3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Max, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::MAXI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Max, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::SHR3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Shr, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SHRI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Shr, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::SHL3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Shl, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SHLI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Shl, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::SAR3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Sar, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::SARI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Sar, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::ROL3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Rol, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::ROLI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Rol, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::ROR3(dst, reg1, reg2) => {
+                self.store_binary(BinaryOp::Ror, dst, self.reg[reg1], self.reg[reg2])
+            },
+            Instruction::RORI(dst, reg1, var) => {
+                self.store_binary(BinaryOp::Ror, dst, self.reg[reg1], Word::from(var))
+            },
+            Instruction::SADD() => {
+                self.pop_binary(BinaryOp::Add)
+            },
+            Instruction::SSUB() => {
+                self.pop_binary(BinaryOp::Sub)
+            },
+            Instruction::SMUL() => {
+                self.pop_binary(BinaryOp::Mul)
+            },
+            Instruction::SDIV() => {
+                self.pop_binary(BinaryOp::Div)
+            },
+            Instruction::SMOD() => {
+                self.pop_binary(BinaryOp::Mod)
+            },
+            Instruction::SAND() => {
+                self.pop_binary(BinaryOp::And)
+            },
+            Instruction::SOR() => {
+                self.pop_binary(BinaryOp::Or)
+            },
+            Instruction::SXOR() => {
+                self.pop_binary(BinaryOp::Xor)
+            },
+            Instruction::SMIN() => {
+                self.pop_binary(BinaryOp::Min)
+            },
+            Instruction::SMAX() => {
+                self.pop_binary(BinaryOp::Max)
+            },
+            Instruction::SSHR() => {
+                self.pop_binary(BinaryOp::Shr)
+            },
+            Instruction::SSHL() => {
+                self.pop_binary(BinaryOp::Shl)
+            },
+            Instruction::SSAR() => {
+                self.pop_binary(BinaryOp::Sar)
+            },
+            Instruction::SROL() => {
+                self.pop_binary(BinaryOp::Rol)
+            },
+            Instruction::SROR() => {
+                self.pop_binary(BinaryOp::Ror)
+            },
+            Instruction::SCMP() => {
+                match self.pop_operands() {
+                    Some((v1, v2)) => self.compare(v1, v2),
+                    None => self.fault(Fault::StackUnderflow)
+                }
+            },
+            Instruction::SJMP() => {
+                self.pop_jump(true)
+            },
+            Instruction::SJE() => {
+                self.pop_jump(self.flag_eq)
+            },
+            Instruction::SJNE() => {
+                self.pop_jump(!self.flag_eq)
+            },
+            Instruction::SJG() => {
+                self.pop_jump(self.flag_gt)
+            },
+            Instruction::SJLE() => {
+                self.pop_jump(!self.flag_gt)
+            },
+            Instruction::SSTORE(addr) => {
+                match self.stack.pop() {
+                    Some(v) => {
+                        self.data[addr] = v;
+                        true
                     },
-                    _ => {return false;}
+                    None => self.fault(Fault::StackUnderflow)
                 }
+            },
+            Instruction::DUP() => {
+                self.stack_item(0, false)
+            },
+            Instruction::DROP() => {
+                self.pop().is_some()
+            },
+            Instruction::SWAP() => {
+                self.stack_item(1, true)
+            },
+            Instruction::OVER() => {
+                self.stack_item(1, false)
+            },
+            Instruction::ROT() => {
+                self.stack_item(2, true)
+            },
+            Instruction::PICK(n) => {
+                self.stack_item(n, false)
+            },
+            Instruction::ROLL(n) => {
+                self.stack_item(n, true)
+            },
+            Instruction::DEPTH() => {
+                self.stack.push(Immediate::U64(self.stack.len() as u64));
                 true
             },
-            Instruction::XOR(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u^v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u^v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u^v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u^v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u^v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u^v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u^v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u^v));
-                    },
-                    _ => {return false;}
+            Instruction::JMPA(addr) => {
+                self.ip = addr;
+                true
+            },
+            Instruction::JEA(addr) => {
+                if self.flag_eq {
+                    self.ip = addr;
                 }
                 true
             },
-            Instruction::SHR(reg1, v2) => {
-                let v1 = self.reg[reg1];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u>>v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u>>v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u>>v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u>>v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u>>v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u>>v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u>>v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u>>v));
-                    },
-                    _ => {return false;}
+            Instruction::JNEA(addr) => {
+                if !self.flag_eq {
+                    self.ip = addr;
                 }
                 true
             },
-            Instruction::SHL(reg1, v2) => {
-                let v1 = self.reg[reg1];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u<<v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u<<v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u<<v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u<<v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u<<v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u<<v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u<<v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u<<v));
-                    },
-                    _ => {return false;}
+            Instruction::JGA(addr) => {
+                if self.flag_gt {
+                    self.ip = addr;
                 }
                 true
             },
-            Instruction::AND(reg1, reg2) => {
-                let v1 = self.reg[reg1];
-                let v2 = self.reg[reg2];
-                match (v1, v2){
-                    (Immediate::U8(v), Immediate::U8(u)) => {
-                        self.stack.push(Immediate::U8(u&v));
-                    },
-                    (Immediate::I8(v), Immediate::I8(u)) => {
-                        self.stack.push(Immediate::I8(u&v));
-                    },
-                    (Immediate::U16(v), Immediate::U16(u)) => {
-                        self.stack.push(Immediate::U16(u&v));
-                    },
-                    (Immediate::I16(v), Immediate::I16(u)) => {
-                        self.stack.push(Immediate::I16(u&v));
-                    },
-                    (Immediate::U32(v), Immediate::U32(u)) => {
-                        self.stack.push(Immediate::U32(u&v));
-                    },
-                    (Immediate::I32(v), Immediate::I32(u)) => {
-                        self.stack.push(Immediate::I32(u&v));
-                    },
-                    (Immediate::U64(v), Immediate::U64(u)) => {
-                        self.stack.push(Immediate::U64(u&v));
-                    },
-                    (Immediate::I64(v), Immediate::I64(u)) => {
-                        self.stack.push(Immediate::I64(u&v));
-                    },
-                    _ => {return false;}
+            Instruction::JLEA(addr) => {
+                if !self.flag_gt {
+                    self.ip = addr;
+                }
+                true
+            },
+            Instruction::CALLA(addr) => {
+                self.stack.push(Immediate::U16(code_location(self.ip)));
+                self.ip = addr;
+                true
+            },
+            Instruction::BRA(offset) => {
+                self.branch(offset)
+            },
+            Instruction::BEQ(offset) => {
+                if !self.flag_eq {
+                    return true;
+                }
+                self.branch(offset)
+            },
+            Instruction::BNE(offset) => {
+                if self.flag_eq {
+                    return true;
+                }
+                self.branch(offset)
+            },
+            Instruction::BGT(offset) => {
+                if !self.flag_gt {
+                    return true;
+                }
+                self.branch(offset)
+            },
+            Instruction::BLE(offset) => {
+                if self.flag_gt {
+                    return true;
+                }
+                self.branch(offset)
+            },
+            Instruction::BSR(offset) => {
+                self.stack.push(Immediate::U16(code_location(self.ip)));
+                self.branch(offset)
+            },
+            Instruction::CBEQ(reg1, reg2, offset) => {
+                self.compare_branch(self.reg[reg1].value(), self.reg[reg2].value(), offset, |a, b| a == b)
+            },
+            Instruction::CBNE(reg1, reg2, offset) => {
+                self.compare_branch(self.reg[reg1].value(), self.reg[reg2].value(), offset, |a, b| a != b)
+            },
+            Instruction::CBGT(reg1, reg2, offset) => {
+                self.compare_branch(self.reg[reg1].value(), self.reg[reg2].value(), offset, |a, b| a > b)
+            },
+            Instruction::CBLT(reg1, reg2, offset) => {
+                self.compare_branch(self.reg[reg1].value(), self.reg[reg2].value(), offset, |a, b| a < b)
+            },
+            Instruction::CBEQI(reg, var, offset) => {
+                self.compare_branch(self.reg[reg].value(), var, offset, |a, b| a == b)
+            },
+            Instruction::CBNEI(reg, var, offset) => {
+                self.compare_branch(self.reg[reg].value(), var, offset, |a, b| a != b)
+            },
+            Instruction::CBGTI(reg, var, offset) => {
+                self.compare_branch(self.reg[reg].value(), var, offset, |a, b| a > b)
+            },
+            Instruction::CBLTI(reg, var, offset) => {
+                self.compare_branch(self.reg[reg].value(), var, offset, |a, b| a < b)
+            },
+            Instruction::DJNZ(reg, offset) => {
+                self.count_down(reg, offset)
+            },
+            Instruction::SWITCH(reg, default, targets) => {
+                self.switch(reg, default, &targets)
+            },
+            Instruction::CMOVE(reg1, reg2) => {
+                if self.flag_eq {
+                    return self.set_reg(reg1, self.reg[reg2].value());
+                }
+                true
+            },
+            Instruction::CMOVNE(reg1, reg2) => {
+                if !self.flag_eq {
+                    return self.set_reg(reg1, self.reg[reg2].value());
+                }
+                true
+            },
+            Instruction::CMOVG(reg1, reg2) => {
+                if self.flag_gt {
+                    return self.set_reg(reg1, self.reg[reg2].value());
+                }
+                true
+            },
+            Instruction::CMOVL(reg1, reg2) => {
+                if !self.flag_gt && !self.flag_eq {
+                    return self.set_reg(reg1, self.reg[reg2].value());
+                }
+                true
+            },
+            Instruction::CMOVGE(reg1, reg2) => {
+                if self.flag_gt || self.flag_eq {
+                    return self.set_reg(reg1, self.reg[reg2].value());
+                }
+                true
+            },
+            Instruction::CMOVLE(reg1, reg2) => {
+                if !self.flag_gt {
+                    return self.set_reg(reg1, self.reg[reg2].value());
                 }
                 true
             },
+            Instruction::SELECT(dst, cond, reg1, reg2) => {
+                let value = if self.reg[cond].value().is_true() { self.reg[reg1].value() } else { self.reg[reg2].value() };
+                self.set_reg(dst, value)
+            },
+            Instruction::CWRITE(addr, reg) => {
+                match self.reg[reg].value() {
+                    Immediate::U8(byte) if addr < self.code.len() => {
+                        self.write_code(addr, byte);
+                        true
+                    },
+                    Immediate::U8(_) => self.fault(Fault::BadCodeAddress),
+                    _ => self.fault(Fault::TypeMismatch)
+                }
+            },
             Instruction::RET() => {
                 match self.stack.pop() {
-                    Some(v) => {
-                        match v {
-                            Immediate::U8(u) => {
-                                self.ip = u as usize;
-                                true
-                            }
-                            Immediate::U16(u) => {
-                                self.ip = u as usize;
-                                true
-                            }
-                            _ => false
-                        }
-                    }
-                    _ => false
+                    Some(v) => self.jump(v),
+                    None => self.fault(Fault::StackUnderflow)
                 }
             }
             Instruction::HALT() => {
@@ -691,28 +1909,83 @@ impl VirtualMachine {
         }
     }
 
+    //profiling, coverage and pausing watch every instruction, which only the decode loop lets them do
+    fn instrumented(&self) -> bool {
+        self.profiler.is_some() || self.coverage.is_some() || self.pause_after.is_some()
+    }
+
     fn cpu(&mut self) {
         //machine is already running.
         if self.is_executing {
             return;
         }
 
+        //refuse to start a program that fails verification
+        if let Err(report) = self.verified() {
+            panic!("{}", report);
+        }
+
+        self.predecode();
         self.is_executing = true;
+        self.paused = false;
+        if self.threaded && !self.instrumented() {
+            self.run_threaded();
+            return;
+        }
 
         while self.ip < self.code.len() && self.is_executing
         {
+            //pausing stops before the next instruction, so running again carries on with it
+            if self.pause_after == Some(0) {
+                self.pause_after = None;
+                self.paused = true;
+                self.is_executing = false;
+                break;
+            }
+            let start = self.ip;
+
             //decode current instruction
-            let instr = self.decode();
-            
+            let instr = if self.decoded.is_some() { self.cached_decode() } else { self.decode() };
+            if let Some(fault) = self.fault.take() {
+                panic!("{} at ip:{:?}{}", fault, start, self.debug.locate(start));
+            }
+
+            //go to next instruction, jumps overwrite this with their target
+            self.ip += 1;
+            let next = self.ip;
+
+            if self.trace {
+                self.trace_location(start);
+            }
+
+            //the profiler needs the instruction after it has run, coverage only whether it branches
+            let profiled = self.profiler.as_ref().map(|_| (instr.clone(), Instant::now()));
+            let branch = self.coverage.is_some() && coverage::conditional(&instr);
+
             //execute instruction
             let result = self.execute(instr);
             //check if instruction execution finished successfully
             if !result {
-                panic!("Failed to execute instruction at ip:{:?}", self.ip)
+                match self.fault.take() {
+                    Some(fault) => panic!("{} at ip:{:?}{}", fault, start, self.debug.locate(start)),
+                    None => panic!("Failed to execute instruction at ip:{:?}{}", start, self.debug.locate(start)),
+                }
+            }
+            if let (Some(profiler), Some((instr, began))) = (&mut self.profiler, profiled) {
+                profiler.record(start, &instr, self.ip, began);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record(start, branch, next, self.ip);
+            }
+            if let Some(count) = &mut self.pause_after {
+                *count -= 1;
             }
 
-            //go to next instruction
-            self.ip += 1;
+            //backward jumps find the hot loops to compile
+            #[cfg(feature = "jit")]
+            if self.ip < start && !self.instrumented() {
+                self.back_edge(next);
+            }
         }
     }
 }
@@ -728,7 +2001,471 @@ impl VirtualMachine {
     // 22           HALT()
     // 7 1          PRINTR(R1)
     // 22           HALT()
+//the value in `result`, or its error printed and the process ended
+pub(crate) fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+//parses a numeric command line option value, exiting with a message if it is missing or bad
+fn option_value(name: &str, value: Option<String>) -> usize {
+    match value.as_deref().map(str::parse) {
+        Some(Ok(n)) => n,
+        _ => {
+            eprintln!("{} needs a number", name);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    let mut vm = VirtualMachine::new(vec![1, 0, 0, 10, 1, 1, 0, 8, 1, 2, 0, 22, 1, 3, 0, 25, 6, 0, 1, 23, 2, 3, 3, 7, 0, 22, 7, 1, 22], 1024);
-    vm.cpu();
+    //run a program if one is given, otherwise the example program. Files ending in .asm are
+    //assembled, anything else is read as a program file of bytecode and optional debug info.
+    //--registers N sets the number of registers, --float-registers N adds a float bank after them,
+    //--cache runs from pre-decoded instructions, --threaded runs compiled closures, --jit compiles
+    //hot loops when built with the jit feature, --quiet turns off the trace and --bench N times
+    //N runs in every dispatch mode instead of running once. --aot FILE writes the program translated
+    //to Rust to FILE, --aot-test builds the translation and compares it against the interpreter.
+    //--optimize runs the program through the peephole optimizer first, --optimize-test compares
+    //the optimized program against the original. --cfg reports unreachable code, jumps with unknown
+    //targets and loops without an exit, --dot FILE writes the control flow graph to FILE as DOT.
+    //--profile FILE prints a flat profile after the run and writes its folded stacks to FILE,
+    //--coverage FILE prints the source annotated with line and branch counts and writes them to
+    //FILE as lcov. --pause-after N stops the program after N instructions, --snapshot FILE saves the
+    //machine to FILE when it stops and --resume FILE carries on with a saved machine, taking debug
+    //info the snapshot lacks from the program if one is given and it is the saved one. --emit FILE writes the program,
+    //optimized if --optimize is given, to FILE as a program file with its debug info
+    let mut path = None;
+    let mut integers = RegisterFile::default().count;
+    let mut floats = 0;
+    let mut cache = false;
+    let mut threaded = false;
+    #[cfg(feature = "jit")]
+    let mut jit = false;
+    let mut quiet = false;
+    let mut bench_runs = None;
+    let mut aot_path = None;
+    let mut aot_test = false;
+    let mut optimize = false;
+    let mut optimize_test = false;
+    let mut cfg_report = false;
+    let mut dot_path = None;
+    let mut profile_path = None;
+    let mut coverage_path = None;
+    let mut pause_after = None;
+    let mut snapshot_path = None;
+    let mut resume_path = None;
+    let mut emit_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--registers" => integers = option_value(&arg, args.next()),
+            "--float-registers" => floats = option_value(&arg, args.next()),
+            "--cache" => cache = true,
+            "--threaded" => threaded = true,
+            #[cfg(feature = "jit")]
+            "--jit" => jit = true,
+            "--quiet" => quiet = true,
+            "--bench" => bench_runs = Some(option_value(&arg, args.next())),
+            "--aot" => match args.next() {
+                Some(file) => aot_path = Some(file),
+                None => {
+                    eprintln!("--aot needs an output file");
+                    process::exit(1);
+                }
+            },
+            "--aot-test" => aot_test = true,
+            "--optimize" => optimize = true,
+            "--optimize-test" => optimize_test = true,
+            "--cfg" => cfg_report = true,
+            "--dot" => match args.next() {
+                Some(file) => dot_path = Some(file),
+                None => {
+                    eprintln!("--dot needs an output file");
+                    process::exit(1);
+                }
+            },
+            "--profile" => match args.next() {
+                Some(file) => profile_path = Some(file),
+                None => {
+                    eprintln!("--profile needs an output file");
+                    process::exit(1);
+                }
+            },
+            "--coverage" => match args.next() {
+                Some(file) => coverage_path = Some(file),
+                None => {
+                    eprintln!("--coverage needs an output file");
+                    process::exit(1);
+                }
+            },
+            "--pause-after" => pause_after = Some(option_value(&arg, args.next())),
+            "--snapshot" => match args.next() {
+                Some(file) => snapshot_path = Some(file),
+                None => {
+                    eprintln!("--snapshot needs an output file");
+                    process::exit(1);
+                }
+            },
+            "--resume" => match args.next() {
+                Some(file) => resume_path = Some(file),
+                None => {
+                    eprintln!("--resume needs a snapshot file");
+                    process::exit(1);
+                }
+            },
+            "--emit" => match args.next() {
+                Some(file) => emit_path = Some(file),
+                None => {
+                    eprintln!("--emit needs an output file");
+                    process::exit(1);
+                }
+            },
+            _ => path = Some(arg),
+        }
+    }
+    let registers = RegisterFile::banked(integers, floats);
+    if !registers.is_valid() {
+        eprintln!("the register file must have 1 to {} registers", RegisterFile::MAX);
+        process::exit(1);
+    }
+
+    //compiled loops are entered from the decode loop's backward jumps, which threaded code skips
+    #[cfg(feature = "jit")]
+    if jit && threaded {
+        eprintln!("--jit can't be used with --threaded");
+        process::exit(1);
+    }
+    let assembly = path.as_ref().filter(|path| path.ends_with(".asm"));
+    if coverage_path.is_some() && assembly.is_none() {
+        eprintln!("--coverage needs an assembly file to report on");
+        process::exit(1);
+    }
+    if coverage_path.is_some() && optimize {
+        eprintln!("--coverage can't map optimized code back to the source");
+        process::exit(1);
+    }
+    if resume_path.is_some() && (optimize || optimize_test || cfg_report || dot_path.is_some() || bench_runs.is_some() || aot_path.is_some() || aot_test || emit_path.is_some()) {
+        eprintln!("--resume only runs the saved machine");
+        process::exit(1);
+    }
+
+    let (code, debug, source) = match &path {
+        Some(path) if assembly.is_none() => {
+            let (code, debug) = program::load(path);
+            (code, debug, String::new())
+        },
+        Some(path) => {
+            let source = match fs::read_to_string(path) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                }
+            };
+            match assembler::assemble(&source, path) {
+                Ok((code, debug)) => (code, debug, source),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => (vec![1, 0, 0, 10, 1, 1, 0, 8, 1, 2, 0, 22, 1, 3, 0, 25, 6, 0, 1, 23, 2, 3, 3, 7, 0, 22, 7, 1, 22], Default::default(), String::new()),
+    };
+    if optimize_test {
+        peephole::check(&code, 1024, registers, path.as_deref());
+        return;
+    }
+    let (code, debug) = if optimize { peephole::run(&code, &debug, 1024, registers) } else { (code, debug) };
+    if let Some(file) = emit_path {
+        program::save(&code, &debug, &file);
+        return;
+    }
+    if cfg_report || dot_path.is_some() {
+        if let Some(file) = dot_path {
+            cfg::write_dot(&code, 1024, registers, &file);
+        }
+        if cfg_report {
+            cfg::report(&code, 1024, registers);
+        }
+        return;
+    }
+    if let Some(runs) = bench_runs {
+        bench::run(&code, 1024, registers, runs as u32);
+        return;
+    }
+    if let Some(file) = aot_path {
+        aot::write(&code, debug, 1024, registers, &file);
+        return;
+    }
+    if aot_test {
+        aot::check(&code, debug, 1024, registers, path.as_deref());
+        return;
+    }
+    let mut vm = match &resume_path {
+        Some(file) => {
+            let vm = snapshot::load(file);
+            if !vm.paused {
+                eprintln!("{}: the saved machine had already stopped", file);
+                process::exit(1);
+            }
+            if vm.code == code && vm.debug.lines.is_empty() { vm.with_debug_info(debug) } else { vm }
+        },
+        None => VirtualMachine::new(code, 1024).with_registers(registers).with_debug_info(debug),
+    };
+    if cache {
+        vm = vm.with_decode_cache();
+    }
+    if threaded {
+        vm = vm.with_threaded_code();
+    }
+    #[cfg(feature = "jit")]
+    if jit {
+        vm = vm.with_jit();
+    }
+    if profile_path.is_some() {
+        vm = vm.with_profiler();
+    }
+    if coverage_path.is_some() {
+        vm = vm.with_coverage();
+    }
+    if let Some(count) = pause_after {
+        vm = vm.with_pause_after(count as u64);
+    }
+    vm.trace = !quiet;
+    //the type checker starts from an empty stack, which a resumed machine may not have
+    if resume_path.is_none() {
+        for problem in vm.typecheck() {
+            eprintln!("warning: {}{}", problem, vm.debug.locate(problem.offset));
+        }
+    }
+    //a fault still leaves a profile and coverage of everything before it
+    let run = panic::catch_unwind(panic::AssertUnwindSafe(|| vm.cpu()));
+    if let Some(file) = profile_path {
+        profile::write(&vm, &file);
+    }
+    if let (Some(file), Some(source_path)) = (coverage_path, assembly) {
+        coverage::write(&mut vm, &source, source_path, &file);
+    }
+    if let Err(fault) = run {
+        panic::resume_unwind(fault);
+    }
+    if vm.paused {
+        eprintln!("paused at ip:{}{}", vm.ip, vm.debug.locate(vm.ip));
+    }
+    if let Some(file) = snapshot_path {
+        snapshot::save(&vm, &file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::{Fault, Immediate, Instruction, VirtualMachine};
+
+    fn run(code: Vec<u8>) -> VirtualMachine {
+        let mut vm = VirtualMachine::new(code, 0);
+        vm.trace = false;
+        vm.cpu();
+        vm
+    }
+
+    //the example program from the README, whose locations name the byte before their target
+    #[test]
+    fn example_program() {
+        let vm = run(vec![1, 0, 0, 10, 1, 1, 0, 8, 1, 2, 0, 22, 1, 3, 0, 25, 6, 0, 1, 23, 2, 3, 3, 7, 0, 22, 7, 1, 22]);
+        //greater, so the HALT at 25 stopped it
+        assert_eq!(vm.ip, 26);
+    }
+
+    //CALL pushes its own last byte and RET resumes after it. This differs from the original VM,
+    //where CALL pushed the next instruction's offset and RET resumed one byte past it, here at 7
+    #[test]
+    fn call_and_return() {
+        //MOV R0, 9u8; CALL R0; HALT; NOP; RET at 10
+        let vm = run(vec![1, 0, 0, 9, 20, 0, 22, 0, 0, 0, 21]);
+        assert_eq!(vm.ip, 7);
+        assert!(vm.stack.is_empty());
+        let vm = run(vec![1, 0, 0, 9, 20, 0, 22, 0, 0, 0, 22]);
+        assert_eq!(vm.stack, vec![Immediate::U16(5)]);
+        //a one byte NOP after the CALL, as migrated programs have, halts the same way in both
+        let vm = run(vec![1, 0, 0, 10, 20, 0, 0, 22, 0, 0, 0, 21]);
+        assert_eq!(vm.ip, 8);
+        assert!(vm.stack.is_empty());
+    }
+
+    //an instruction that can't run says why
+    fn fails(instr: Instruction, reg: [Immediate; 2]) -> Option<Fault> {
+        let mut vm = VirtualMachine::new(Vec::new(), 0);
+        vm.set_reg(0, reg[0]);
+        vm.set_reg(1, reg[1]);
+        assert!(!vm.execute(instr));
+        vm.fault
+    }
+
+    //what an instruction that succeeds pushes, or leaves in R2
+    fn result(instr: Instruction, reg: [Immediate; 2]) -> Immediate {
+        let mut vm = VirtualMachine::new(Vec::new(), 0);
+        vm.set_reg(0, reg[0]);
+        vm.set_reg(1, reg[1]);
+        assert!(vm.execute(instr));
+        vm.stack.pop().unwrap_or_else(|| vm.reg[2].value())
+    }
+
+    //a shift or rotate of a register and an immediate moves the immediate by the register, as
+    //SHR always has. Every other form moves its first operand by its second
+    #[test]
+    fn shift_operand_order() {
+        let count = Immediate::U8(2);
+        assert_eq!(result(Instruction::SHR(0, Immediate::U8(0x80)), [count, count]), Immediate::U8(0x20));
+        assert_eq!(result(Instruction::SHL(0, Immediate::U8(0x01)), [count, count]), Immediate::U8(0x04));
+        assert_eq!(result(Instruction::SAR(0, Immediate::I8(-128)), [count, count]), Immediate::I8(-32));
+        assert_eq!(result(Instruction::ROL(0, Immediate::U8(0x81)), [count, count]), Immediate::U8(0x06));
+        assert_eq!(result(Instruction::ROR(0, Immediate::U8(0x81)), [count, count]), Immediate::U8(0x60));
+
+        let value = Immediate::I8(-128);
+        assert_eq!(result(Instruction::SHRR(0, 1), [value, count]), Immediate::I8(0x20));
+        assert_eq!(result(Instruction::SARR(0, 1), [value, count]), Immediate::I8(-32));
+        assert_eq!(result(Instruction::ROLR(0, 1), [value, count]), Immediate::I8(0x02));
+        assert_eq!(result(Instruction::SAR3(2, 0, 1), [value, count]), Immediate::I8(-32));
+        assert_eq!(result(Instruction::SARI(2, 0, count), [value, count]), Immediate::I8(-32));
+        assert_eq!(result(Instruction::RORI(2, 0, count), [value, count]), Immediate::I8(0x20));
+    }
+
+    fn values(stack: &[u8]) -> Vec<Immediate> {
+        stack.iter().map(|&v| Immediate::U8(v)).collect()
+    }
+
+    //the stack after running `instr` on `stack`, and the fault it stopped with if it did
+    fn on_stack(instr: Instruction, stack: &[u8]) -> (Vec<Immediate>, Option<Fault>) {
+        let mut vm = VirtualMachine::new(Vec::new(), 0);
+        vm.stack = values(stack);
+        let fault = if vm.execute(instr) { None } else { vm.fault };
+        (vm.stack, fault)
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        use Instruction::*;
+        assert_eq!(on_stack(DUP(), &[1, 2]), (values(&[1, 2, 2]), None));
+        assert_eq!(on_stack(DROP(), &[1, 2]), (values(&[1]), None));
+        assert_eq!(on_stack(SWAP(), &[1, 2]), (values(&[2, 1]), None));
+        assert_eq!(on_stack(OVER(), &[1, 2]), (values(&[1, 2, 1]), None));
+        assert_eq!(on_stack(ROT(), &[1, 2, 3]), (values(&[2, 3, 1]), None));
+        assert_eq!(on_stack(PICK(0), &[1, 2, 3]), (values(&[1, 2, 3, 3]), None));
+        assert_eq!(on_stack(PICK(2), &[1, 2, 3]), (values(&[1, 2, 3, 1]), None));
+        assert_eq!(on_stack(ROLL(0), &[1, 2, 3]), (values(&[1, 2, 3]), None));
+        assert_eq!(on_stack(ROLL(2), &[1, 2, 3]), (values(&[2, 3, 1]), None));
+        assert_eq!(on_stack(SADD(), &[1, 2]), (values(&[3]), None));
+        //DEPTH counts as a u64
+        assert_eq!(on_stack(DEPTH(), &[]), (vec![Immediate::U64(0)], None));
+        assert_eq!(on_stack(DEPTH(), &[1, 2]), (vec![Immediate::U8(1), Immediate::U8(2), Immediate::U64(2)], None));
+    }
+
+    //too few values fail without changing the stack
+    #[test]
+    fn stack_underflow() {
+        use Instruction::*;
+        let underflow = Some(Fault::StackUnderflow);
+        assert_eq!(on_stack(DUP(), &[]), (values(&[]), underflow));
+        assert_eq!(on_stack(DROP(), &[]), (values(&[]), underflow));
+        assert_eq!(on_stack(SWAP(), &[1]), (values(&[1]), underflow));
+        assert_eq!(on_stack(OVER(), &[1]), (values(&[1]), underflow));
+        assert_eq!(on_stack(ROT(), &[1, 2]), (values(&[1, 2]), underflow));
+        assert_eq!(on_stack(PICK(3), &[1, 2, 3]), (values(&[1, 2, 3]), underflow));
+        assert_eq!(on_stack(ROLL(3), &[1, 2, 3]), (values(&[1, 2, 3]), underflow));
+        assert_eq!(on_stack(SADD(), &[1]), (values(&[1]), underflow));
+        assert_eq!(on_stack(SCMP(), &[1]), (values(&[1]), underflow));
+        assert_eq!(on_stack(SJMP(), &[]), (values(&[]), underflow));
+    }
+
+    //the case a SWITCH on `index` runs, with `cases` entries in its table. 9 is the default
+    fn switch_case(index: &str, cases: usize) -> Immediate {
+        let table: String = (0..cases).map(|i| format!(", case{}", i)).collect();
+        let bodies: String = (0..cases).map(|i| format!("case{}:\nMOV R1, {}u8\nHALT\n", i, i)).collect();
+        let source = format!("MOV R0, {}\nSWITCH R0, default{}\ndefault:\nMOV R1, 9u8\nHALT\n{}", index, table, bodies);
+        let (code, _) = assemble(&source, "test.asm").expect("test program assembles");
+        run(code).reg[1].value()
+    }
+
+    #[test]
+    fn switch() {
+        assert_eq!(switch_case("0u8", 3), Immediate::U8(0));
+        assert_eq!(switch_case("2i64", 3), Immediate::U8(2));
+        //past the end, negative and too large for a count all take the default
+        assert_eq!(switch_case("3u8", 3), Immediate::U8(9));
+        assert_eq!(switch_case("-1i8", 3), Immediate::U8(9));
+        assert_eq!(switch_case("-9223372036854775808i64", 3), Immediate::U8(9));
+        assert_eq!(switch_case("0x100000000u64", 3), Immediate::U8(9));
+        //an empty table always takes the default
+        assert_eq!(switch_case("0u8", 0), Immediate::U8(9));
+        //the index has to be an integer
+        assert_eq!(fails(Instruction::SWITCH(0, 0, vec![]), [Immediate::F32(0.0), Immediate::U8(0)]), Some(Fault::TypeMismatch));
+        assert_eq!(fails(Instruction::SWITCH(0, 0, vec![]), [Immediate::None(), Immediate::U8(0)]), Some(Fault::TypeMismatch));
+    }
+
+    //every conditional move with each state of the flags, equal then greater. The moved value
+    //has another type than the one it replaces, which moves don't check
+    #[test]
+    fn conditional_moves() {
+        use Instruction::*;
+        let flags = [(false, false), (true, false), (false, true), (true, true)];
+        let table: [(Instruction, [bool; 4]); 6] = [
+            (CMOVE(0, 1), [false, true, false, true]),
+            (CMOVNE(0, 1), [true, false, true, false]),
+            (CMOVG(0, 1), [false, false, true, true]),
+            (CMOVL(0, 1), [true, false, false, false]),
+            (CMOVGE(0, 1), [false, true, true, true]),
+            (CMOVLE(0, 1), [true, true, false, false]),
+        ];
+        for (instr, moves) in table {
+            for (&(eq, gt), &moved) in flags.iter().zip(moves.iter()) {
+                let mut vm = VirtualMachine::new(Vec::new(), 0);
+                vm.set_reg(0, Immediate::U8(1));
+                vm.set_reg(1, Immediate::I64(-2));
+                vm.flag_eq = eq;
+                vm.flag_gt = gt;
+                assert!(vm.execute(instr.clone()));
+                let expected = if moved { Immediate::I64(-2) } else { Immediate::U8(1) };
+                assert_eq!(vm.reg[0].value(), expected, "{:?} with eq {} gt {}", instr, eq, gt);
+                assert_eq!((vm.flag_eq, vm.flag_gt), (eq, gt));
+            }
+        }
+    }
+
+    //SELECT R2, R3, R0, R1 with each condition in R3
+    #[test]
+    fn select() {
+        let picks = |cond: Immediate| {
+            let mut vm = VirtualMachine::new(Vec::new(), 0);
+            vm.set_reg(0, Immediate::U8(1));
+            vm.set_reg(1, Immediate::F32(2.0));
+            vm.set_reg(3, cond);
+            assert!(vm.execute(Instruction::SELECT(2, 3, 0, 1)));
+            vm.reg[2].value()
+        };
+        for cond in [Immediate::U8(1), Immediate::I64(-5), Immediate::F64(0.5), Immediate::F32(f32::NAN)] {
+            assert_eq!(picks(cond), Immediate::U8(1), "{:?}", cond);
+        }
+        for cond in [Immediate::U8(0), Immediate::I64(0), Immediate::F64(0.0), Immediate::F32(-0.0), Immediate::None()] {
+            assert_eq!(picks(cond), Immediate::F32(2.0), "{:?}", cond);
+        }
+    }
+
+    #[test]
+    fn faults() {
+        assert_eq!(fails(Instruction::DIV(0, 1), [Immediate::U8(0), Immediate::U8(7)]), Some(Fault::DivideByZero));
+        assert_eq!(fails(Instruction::DIV(0, 1), [Immediate::U8(1), Immediate::I8(7)]), Some(Fault::TypeMismatch));
+        assert_eq!(fails(Instruction::SHR(0, Immediate::U8(1)), [Immediate::U8(8), Immediate::U8(0)]), Some(Fault::ShiftCount));
+        assert_eq!(fails(Instruction::NEG(0), [Immediate::None(), Immediate::U8(0)]), Some(Fault::TypeMismatch));
+        assert_eq!(fails(Instruction::SQRT(0), [Immediate::U8(4), Immediate::U8(0)]), Some(Fault::TypeMismatch));
+        assert_eq!(fails(Instruction::BTS(0, 1), [Immediate::U8(1), Immediate::U8(8)]), Some(Fault::ShiftCount));
+        assert_eq!(fails(Instruction::BT(0, 1), [Immediate::U8(1), Immediate::U16(0)]), Some(Fault::TypeMismatch));
+    }
 }
@@ -0,0 +1,711 @@
+use std::process;
+use crate::{code_target, or_exit, Address, Immediate, Instruction, Offset, Register, RegisterFile, VirtualMachine};
+use crate::aot::{interpret, outcome};
+use crate::assembler::DebugInfo;
+use crate::verifier::static_targets;
+
+//Peephole optimizer.
+//
+//Rewrites a verified program into a shorter one that prints and fails the same way, with faults
+//reported at the instruction the original faulted in. The program is decoded into a list of
+//instructions that remember where they started in the original code, rewritten until nothing
+//changes, then laid out again with every jump, branch and switch target moved to the new offset
+//of the instruction it pointed at. The rewrites are:
+//
+//- a constant jump target moved into a register only ever used for that, or pushed for a stack
+//  jump, becomes an embedded jump: `MOV R0, 10u8; JMP R0` is `JMPA 11`, `VPUSH 10u8; SJG` is `JGA 11`
+//- jumps to an unconditional jump go straight to where that one goes
+//- `NOP`s and jumps to the next instruction are dropped
+//- a stack form ALU instruction followed by `VPOP` becomes the register destination form, when
+//  there is no float bank to reject the result
+//
+//Pairs are only combined when nothing jumps to the second instruction. Code addresses held in
+//registers or on the stack can't be moved, so a program that still has a register or stack jump,
+//a call or a return keeps its layout and only has its jumps threaded, and a program with CWRITE
+//is left alone.
+
+struct Item {
+    //offsets of the instruction and of the one after it in the original code
+    start: Address,
+    next: Address,
+    instr: Instruction,
+}
+
+pub struct Optimized {
+    pub code: Vec<u8>,
+    //new and original offset of every instruction, in order
+    origins: Vec<(Address, Address)>,
+    //why the program kept its layout, if it did
+    pub kept: Option<&'static str>,
+}
+
+impl Optimized {
+    //original offset of the instruction at `offset` in the optimized code
+    pub fn original(&self, offset: Address) -> Option<Address> {
+        self.origins.binary_search_by_key(&offset, |&(new, _)| new).ok().map(|i| self.origins[i].1)
+    }
+
+    //`debug` moved to the optimized code. Instructions keep the line of the one they came from, and
+    //a label on a removed instruction goes to the one that took its place, as jumps to it do
+    pub fn debug_info(&self, debug: &DebugInfo) -> DebugInfo {
+        let mut moved = DebugInfo { file: debug.file.clone(), ..DebugInfo::default() };
+        for &(new, original) in &self.origins {
+            if let Some(&location) = debug.lines.get(&original) {
+                moved.lines.insert(new, location);
+            }
+        }
+        for (&addr, symbol) in &debug.symbols {
+            if let Some(&(new, _)) = self.origins.iter().find(|&&(_, original)| original >= addr) {
+                moved.symbols.entry(new).or_insert_with(|| symbol.clone());
+            }
+        }
+        moved
+    }
+}
+
+//jumps whose target is only known when they run
+fn is_dynamic(instr: &Instruction) -> bool {
+    matches!(instr, Instruction::JMP(_) | Instruction::JE(_) | Instruction::JNE(_) | Instruction::JG(_) | Instruction::JL(_)
+        | Instruction::SJMP() | Instruction::SJE() | Instruction::SJNE() | Instruction::SJG() | Instruction::SJLE()
+        | Instruction::CALL(_) | Instruction::CALLA(_) | Instruction::BSR(_) | Instruction::RET())
+}
+
+//register operands of an instruction, read or written
+fn registers(instr: &Instruction) -> Vec<Register> {
+    match *instr {
+        Instruction::JMP(reg)
+        | Instruction::JE(reg)
+        | Instruction::JNE(reg)
+        | Instruction::JG(reg)
+        | Instruction::JL(reg)
+        | Instruction::PRINTR(reg)
+        | Instruction::VSTORER(_, reg)
+        | Instruction::VLOADR(reg, _)
+        | Instruction::VPUSHR(reg)
+        | Instruction::VPOP(reg)
+        | Instruction::CALL(reg)
+        | Instruction::MOV(reg, _)
+        | Instruction::SHR(reg, _)
+        | Instruction::SHL(reg, _)
+        | Instruction::SAR(reg, _)
+        | Instruction::ROL(reg, _)
+        | Instruction::ROR(reg, _)
+        | Instruction::NEG(reg)
+        | Instruction::NOT(reg)
+        | Instruction::ABS(reg)
+        | Instruction::SQRT(reg)
+        | Instruction::FLOOR(reg)
+        | Instruction::CEIL(reg)
+        | Instruction::ROUND(reg)
+        | Instruction::TRUNC(reg)
+        | Instruction::SIN(reg)
+        | Instruction::COS(reg)
+        | Instruction::TAN(reg)
+        | Instruction::EXP(reg)
+        | Instruction::LOG(reg)
+        | Instruction::ISNAN(reg)
+        | Instruction::ISINF(reg)
+        | Instruction::POPCNT(reg)
+        | Instruction::CLZ(reg)
+        | Instruction::CTZ(reg)
+        | Instruction::BSWAP(reg)
+        | Instruction::CBEQI(reg, _, _)
+        | Instruction::CBNEI(reg, _, _)
+        | Instruction::CBGTI(reg, _, _)
+        | Instruction::CBLTI(reg, _, _)
+        | Instruction::DJNZ(reg, _)
+        | Instruction::SWITCH(reg, _, _)
+        | Instruction::CWRITE(_, reg) => vec![reg],
+        Instruction::MOVR(reg1, reg2)
+        | Instruction::CMP(reg1, reg2)
+        | Instruction::ADD(reg1, reg2)
+        | Instruction::SUB(reg1, reg2)
+        | Instruction::MUL(reg1, reg2)
+        | Instruction::DIV(reg1, reg2)
+        | Instruction::AND(reg1, reg2)
+        | Instruction::OR(reg1, reg2)
+        | Instruction::XOR(reg1, reg2)
+        | Instruction::MOD(reg1, reg2)
+        | Instruction::MIN(reg1, reg2)
+        | Instruction::MAX(reg1, reg2)
+        | Instruction::POW(reg1, reg2)
+        | Instruction::COPYSIGN(reg1, reg2)
+        | Instruction::BT(reg1, reg2)
+        | Instruction::BTS(reg1, reg2)
+        | Instruction::BTR(reg1, reg2)
+        | Instruction::SHRR(reg1, reg2)
+        | Instruction::SHLR(reg1, reg2)
+        | Instruction::SARR(reg1, reg2)
+        | Instruction::ROLR(reg1, reg2)
+        | Instruction::RORR(reg1, reg2)
+        | Instruction::ADDI(reg1, reg2, _)
+        | Instruction::SUBI(reg1, reg2, _)
+        | Instruction::MULI(reg1, reg2, _)
+        | Instruction::DIVI(reg1, reg2, _)
+        | Instruction::MODI(reg1, reg2, _)
+        | Instruction::ANDI(reg1, reg2, _)
+        | Instruction::ORI(reg1, reg2, _)
+        | Instruction::XORI(reg1, reg2, _)
+        | Instruction::MINI(reg1, reg2, _)
+        | Instruction::MAXI(reg1, reg2, _)
+        | Instruction::SHRI(reg1, reg2, _)
+        | Instruction::SHLI(reg1, reg2, _)
+        | Instruction::SARI(reg1, reg2, _)
+        | Instruction::ROLI(reg1, reg2, _)
+        | Instruction::RORI(reg1, reg2, _)
+        | Instruction::CBEQ(reg1, reg2, _)
+        | Instruction::CBNE(reg1, reg2, _)
+        | Instruction::CBGT(reg1, reg2, _)
+        | Instruction::CBLT(reg1, reg2, _)
+        | Instruction::CMOVE(reg1, reg2)
+        | Instruction::CMOVNE(reg1, reg2)
+        | Instruction::CMOVG(reg1, reg2)
+        | Instruction::CMOVL(reg1, reg2)
+        | Instruction::CMOVGE(reg1, reg2)
+        | Instruction::CMOVLE(reg1, reg2) => vec![reg1, reg2],
+        Instruction::FMA(reg1, reg2, reg3)
+        | Instruction::ADD3(reg1, reg2, reg3)
+        | Instruction::SUB3(reg1, reg2, reg3)
+        | Instruction::MUL3(reg1, reg2, reg3)
+        | Instruction::DIV3(reg1, reg2, reg3)
+        | Instruction::MOD3(reg1, reg2, reg3)
+        | Instruction::AND3(reg1, reg2, reg3)
+        | Instruction::OR3(reg1, reg2, reg3)
+        | Instruction::XOR3(reg1, reg2, reg3)
+        | Instruction::MIN3(reg1, reg2, reg3)
+        | Instruction::MAX3(reg1, reg2, reg3)
+        | Instruction::SHR3(reg1, reg2, reg3)
+        | Instruction::SHL3(reg1, reg2, reg3)
+        | Instruction::SAR3(reg1, reg2, reg3)
+        | Instruction::ROL3(reg1, reg2, reg3)
+        | Instruction::ROR3(reg1, reg2, reg3) => vec![reg1, reg2, reg3],
+        Instruction::SELECT(dst, cond, reg1, reg2) => vec![dst, cond, reg1, reg2],
+        _ => Vec::new(),
+    }
+}
+
+//embedded jump taken under the same condition as a register or stack jump
+fn embedded(instr: &Instruction, addr: Address) -> Option<Instruction> {
+    Some(match *instr {
+        Instruction::JMP(_) | Instruction::SJMP() => Instruction::JMPA(addr),
+        Instruction::JE(_) | Instruction::SJE() => Instruction::JEA(addr),
+        Instruction::JNE(_) | Instruction::SJNE() => Instruction::JNEA(addr),
+        Instruction::JG(_) | Instruction::SJG() => Instruction::JGA(addr),
+        Instruction::JL(_) | Instruction::SJLE() => Instruction::JLEA(addr),
+        _ => return None,
+    })
+}
+
+//register destination form of a stack form ALU instruction. The shifts and rotates by a register
+//shift their immediate and have none
+fn into_register(instr: &Instruction, dst: Register) -> Option<Instruction> {
+    Some(match *instr {
+        Instruction::ADD(reg1, reg2) => Instruction::ADD3(dst, reg1, reg2),
+        Instruction::SUB(reg1, reg2) => Instruction::SUB3(dst, reg1, reg2),
+        Instruction::MUL(reg1, reg2) => Instruction::MUL3(dst, reg1, reg2),
+        Instruction::DIV(reg1, reg2) => Instruction::DIV3(dst, reg2, reg1),
+        Instruction::MOD(reg1, reg2) => Instruction::MOD3(dst, reg2, reg1),
+        Instruction::AND(reg1, reg2) => Instruction::AND3(dst, reg1, reg2),
+        Instruction::OR(reg1, reg2) => Instruction::OR3(dst, reg1, reg2),
+        Instruction::XOR(reg1, reg2) => Instruction::XOR3(dst, reg1, reg2),
+        Instruction::MIN(reg1, reg2) => Instruction::MIN3(dst, reg1, reg2),
+        Instruction::MAX(reg1, reg2) => Instruction::MAX3(dst, reg1, reg2),
+        Instruction::SHRR(reg1, reg2) => Instruction::SHR3(dst, reg1, reg2),
+        Instruction::SHLR(reg1, reg2) => Instruction::SHL3(dst, reg1, reg2),
+        Instruction::SARR(reg1, reg2) => Instruction::SAR3(dst, reg1, reg2),
+        Instruction::ROLR(reg1, reg2) => Instruction::ROL3(dst, reg1, reg2),
+        Instruction::RORR(reg1, reg2) => Instruction::ROR3(dst, reg1, reg2),
+        _ => return None,
+    })
+}
+
+//a copy of the instruction with every embedded target passed through `map`. Relative offsets
+//count from `old_next` before and `new_next` after. None if an offset no longer fits
+fn retarget(instr: &Instruction, old_next: Address, new_next: Address, map: impl Fn(Address) -> Address) -> Option<Instruction> {
+    let offset = |offset: Offset| -> Option<Offset> {
+        let moved = map((old_next as Offset + offset) as Address) as Offset - new_next as Offset;
+        if moved < i16::MIN as Offset || moved > i16::MAX as Offset {
+            return None;
+        }
+        Some(moved)
+    };
+    Some(match *instr {
+        Instruction::JMPA(addr) => Instruction::JMPA(map(addr)),
+        Instruction::JEA(addr) => Instruction::JEA(map(addr)),
+        Instruction::JNEA(addr) => Instruction::JNEA(map(addr)),
+        Instruction::JGA(addr) => Instruction::JGA(map(addr)),
+        Instruction::JLEA(addr) => Instruction::JLEA(map(addr)),
+        Instruction::CALLA(addr) => Instruction::CALLA(map(addr)),
+        Instruction::BRA(o) => Instruction::BRA(offset(o)?),
+        Instruction::BEQ(o) => Instruction::BEQ(offset(o)?),
+        Instruction::BNE(o) => Instruction::BNE(offset(o)?),
+        Instruction::BGT(o) => Instruction::BGT(offset(o)?),
+        Instruction::BLE(o) => Instruction::BLE(offset(o)?),
+        Instruction::BSR(o) => Instruction::BSR(offset(o)?),
+        Instruction::CBEQ(reg1, reg2, o) => Instruction::CBEQ(reg1, reg2, offset(o)?),
+        Instruction::CBNE(reg1, reg2, o) => Instruction::CBNE(reg1, reg2, offset(o)?),
+        Instruction::CBGT(reg1, reg2, o) => Instruction::CBGT(reg1, reg2, offset(o)?),
+        Instruction::CBLT(reg1, reg2, o) => Instruction::CBLT(reg1, reg2, offset(o)?),
+        Instruction::CBEQI(reg, var, o) => Instruction::CBEQI(reg, var, offset(o)?),
+        Instruction::CBNEI(reg, var, o) => Instruction::CBNEI(reg, var, offset(o)?),
+        Instruction::CBGTI(reg, var, o) => Instruction::CBGTI(reg, var, offset(o)?),
+        Instruction::CBLTI(reg, var, o) => Instruction::CBLTI(reg, var, offset(o)?),
+        Instruction::DJNZ(reg, o) => Instruction::DJNZ(reg, offset(o)?),
+        Instruction::SWITCH(reg, default, ref targets) => Instruction::SWITCH(reg, map(default), targets.iter().map(|&t| map(t)).collect()),
+        ref other => other.clone(),
+    })
+}
+
+fn encoded_len(instr: &Instruction) -> usize {
+    let mut code = Vec::new();
+    instr.encode(&mut code);
+    code.len()
+}
+
+//index of the item a jump to original offset `addr` lands on. Removed instructions hand their
+//jumps on to the instruction after them
+fn landing(items: &[Item], addr: Address) -> usize {
+    items.partition_point(|item| item.start < addr)
+}
+
+//marks every item some embedded target lands on
+fn targeted(items: &[Item]) -> Vec<bool> {
+    let mut targeted = vec![false; items.len() + 1];
+    for item in items {
+        for target in static_targets(&item.instr, item.next) {
+            targeted[landing(items, target as Address)] = true;
+        }
+    }
+    targeted
+}
+
+//where an unconditional jump goes
+fn unconditional(item: &Item) -> Option<Address> {
+    match item.instr {
+        Instruction::JMPA(addr) => Some(addr),
+        Instruction::BRA(offset) => Some((item.next as Offset + offset) as Address),
+        _ => None,
+    }
+}
+
+//follows a chain of unconditional jumps from `target`, stopping at a loop
+fn destination(items: &[Item], mut target: Address) -> Address {
+    for _ in 0..items.len() {
+        let i = landing(items, target);
+        match items.get(i).and_then(unconditional) {
+            Some(next) if landing(items, next) != i => target = next,
+            _ => break,
+        }
+    }
+    target
+}
+
+//sends jumps to unconditional jumps straight on
+fn thread(items: &mut [Item]) -> bool {
+    let mut changed = false;
+    for i in 0..items.len() {
+        let (instr, next) = (&items[i].instr, items[i].next);
+        let before: Vec<usize> = static_targets(instr, next).into_iter().map(|t| landing(items, t as Address)).collect();
+        let threaded = match retarget(instr, next, next, |t| destination(items, t)) {
+            Some(threaded) => threaded,
+            None => continue,
+        };
+        let after: Vec<usize> = static_targets(&threaded, next).into_iter().map(|t| landing(items, t as Address)).collect();
+        if before != after {
+            items[i].instr = threaded;
+            changed = true;
+        }
+    }
+    changed
+}
+
+//drops one NOP or jump to the next instruction. The last instruction stays if something jumps
+//to it, so every jump still has an instruction to land on
+fn remove(items: &mut Vec<Item>) -> bool {
+    let targeted = targeted(items);
+    for i in 0..items.len() {
+        let skip = match items[i].instr {
+            Instruction::NOP() => true,
+            Instruction::JMPA(_) | Instruction::JEA(_) | Instruction::JNEA(_) | Instruction::JGA(_) | Instruction::JLEA(_)
+            | Instruction::BRA(_) | Instruction::BEQ(_) | Instruction::BNE(_) | Instruction::BGT(_) | Instruction::BLE(_) => {
+                static_targets(&items[i].instr, items[i].next).into_iter().all(|t| landing(items, t as Address) == i + 1)
+            },
+            _ => false,
+        };
+        if skip && !(targeted[i] && i + 1 == items.len()) {
+            items.remove(i);
+            return true;
+        }
+    }
+    false
+}
+
+//turns one stack form ALU instruction and the VPOP of its result into the register form
+fn fuse(items: &mut Vec<Item>) -> bool {
+    let targeted = targeted(items);
+    for i in 1..items.len() {
+        if targeted[i] {
+            continue;
+        }
+        let fused = match items[i].instr {
+            Instruction::VPOP(dst) => into_register(&items[i - 1].instr, dst),
+            _ => None,
+        };
+        if let Some(instr) = fused {
+            items[i - 1].instr = instr;
+            items[i - 1].next = items[i].next;
+            items.remove(i);
+            return true;
+        }
+    }
+    false
+}
+
+//encodes the items at their new offsets, moving every target along
+fn layout(items: &[Item]) -> Optimized {
+    let mut starts = Vec::with_capacity(items.len());
+    let mut len = 0;
+    for item in items {
+        starts.push(len);
+        len += encoded_len(&item.instr);
+    }
+    let moved = |addr: Address| starts.get(landing(items, addr)).copied().unwrap_or(len);
+    let mut code = Vec::with_capacity(len);
+    for (item, &start) in items.iter().zip(&starts) {
+        let new_next = start + encoded_len(&item.instr);
+        //instructions only ever get shorter, so offsets between them do too
+        let instr = retarget(&item.instr, item.next, new_next, moved).expect("branch offset grew while optimizing");
+        instr.encode(&mut code);
+    }
+    Optimized { code, origins: starts.into_iter().zip(items.iter().map(|item| item.start)).collect(), kept: None }
+}
+
+impl VirtualMachine {
+    //optimizes the program, see above. Fails if it doesn't pass verification
+    pub(crate) fn optimize(&mut self) -> Result<Optimized, String> {
+        self.verified()?;
+        let mut items = Vec::new();
+        let mut start = 0;
+        while start < self.code.len() {
+            let (instr, next) = self.decode_at(start).map_err(|fault| format!("{} at ip:{}", fault, start))?;
+            items.push(Item { start, next, instr });
+            start = next;
+        }
+        if items.iter().any(|item| matches!(item.instr, Instruction::CWRITE(_, _))) {
+            let origins = items.iter().map(|item| (item.start, item.start)).collect();
+            return Ok(Optimized { code: self.code.clone(), origins, kept: Some("the program writes its own code") });
+        }
+
+        //constant jump targets, moved into a register or pushed right before the jump
+        let targeted = targeted(&items);
+        let is_start = |addr: Address| items.get(landing(&items, addr)).is_some_and(|item| item.start == addr);
+        let constant = |var: Immediate| match var {
+            Immediate::U8(v) if is_start(v as Address + 1) => Some(v as Address + 1),
+            Immediate::U16(v) if is_start(code_target(v)) => Some(code_target(v)),
+            _ => None,
+        };
+        let mut mentions = vec![0; self.registers.count];
+        for item in &items {
+            for reg in registers(&item.instr) {
+                mentions[reg] += 1;
+            }
+        }
+        let mut pairs = Vec::new();
+        let mut in_pairs = vec![0; self.registers.count];
+        for i in 1..items.len() {
+            if targeted[i] {
+                continue;
+            }
+            let (var, reg) = match (&items[i - 1].instr, &items[i].instr) {
+                (&Instruction::MOV(reg, var), &Instruction::JMP(jump_reg))
+                | (&Instruction::MOV(reg, var), &Instruction::JE(jump_reg))
+                | (&Instruction::MOV(reg, var), &Instruction::JNE(jump_reg))
+                | (&Instruction::MOV(reg, var), &Instruction::JG(jump_reg))
+                | (&Instruction::MOV(reg, var), &Instruction::JL(jump_reg)) if reg == jump_reg && self.registers.accepts(reg, false) => (var, Some(reg)),
+                (&Instruction::VPUSH(var), Instruction::SJMP())
+                | (&Instruction::VPUSH(var), Instruction::SJE())
+                | (&Instruction::VPUSH(var), Instruction::SJNE())
+                | (&Instruction::VPUSH(var), Instruction::SJG())
+                | (&Instruction::VPUSH(var), Instruction::SJLE()) => (var, None),
+                _ => continue,
+            };
+            if let Some(addr) = constant(var) {
+                if let Some(reg) = reg {
+                    in_pairs[reg] += 2;
+                }
+                pairs.push((i, reg, addr));
+            }
+        }
+        //a register keeps its MOVs unless every use of it is one of these jumps
+        let mut rewritten = Vec::with_capacity(items.len());
+        let mut pairs = pairs.into_iter().filter(|&(_, reg, _)| reg.is_none_or(|reg| mentions[reg] == in_pairs[reg])).peekable();
+        let mut iter = items.iter().enumerate();
+        while let Some((i, item)) = iter.next() {
+            match pairs.peek() {
+                Some(&(second, _, addr)) if second == i + 1 => {
+                    let (_, jump) = iter.next().expect("a pair has a second instruction");
+                    let instr = embedded(&jump.instr, addr).expect("pairs end with a register or stack jump");
+                    rewritten.push(Item { start: item.start, next: jump.next, instr });
+                    pairs.next();
+                },
+                _ => rewritten.push(Item { start: item.start, next: item.next, instr: item.instr.clone() }),
+            }
+        }
+
+        //code addresses that are values can't move
+        if rewritten.iter().any(|item| is_dynamic(&item.instr)) {
+            while thread(&mut items) {}
+            let mut optimized = layout(&items);
+            optimized.kept = Some("the program uses code addresses as values");
+            return Ok(optimized);
+        }
+        let mut items = rewritten;
+        let floats = self.registers.floats;
+        while thread(&mut items) | remove(&mut items) | (floats == 0 && fuse(&mut items)) {}
+        Ok(layout(&items))
+    }
+}
+
+fn optimized(code: &[u8], heap_capacity: usize, registers: RegisterFile) -> Optimized {
+    let mut vm = VirtualMachine::new(code.to_vec(), heap_capacity).with_registers(registers);
+    or_exit(vm.optimize())
+}
+
+//the optimized program and its debug info
+pub fn run(code: &[u8], debug: &DebugInfo, heap_capacity: usize, registers: RegisterFile) -> (Vec<u8>, DebugInfo) {
+    let optimized = optimized(code, heap_capacity, registers);
+    let debug = optimized.debug_info(debug);
+    (optimized.code, debug)
+}
+
+//runs the program read from `source` with and without optimizing and checks that both print the
+//same and fail with the same message at the same original instruction
+pub fn check(code: &[u8], heap_capacity: usize, registers: RegisterFile, source: Option<&str>) {
+    let optimized = optimized(code, heap_capacity, registers);
+    let (original, rewritten) = match (interpret(registers, source, &[]), interpret(registers, source, &["--optimize"])) {
+        (Ok(original), Ok(rewritten)) => (outcome(original), outcome(rewritten)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("failed to run: {}", e);
+            process::exit(1);
+        }
+    };
+    //report the fault at the original offset
+    let (stdout, panic) = rewritten;
+    let panic = panic.map(|message| match message.rsplit_once(" at ip:") {
+        Some((fault, ip)) => match ip.parse().ok().and_then(|ip| optimized.original(ip)) {
+            Some(ip) => format!("{} at ip:{}", fault, ip),
+            None => message,
+        },
+        None => message,
+    });
+    match optimized.kept {
+        Some(reason) => println!("layout kept, {}", reason),
+        None => println!("optimized {} bytes to {}", code.len(), optimized.code.len()),
+    }
+    if original == (stdout.clone(), panic.clone()) {
+        println!("optimized program matches the original");
+        return;
+    }
+    println!("optimized program differs from the original");
+    println!("original:  {:?}", original);
+    println!("optimized: {:?}", (stdout, panic));
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use crate::assembler::assemble;
+    use crate::verifier::static_targets;
+    use crate::{Address, Immediate, Instruction, Offset, VirtualMachine};
+    use super::{into_register, retarget, Optimized};
+
+    fn optimize(source: &str) -> (Vec<u8>, Optimized) {
+        let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+        let optimized = VirtualMachine::new(code.clone(), 16).optimize().expect("test program verifies");
+        (code, optimized)
+    }
+
+    //every instruction with its offset and the offset after it
+    fn instructions(code: &[u8]) -> Vec<(Address, Instruction, Address)> {
+        let mut vm = VirtualMachine::new(code.to_vec(), 0);
+        let mut instrs = Vec::new();
+        let mut start = 0;
+        while start < code.len() {
+            let (instr, next) = vm.decode_at(start).expect("optimized code decodes");
+            instrs.push((start, instr, next));
+            start = next;
+        }
+        instrs
+    }
+
+    //the fault a run stopped with, its offset passed through `original`, and the final state
+    fn run(code: &[u8], original: impl Fn(Address) -> Option<Address>) -> (Option<String>, String) {
+        let mut vm = VirtualMachine::new(code.to_vec(), 16);
+        vm.trace = false;
+        let fault = panic::catch_unwind(AssertUnwindSafe(|| vm.cpu())).err().map(|e| {
+            let message = *e.downcast::<String>().expect("faults panic with a message");
+            let (fault, ip) = message.rsplit_once(" at ip:").expect("faults name their offset");
+            format!("{} at ip:{:?}", fault, ip.parse().ok().and_then(&original))
+        });
+        (fault, format!("{:?} {:?} {:?}", vm.reg, vm.stack, vm.data))
+    }
+
+    //the optimized program ends like the original
+    fn assert_same_run(code: &[u8], optimized: &Optimized) {
+        assert_eq!(run(&optimized.code, |ip| optimized.original(ip)), run(code, Some));
+    }
+
+    //every target in the optimized code is the new offset of the instruction the original target
+    //landed on, or of the first one kept after it
+    fn assert_relocated(code: &[u8], optimized: &Optimized) {
+        let old = instructions(code);
+        let new = instructions(&optimized.code);
+        let moved = |target: Offset| -> Offset {
+            new.iter().map(|&(start, _, _)| start).find(|&start| optimized.original(start).expect("instructions have an origin") as Offset >= target)
+                .unwrap_or(optimized.code.len()) as Offset
+        };
+        for (start, instr, next) in &new {
+            let origin = optimized.original(*start).expect("instructions have an origin");
+            let (_, old_instr, old_next) = old.iter().find(|&&(old_start, _, _)| old_start == origin).expect("origins are original instructions");
+            let expected: Vec<Offset> = static_targets(old_instr, *old_next).into_iter().map(moved).collect();
+            assert_eq!(static_targets(instr, *next), expected, "{:?} at {} from {:?} at {}", instr, start, old_instr, origin);
+        }
+    }
+
+    #[test]
+    fn retarget_moves_targets() {
+        let map = |addr: Address| match addr {
+            30 => 26,
+            10 => 8,
+            addr => addr,
+        };
+        let retargeted = |instr: Instruction, old_next: Address, new_next: Address| format!("{:?}", retarget(&instr, old_next, new_next, map));
+        assert_eq!(retargeted(Instruction::BRA(10), 20, 20), "Some(BRA(6))");
+        assert_eq!(retargeted(Instruction::BEQ(10), 20, 16), "Some(BEQ(10))");
+        assert_eq!(retargeted(Instruction::CBEQI(0, Immediate::U8(1), 10), 20, 18), "Some(CBEQI(0, U8(1), 8))");
+        assert_eq!(retargeted(Instruction::CBLT(1, 2, -10), 20, 14), "Some(CBLT(1, 2, -6))");
+        assert_eq!(retargeted(Instruction::DJNZ(3, -10), 20, 16), "Some(DJNZ(3, -8))");
+        assert_eq!(retargeted(Instruction::SWITCH(2, 30, vec![10, 40]), 20, 20), "Some(SWITCH(2, 26, [8, 40]))");
+        assert_eq!(retargeted(Instruction::JLEA(30), 20, 20), "Some(JLEA(26))");
+        //an offset that no longer fits in 16 bits
+        assert!(retarget(&Instruction::BRA(0), 0, 0, |_| 40000).is_none());
+    }
+
+    //dropped NOPs move branches, compare-and-branches, DJNZ and SWITCH targets
+    //a shift by a register moves its immediate, which the immediate register forms can't express
+    #[test]
+    fn shifts_by_register_keep_their_form() {
+        for instr in [Instruction::SHR(0, Immediate::U8(1)), Instruction::SAR(0, Immediate::U8(1)), Instruction::ROL(0, Immediate::U8(1))] {
+            assert!(into_register(&instr, 2).is_none(), "{:?}", instr);
+        }
+        assert!(matches!(into_register(&Instruction::SARR(0, 1), 2), Some(Instruction::SAR3(2, 0, 1))));
+    }
+
+    //instructions keep their lines when they move, and labels on removed NOPs go to what replaced them
+    #[test]
+    fn debug_info_moves() {
+        let (code, debug) = assemble("
+            MOV R0, 3u8
+            NOP
+        top:
+            NOP
+            DJNZ R0, top
+            HALT
+        ", "test.asm").expect("test program assembles");
+        let optimized = VirtualMachine::new(code, 16).optimize().expect("test program verifies");
+        let moved = optimized.debug_info(&debug);
+        let lines: Vec<_> = instructions(&optimized.code).iter().map(|&(start, _, _)| moved.lines[&start].line).collect();
+        assert_eq!(lines, vec![2, 6, 7]);
+        let djnz = instructions(&optimized.code)[1].0;
+        assert_eq!(moved.label(djnz).as_deref(), Some("top"));
+    }
+
+    #[test]
+    fn layout_relocates_targets() {
+        let (code, optimized) = optimize("
+            MOV R0, 3u8
+            MOV R1, 0u8
+            MOV R2, 1u8
+        top:
+            NOP
+            ADD R1, R1, R0
+            CBLTI R1, 4u8, skip
+            NOP
+            MOV R2, 0u8
+        skip:
+            SWITCH R2, b, a, b
+        a:
+            NOP
+            ADD R1, R1, 1u8
+        b:
+            DJNZ R0, top
+            CMP R1, R2
+            BEQ end
+            PRINTR R1
+            NOP
+        end:
+            HALT
+        ");
+        assert_eq!(optimized.kept, None);
+        assert!(optimized.code.len() < code.len());
+        assert_relocated(&code, &optimized);
+        assert_same_run(&code, &optimized);
+    }
+
+    //a fault after moved code is reported at the original offset
+    #[test]
+    fn faults_map_back() {
+        let (code, optimized) = optimize("
+            NOP
+            NOP
+            MOV R0, 0u8
+            MOV R1, 1u8
+            DIV R0, R1
+            HALT
+        ");
+        assert!(optimized.code.len() < code.len());
+        let (fault, _) = run(&optimized.code, |ip| optimized.original(ip));
+        assert_eq!(fault.as_deref(), Some("Integer division by zero at ip:Some(10)"));
+        assert_same_run(&code, &optimized);
+    }
+
+    #[test]
+    fn self_modifying_code_is_left_alone() {
+        let (code, optimized) = optimize("
+            NOP
+            MOV R0, 0u8
+            CWRITE 0u16, R0
+            HALT
+        ");
+        assert_eq!(optimized.kept, Some("the program writes its own code"));
+        assert_eq!(optimized.code, code);
+    }
+
+    //a call keeps every instruction where it was, but jumps to jumps still go straight on
+    #[test]
+    fn code_addresses_keep_the_layout() {
+        let (code, optimized) = optimize("
+            BRA first
+            NOP
+        first:
+            BRA last
+            NOP
+        last:
+            CALLA function
+            HALT
+        function:
+            NOP
+            RET
+        ");
+        assert_eq!(optimized.kept, Some("the program uses code addresses as values"));
+        assert_eq!(optimized.code.len(), code.len());
+        let new = instructions(&optimized.code);
+        for &(start, _, _) in &new {
+            assert_eq!(optimized.original(start), Some(start));
+        }
+        let last = new[4].0;
+        assert_eq!(static_targets(&new[0].1, new[0].2), vec![last as Offset]);
+        assert_same_run(&code, &optimized);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+use std::time::{Duration, Instant};
+use crate::assembler::DebugInfo;
+use crate::{Address, Instruction, VirtualMachine};
+
+//Execution profiler.
+//
+//Counts how often the instruction at each code offset runs and how long it takes, and keeps the
+//call stack by following `CALL`, `CALLA` and `BSR` into the function at their target and `RET`
+//back out, so every executed instruction is a sample of the stack it ran in. Functions are named
+//by the label at their entry, or by offset when there is none. Profiling runs the decode loop,
+//so it turns off threaded code and the JIT; the times include the timer itself and are best
+//compared with each other.
+//
+//The report is a flat profile by offset, opcode and function, and the stacks are written in the
+//folded format flamegraph tools read, one `outer;inner count` line per stack.
+
+#[derive(Default)]
+pub struct Profiler {
+    //executions and time per code offset
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    //the instruction first run at each offset
+    instrs: Vec<Option<Instruction>>,
+    //entries of the functions being run, outermost first
+    calls: Vec<Address>,
+    //samples and time per call stack
+    stacks: HashMap<Vec<Address>, (u64, Duration)>,
+    //times each function was called
+    entered: HashMap<Address, u64>,
+}
+
+impl Profiler {
+    //records one run of `instr` at `start` which left the machine at `ip`
+    pub(crate) fn record(&mut self, start: Address, instr: &Instruction, ip: Address, began: Instant) {
+        let elapsed = began.elapsed();
+        if self.counts.len() <= start {
+            self.counts.resize(start + 1, 0);
+            self.times.resize(start + 1, Duration::default());
+            self.instrs.resize(start + 1, None);
+        }
+        self.counts[start] += 1;
+        self.times[start] += elapsed;
+        if self.instrs[start].is_none() {
+            self.instrs[start] = Some(instr.clone());
+        }
+        match self.stacks.get_mut(self.calls.as_slice()) {
+            Some((count, time)) => {
+                *count += 1;
+                *time += elapsed;
+            },
+            None => {
+                self.stacks.insert(self.calls.clone(), (1, elapsed));
+            },
+        }
+        match instr {
+            Instruction::CALL(_) | Instruction::CALLA(_) | Instruction::BSR(_) => {
+                self.calls.push(ip);
+                *self.entered.entry(ip).or_insert(0) += 1;
+            },
+            //a return from the outermost function has nowhere known to go, so it stays put
+            Instruction::RET() if self.calls.len() > 1 => {
+                self.calls.pop();
+            },
+            _ => {},
+        }
+    }
+
+    fn function(debug: &DebugInfo, entry: Address) -> String {
+        debug.symbols.get(&entry).map_or_else(|| format!("ip:{}", entry), |symbol| symbol.name.clone())
+    }
+
+    //the mnemonic of an instruction, as its debug form names it
+    fn opcode(instr: &Instruction) -> String {
+        let name = format!("{:?}", instr);
+        name.split('(').next().unwrap_or_default().to_string()
+    }
+
+    pub fn flat(&self, debug: &DebugInfo) -> String {
+        let total: u64 = self.counts.iter().sum();
+        let time: Duration = self.times.iter().sum();
+        let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+        let mut out = format!("{} instructions in {:?}\n\n", total, time);
+
+        out.push_str(&format!("{:>8}  {:<16} {:>10} {:>7} {:>12}  instruction\n", "offset", "symbol", "count", "%", "time"));
+        for (offset, instr) in self.instrs.iter().enumerate() {
+            if let Some(instr) = instr {
+                let count = self.counts[offset];
+                out.push_str(&format!("{:>8}  {:<16} {:>10} {:>6.2}% {:>12?}  {:?}\n", offset, debug.label(offset).unwrap_or_default(), count, percent(count), self.times[offset], instr));
+            }
+        }
+
+        let mut opcodes: HashMap<String, (u64, Duration)> = HashMap::new();
+        for (offset, instr) in self.instrs.iter().enumerate() {
+            if let Some(instr) = instr {
+                let entry = opcodes.entry(Self::opcode(instr)).or_default();
+                entry.0 += self.counts[offset];
+                entry.1 += self.times[offset];
+            }
+        }
+        let mut opcodes: Vec<_> = opcodes.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+        out.push_str(&format!("\n{:<10} {:>10} {:>7} {:>12}\n", "opcode", "count", "%", "time"));
+        for (name, (count, time)) in opcodes {
+            out.push_str(&format!("{:<10} {:>10} {:>6.2}% {:>12?}\n", name, count, percent(count), time));
+        }
+
+        //self counts the samples with the function innermost, total those with it anywhere
+        let mut functions: HashMap<Address, (u64, u64, Duration)> = HashMap::new();
+        for (stack, &(count, time)) in &self.stacks {
+            let mut seen = Vec::new();
+            for &entry in stack {
+                if !seen.contains(&entry) {
+                    seen.push(entry);
+                    functions.entry(entry).or_default().1 += count;
+                }
+            }
+            if let Some(&entry) = stack.last() {
+                let function = functions.entry(entry).or_default();
+                function.0 += count;
+                function.2 += time;
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+        out.push_str(&format!("\n{:<16} {:>8} {:>10} {:>7} {:>10} {:>7} {:>12}\n", "function", "calls", "self", "%", "total", "%", "self time"));
+        for (entry, (own, all, time)) in functions {
+            let calls = self.entered.get(&entry).copied().unwrap_or(0);
+            out.push_str(&format!("{:<16} {:>8} {:>10} {:>6.2}% {:>10} {:>6.2}% {:>12?}\n", Self::function(debug, entry), calls, own, percent(own), all, percent(all), time));
+        }
+        out
+    }
+
+    //samples per call stack in the folded format, outermost function first
+    pub fn folded(&self, debug: &DebugInfo) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(stack, (count, _))| {
+            let names: Vec<String> = stack.iter().map(|&entry| Self::function(debug, entry)).collect();
+            format!("{} {}", names.join(";"), count)
+        }).collect();
+        lines.sort();
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+impl VirtualMachine {
+    //counts and times every instruction the program runs
+    pub(crate) fn with_profiler(mut self) -> Self {
+        self.profiler = Some(Box::new(Profiler { calls: vec![self.ip], ..Profiler::default() }));
+        self
+    }
+}
+
+//prints the flat profile of a finished run and writes its folded stacks to `path`
+pub fn write(vm: &VirtualMachine, path: &str) {
+    let profiler = match &vm.profiler {
+        Some(profiler) => profiler,
+        None => return,
+    };
+    print!("{}", profiler.flat(&vm.debug));
+    if let Err(e) = fs::write(path, profiler.folded(&vm.debug)) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::VirtualMachine;
+
+    //main calls f twice and f calls g each time
+    #[test]
+    fn nested_calls() {
+        let (code, debug) = assemble("
+        main:
+            BSR f
+            BSR f
+            HALT
+        f:
+            BSR g
+            NOP
+            RET
+        g:
+            NOP
+            RET
+        ", "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 0).with_debug_info(debug).with_profiler();
+        vm.trace = false;
+        vm.cpu();
+        let profiler = vm.profiler.as_ref().expect("profiler is on");
+        //f starts at 7 and g at 12
+        assert_eq!(profiler.counts, vec![1, 0, 0, 1, 0, 0, 1, 2, 0, 0, 2, 2, 2, 2]);
+        assert_eq!(profiler.entered.get(&7), Some(&2));
+        assert_eq!(profiler.entered.get(&12), Some(&2));
+        assert_eq!(profiler.entered.len(), 2);
+        assert_eq!(profiler.folded(&vm.debug), "main 3\nmain;f 6\nmain;f;g 4\n");
+    }
+}
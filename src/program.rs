@@ -0,0 +1,196 @@
+use std::convert::TryInto;
+use std::fs;
+use std::process;
+use crate::assembler::{DebugInfo, Location, Symbol};
+use crate::reader::Reader;
+
+//Program files.
+//
+//A program file is the bytecode, optionally followed by a debug section with the assembler's line
+//and symbol tables. The section is a trailer, so the code still starts at offset 0 and a file
+//without one is plain bytecode. The loader splits the trailer off before the code reaches the
+//verifier or the machine, which never see it.
+//
+//Layout, integers little endian:
+//
+//  the code
+//  the debug section, then its length as u32 and "SVMD"
+//
+//Debug section:
+//
+//  source file name as a u16 length and UTF-8
+//  symbol count as u32, then for each its offset as u64, 1 if it is a function and its name
+//  line count as u32, then for each the instruction offset as u64 and its line and column as u32
+//
+//A file is only taken to have a debug section when its trailer frames one that parses, so plain
+//bytecode that happens to end in "SVMD" still loads as all code.
+
+const MAGIC: &[u8; 4] = b"SVMD";
+
+//items of a debug section
+impl Reader<'_> {
+    fn offset(&mut self) -> Result<usize, String> {
+        let offset = self.u64()?;
+        offset.try_into().map_err(|_| format!("debug section offset {} is out of range", offset))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = u16::from_le_bytes(self.array()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "debug section has a name that isn't UTF-8".to_string())
+    }
+}
+
+fn put_string(s: &str, out: &mut Vec<u8>) {
+    //names are cut at a char boundary to fit their u16 length
+    let mut len = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    out.extend_from_slice(&(len as u16).to_le_bytes());
+    out.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+//appends the debug section for `debug`, without the trailer
+pub fn encode_debug(debug: &DebugInfo, out: &mut Vec<u8>) {
+    put_string(&debug.file, out);
+    out.extend_from_slice(&(debug.symbols.len() as u32).to_le_bytes());
+    for (&offset, symbol) in &debug.symbols {
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        out.push(symbol.function as u8);
+        put_string(&symbol.name, out);
+    }
+    out.extend_from_slice(&(debug.lines.len() as u32).to_le_bytes());
+    for (&offset, location) in &debug.lines {
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        out.extend_from_slice(&(location.line as u32).to_le_bytes());
+        out.extend_from_slice(&(location.column as u32).to_le_bytes());
+    }
+}
+
+//the debug info in a section written by `encode_debug`, which must be all of `section`
+pub fn decode_debug(section: &[u8]) -> Result<DebugInfo, String> {
+    let mut reader = Reader::new(section, "debug section ends early");
+    let mut debug = DebugInfo { file: reader.string()?, ..DebugInfo::default() };
+    for _ in 0..reader.u32()? {
+        let offset = reader.offset()?;
+        let function = reader.array::<1>()?[0] != 0;
+        debug.symbols.insert(offset, Symbol { name: reader.string()?, function });
+    }
+    for _ in 0..reader.u32()? {
+        let offset = reader.offset()?;
+        let location = Location { line: reader.u32()? as usize, column: reader.u32()? as usize };
+        debug.lines.insert(offset, location);
+    }
+    if reader.left() != 0 {
+        return Err("debug section has bytes after the line table".to_string());
+    }
+    Ok(debug)
+}
+
+//the program file for `code` with `debug` as its debug section
+pub fn encode(code: &[u8], debug: &DebugInfo) -> Vec<u8> {
+    let mut out = code.to_vec();
+    encode_debug(debug, &mut out);
+    let len = out.len() - code.len();
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    out
+}
+
+//the code in a program file and its debug info, empty if the file has no debug section
+pub fn decode(bytes: &[u8]) -> (Vec<u8>, DebugInfo) {
+    match split(bytes) {
+        Some((code, debug)) => (code.to_vec(), debug),
+        None => (bytes.to_vec(), DebugInfo::default()),
+    }
+}
+
+//the code before a well formed debug section and the section's debug info
+fn split(bytes: &[u8]) -> Option<(&[u8], DebugInfo)> {
+    if bytes.len() < 8 || !bytes.ends_with(MAGIC) {
+        return None;
+    }
+    let len_at = bytes.len() - 8;
+    let len = u32::from_le_bytes(bytes[len_at..len_at + 4].try_into().expect("slice of 4 bytes")) as usize;
+    let start = len_at.checked_sub(len)?;
+    let debug = decode_debug(&bytes[start..len_at]).ok()?;
+    Some((&bytes[..start], debug))
+}
+
+//writes `code` with its debug info to `path`
+pub fn save(code: &[u8], debug: &DebugInfo, path: &str) {
+    if let Err(e) = fs::write(path, encode(code, debug)) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+//reads back a program file from `path`
+pub fn load(path: &str) -> (Vec<u8>, DebugInfo) {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, DebugInfo};
+    use super::{decode, encode};
+
+    fn program() -> (Vec<u8>, DebugInfo) {
+        assemble("
+        main:
+            MOV R0, 3u8
+            BSR count
+            HALT
+        count:
+            DJNZ R0, count
+            RET
+        ", "test.asm").expect("test program assembles")
+    }
+
+    #[test]
+    fn round_trip() {
+        let (code, debug) = program();
+        let (read_code, read_debug) = decode(&encode(&code, &debug));
+        assert_eq!(read_code, code);
+        assert_eq!(read_debug, debug);
+        assert_eq!(read_debug.locate(read_debug.symbols.keys().copied().max().unwrap()), "\n  --> test.asm:7:13, in count");
+    }
+
+    //a file without the trailer is all code
+    #[test]
+    fn plain_bytecode() {
+        let (code, _) = program();
+        let (read_code, read_debug) = decode(&code);
+        assert_eq!(read_code, code);
+        assert!(read_debug.lines.is_empty() && read_debug.symbols.is_empty());
+    }
+
+    //a trailer that doesn't frame a section which parses is read as code
+    #[test]
+    fn damaged_section() {
+        let (code, debug) = program();
+        let file = encode(&code, &debug);
+        let mut long = file.clone();
+        let len_at = long.len() - 8;
+        long[len_at..len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let (read_code, read_debug) = decode(&long);
+        assert_eq!(read_code, long);
+        assert_eq!(read_debug, DebugInfo::default());
+        //the first byte of the section read as code
+        let mut short = file;
+        short[len_at..len_at + 4].copy_from_slice(&((len_at - code.len() - 1) as u32).to_le_bytes());
+        let (read_code, read_debug) = decode(&short);
+        assert_eq!(read_code, short);
+        assert_eq!(read_debug, DebugInfo::default());
+        //bytecode ending in the magic
+        let mut plain = code;
+        plain.extend_from_slice(b"\0\0\0\0SVMD");
+        assert_eq!(decode(&plain).0, plain);
+    }
+}
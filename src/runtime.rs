@@ -0,0 +1,514 @@
+use std::convert::TryInto;
+use std::fmt;
+use crate::{Address, Register};
+
+//Instruction semantics shared by the interpreter and translated programs.
+//
+//The value and fault types, the register file and the helpers instructions are run with live
+//here, and aot.rs embeds this file in every translated program the way it embeds word.rs, so an
+//instruction does the same thing in both. It only needs the standard library, `Word` and the
+//`Address`, `Offset` and `Register` aliases, which translated programs define as well. The
+//helpers that work on a machine are a macro expanded in the impl of each machine, which both
+//give the fields it uses.
+
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum Immediate {
+    None(),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64)
+}
+
+//two operand ALU operations shared by the stack, three register and register-immediate forms
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Min,
+    Max,
+    Shr,
+    Shl,
+    Sar,
+    Rol,
+    Ror,
+}
+
+impl Immediate {
+    //condition value for SELECT. Zero, +-0.0 and None() are false, everything else is true
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Immediate::None() => false,
+            Immediate::U8(v) => v != 0,
+            Immediate::I8(v) => v != 0,
+            Immediate::U16(v) => v != 0,
+            Immediate::I16(v) => v != 0,
+            Immediate::U32(v) => v != 0,
+            Immediate::I32(v) => v != 0,
+            Immediate::U64(v) => v != 0,
+            Immediate::I64(v) => v != 0,
+            Immediate::F32(v) => v != 0.0,
+            Immediate::F64(v) => v != 0.0,
+        }
+    }
+
+    //integer immediate as a shift count or table index. None for negative values and non-integers
+    pub fn as_count(&self) -> Option<u32> {
+        match *self {
+            Immediate::U8(v) => Some(v as u32),
+            Immediate::I8(v) => v.try_into().ok(),
+            Immediate::U16(v) => Some(v as u32),
+            Immediate::I16(v) => v.try_into().ok(),
+            Immediate::U32(v) => Some(v),
+            Immediate::I32(v) => v.try_into().ok(),
+            Immediate::U64(v) => v.try_into().ok(),
+            Immediate::I64(v) => v.try_into().ok(),
+            _ => None
+        }
+    }
+}
+
+//reasons an instruction can fail
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+    StackUnderflow,
+    BadJumpTarget,
+    TypeMismatch,
+    BadRegister,
+    BadHeapAddress,
+    UnknownOpcode,
+    UnknownImmediate,
+    Truncated,
+    MisalignedTarget,
+    BadCodeAddress,
+    RegisterClass,
+    DivideByZero,
+    ShiftCount,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackUnderflow => write!(f, "Stack underflow"),
+            Fault::BadJumpTarget => write!(f, "Jump before the start of the code"),
+            Fault::TypeMismatch => write!(f, "Operand type mismatch"),
+            Fault::BadRegister => write!(f, "Register outside the register file"),
+            Fault::BadHeapAddress => write!(f, "Heap address outside the heap"),
+            Fault::UnknownOpcode => write!(f, "Unknown opcode"),
+            Fault::UnknownImmediate => write!(f, "Unknown immediate type tag"),
+            Fault::Truncated => write!(f, "Instruction runs past the end of the code"),
+            Fault::MisalignedTarget => write!(f, "Jump target is not the start of an instruction"),
+            Fault::BadCodeAddress => write!(f, "Code address outside the code"),
+            Fault::RegisterClass => write!(f, "Value does not fit the register class"),
+            Fault::DivideByZero => write!(f, "Integer division by zero"),
+            Fault::ShiftCount => write!(f, "Shift count or bit index out of range"),
+        }
+    }
+}
+
+//shape of the register file. The last `floats` registers form a float bank that only holds
+//F32 and F64 values, the rest form an integer bank that never holds floats. With no float
+//bank every register holds any value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RegisterFile {
+    pub count : usize,
+    pub floats : usize,
+}
+
+impl RegisterFile {
+    //at most 256 registers, the most a register byte can name
+    pub const MAX: usize = 256;
+
+    pub fn uniform(count: usize) -> Self {
+        RegisterFile { count, floats: 0 }
+    }
+
+    pub fn banked(integers: usize, floats: usize) -> Self {
+        RegisterFile { count: integers + floats, floats }
+    }
+
+    //whether a register byte can name every register and there is at least one
+    pub fn is_valid(&self) -> bool {
+        self.count > 0 && self.count <= Self::MAX
+    }
+
+    pub fn is_float(&self, reg: Register) -> bool {
+        reg >= self.count - self.floats
+    }
+
+    pub fn accepts(&self, reg: Register, float: bool) -> bool {
+        self.floats == 0 || float == self.is_float(reg)
+    }
+
+    //the value a register holds before anything is written to it
+    pub fn initial(&self, reg: Register) -> Immediate {
+        if self.is_float(reg) { Immediate::F64(0.0) } else { Immediate::U8(0) }
+    }
+}
+
+impl Default for RegisterFile {
+    fn default() -> Self {
+        RegisterFile::uniform(8)
+    }
+}
+
+//Code locations held in registers, on the stack or embedded in jumps name the byte before the
+//instruction they lead to: a jump sets ip to the location and the decode loop steps past it, and
+//a call pushes its own last byte. 0xffff stands for offset 0. Decoded instructions hold the
+//offset of the instruction itself.
+pub fn code_target(location: u16) -> Address {
+    location.wrapping_add(1) as Address
+}
+
+//the location standing for the instruction at `addr`
+pub fn code_location(addr: Address) -> u16 {
+    (addr as u16).wrapping_sub(1)
+}
+
+//applies an operation to any integer, casting the result back to its type
+macro_rules! integer {
+    ($v:expr, |$x:ident| $result:expr) => {
+        match $v {
+            Immediate::U8($x) => Ok(Immediate::U8($result as u8)),
+            Immediate::I8($x) => Ok(Immediate::I8($result as i8)),
+            Immediate::U16($x) => Ok(Immediate::U16($result as u16)),
+            Immediate::I16($x) => Ok(Immediate::I16($result as i16)),
+            Immediate::U32($x) => Ok(Immediate::U32($result as u32)),
+            Immediate::I32($x) => Ok(Immediate::I32($result as i32)),
+            Immediate::U64($x) => Ok(Immediate::U64($result as u64)),
+            Immediate::I64($x) => Ok(Immediate::I64($result as i64)),
+            _ => Err(Fault::TypeMismatch),
+        }
+    };
+}
+
+//applies an operation to an integer and a bit of the same type, given by the second integer, or
+//gives the fault it stops with
+macro_rules! bit {
+    ($v1:expr, $v2:expr, |$x:ident, $bit:ident| $result:expr, $wrap:ident) => {
+        match ($v1, $v2) {
+            (Immediate::U8($x), Immediate::U8(u)) => 1u8.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap U8, $result)),
+            (Immediate::I8($x), Immediate::I8(u)) => 1i8.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap I8, $result)),
+            (Immediate::U16($x), Immediate::U16(u)) => 1u16.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap U16, $result)),
+            (Immediate::I16($x), Immediate::I16(u)) => 1i16.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap I16, $result)),
+            (Immediate::U32($x), Immediate::U32(u)) => 1u32.checked_shl(u).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap U32, $result)),
+            (Immediate::I32($x), Immediate::I32(u)) => 1i32.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap I32, $result)),
+            (Immediate::U64($x), Immediate::U64(u)) => 1u64.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap U64, $result)),
+            (Immediate::I64($x), Immediate::I64(u)) => 1i64.checked_shl(u as u32).ok_or(Fault::ShiftCount).map(|$bit| bit!(@$wrap I64, $result)),
+            _ => Err(Fault::TypeMismatch),
+        }
+    };
+    (@value $variant:ident, $result:expr) => { Immediate::$variant($result) };
+    (@test $variant:ident, $result:expr) => { $result };
+}
+
+pub fn negate(v: Immediate) -> Result<Immediate, Fault> {
+    match v {
+        Immediate::F32(x) => Ok(Immediate::F32(-x)),
+        Immediate::F64(x) => Ok(Immediate::F64(-x)),
+        v => integer!(v, |x| x.wrapping_neg()),
+    }
+}
+
+//unsigned integers are their own absolute value
+pub fn absolute(v: Immediate) -> Result<Immediate, Fault> {
+    match v {
+        Immediate::I8(x) => Ok(Immediate::I8(x.wrapping_abs())),
+        Immediate::I16(x) => Ok(Immediate::I16(x.wrapping_abs())),
+        Immediate::I32(x) => Ok(Immediate::I32(x.wrapping_abs())),
+        Immediate::I64(x) => Ok(Immediate::I64(x.wrapping_abs())),
+        Immediate::F32(x) => Ok(Immediate::F32(x.abs())),
+        Immediate::F64(x) => Ok(Immediate::F64(x.abs())),
+        Immediate::None() => Err(Fault::TypeMismatch),
+        v => Ok(v),
+    }
+}
+
+pub fn float(v: Immediate, single: fn(f32) -> f32, double: fn(f64) -> f64) -> Result<Immediate, Fault> {
+    match v {
+        Immediate::F32(x) => Ok(Immediate::F32(single(x))),
+        Immediate::F64(x) => Ok(Immediate::F64(double(x))),
+        _ => Err(Fault::TypeMismatch),
+    }
+}
+
+pub fn float_pair(v1: Immediate, v2: Immediate, single: fn(f32, f32) -> f32, double: fn(f64, f64) -> f64) -> Result<Immediate, Fault> {
+    match (v1, v2) {
+        (Immediate::F32(x), Immediate::F32(y)) => Ok(Immediate::F32(single(x, y))),
+        (Immediate::F64(x), Immediate::F64(y)) => Ok(Immediate::F64(double(x, y))),
+        _ => Err(Fault::TypeMismatch),
+    }
+}
+
+pub fn float_test(v: Immediate, single: fn(f32) -> bool, double: fn(f64) -> bool) -> Result<bool, Fault> {
+    match v {
+        Immediate::F32(x) => Ok(single(x)),
+        Immediate::F64(x) => Ok(double(x)),
+        _ => Err(Fault::TypeMismatch),
+    }
+}
+
+pub fn fma(v1: Immediate, v2: Immediate, v3: Immediate) -> Result<Immediate, Fault> {
+    match (v1, v2, v3) {
+        (Immediate::F32(v), Immediate::F32(u), Immediate::F32(w)) => Ok(Immediate::F32(v.mul_add(u, w))),
+        (Immediate::F64(v), Immediate::F64(u), Immediate::F64(w)) => Ok(Immediate::F64(v.mul_add(u, w))),
+        _ => Err(Fault::TypeMismatch),
+    }
+}
+
+//an integer minus one, and whether that made it zero
+pub fn decrement(v: Immediate) -> Result<(Immediate, bool), Fault> {
+    Ok(match v {
+        Immediate::U8(v) => (Immediate::U8(v.wrapping_sub(1)), v == 1),
+        Immediate::I8(v) => (Immediate::I8(v.wrapping_sub(1)), v == 1),
+        Immediate::U16(v) => (Immediate::U16(v.wrapping_sub(1)), v == 1),
+        Immediate::I16(v) => (Immediate::I16(v.wrapping_sub(1)), v == 1),
+        Immediate::U32(v) => (Immediate::U32(v.wrapping_sub(1)), v == 1),
+        Immediate::I32(v) => (Immediate::I32(v.wrapping_sub(1)), v == 1),
+        Immediate::U64(v) => (Immediate::U64(v.wrapping_sub(1)), v == 1),
+        Immediate::I64(v) => (Immediate::I64(v.wrapping_sub(1)), v == 1),
+        _ => return Err(Fault::TypeMismatch)
+    })
+}
+
+//the helpers instructions are run with, for a machine with `ip`, `flag_eq`, `flag_gt`,
+//`registers`, `reg`, `stack` and `fault` fields. Each returns false when the instruction fails,
+//with the reason in `fault`
+macro_rules! helpers {
+    () => {
+        fn fault(&mut self, fault: Fault) -> bool {
+            self.fault = Some(fault);
+            false
+        }
+
+        //writes a register, failing if the value does not belong in its bank
+        fn set_reg(&mut self, reg: Register, value: Immediate) -> bool {
+            self.set_word(reg, Word::from(value))
+        }
+
+        fn set_word(&mut self, reg: Register, word: Word) -> bool {
+            if !self.registers.accepts(reg, word.is_float()) {
+                return self.fault(Fault::RegisterClass);
+            }
+            self.reg[reg] = word;
+            true
+        }
+
+        //jumps to a code location held in a U8 or U16
+        fn jump(&mut self, target: Immediate) -> bool {
+            match target {
+                Immediate::U8(v) => {
+                    self.ip = v as Address + 1;
+                    true
+                }
+                Immediate::U16(v) => {
+                    self.ip = code_target(v);
+                    true
+                }
+                _ => self.fault(Fault::TypeMismatch)
+            }
+        }
+
+        //jumps relative to the start of the next instruction
+        fn branch(&mut self, offset: Offset) -> bool {
+            match (self.ip as Offset).checked_add(offset) {
+                Some(target) if target >= 0 => {
+                    self.ip = target as Address;
+                    true
+                },
+                _ => self.fault(Fault::BadJumpTarget)
+            }
+        }
+
+        fn compare(&mut self, v1: Immediate, v2: Immediate) -> bool {
+            self.flag_eq = v1 == v2;
+            self.flag_gt = v1 > v2;
+            true
+        }
+
+        //branches if both values have the same type and the comparison holds
+        fn compare_branch(&mut self, v1: Immediate, v2: Immediate, offset: Offset, holds: fn(&Immediate, &Immediate) -> bool) -> bool {
+            if std::mem::discriminant(&v1) != std::mem::discriminant(&v2) {
+                return self.fault(Fault::TypeMismatch);
+            }
+            if holds(&v1, &v2) {
+                return self.branch(offset);
+            }
+            true
+        }
+
+        //decrements an integer register and branches unless it reached zero
+        fn count_down(&mut self, reg: Register, offset: Offset) -> bool {
+            let (value, zero) = match decrement(self.reg[reg].value()) {
+                Ok(result) => result,
+                Err(fault) => return self.fault(fault),
+            };
+            self.reg[reg] = Word::from(value);
+            if zero {
+                return true;
+            }
+            self.branch(offset)
+        }
+
+        //jumps to the target an integer register indexes, or to the default when it is past the end
+        fn switch(&mut self, reg: Register, default: Address, targets: &[Address]) -> bool {
+            let index = match self.reg[reg].value() {
+                Immediate::F32(_) | Immediate::F64(_) | Immediate::None() => return self.fault(Fault::TypeMismatch),
+                v => v.as_count(),
+            };
+            self.ip = index.and_then(|i| targets.get(i as usize)).copied().unwrap_or(default);
+            true
+        }
+
+        fn pop(&mut self) -> Option<Immediate> {
+            let v = self.stack.pop();
+            if v.is_none() {
+                self.fault(Fault::StackUnderflow);
+            }
+            v
+        }
+
+        //pops the right then the left operand of a stack instruction. With fewer than two values
+        //the stack is left as it is
+        fn pop_operands(&mut self) -> Option<(Immediate, Immediate)> {
+            if self.stack.len() < 2 {
+                return None;
+            }
+            let v2 = self.stack.pop()?;
+            let v1 = self.stack.pop()?;
+            Some((v1, v2))
+        }
+
+        //pops a jump target and takes the jump if the condition holds
+        fn pop_jump(&mut self, condition: bool) -> bool {
+            match self.stack.pop() {
+                Some(target) if condition => self.jump(target),
+                Some(_) => true,
+                None => self.fault(Fault::StackUnderflow)
+            }
+        }
+
+        fn push(&mut self, v: Result<Immediate, Fault>) -> bool {
+            match v {
+                Ok(v) => {
+                    self.stack.push(v);
+                    true
+                },
+                Err(fault) => self.fault(fault)
+            }
+        }
+
+        //sets the equal flag from a test, clearing the greater flag
+        fn test(&mut self, result: Result<bool, Fault>) -> bool {
+            match result {
+                Ok(set) => {
+                    self.flag_eq = set;
+                    self.flag_gt = false;
+                    true
+                },
+                Err(fault) => self.fault(fault)
+            }
+        }
+
+        //pushes the result of a two operand ALU operation on the stack
+        fn push_binary(&mut self, op: BinaryOp, v1: Word, v2: Word) -> bool {
+            self.push(v1.binary(op, v2).map(Word::value))
+        }
+
+        //stores the result of a two operand ALU operation in a register
+        fn store_binary(&mut self, op: BinaryOp, reg: Register, v1: Word, v2: Word) -> bool {
+            match v1.binary(op, v2) {
+                Ok(r) => self.set_word(reg, r),
+                Err(fault) => self.fault(fault)
+            }
+        }
+
+        //applies a two operand ALU operation to the top two stack items
+        fn pop_binary(&mut self, op: BinaryOp) -> bool {
+            match self.pop_operands() {
+                Some((v1, v2)) => self.push_binary(op, Word::from(v1), Word::from(v2)),
+                None => self.fault(Fault::StackUnderflow)
+            }
+        }
+
+        //pushes stack item `n` from the top, taken out when `remove` is set
+        fn stack_item(&mut self, n: usize, remove: bool) -> bool {
+            let len = self.stack.len();
+            if len <= n {
+                return self.fault(Fault::StackUnderflow);
+            }
+            let v = if remove { self.stack.remove(len - 1 - n) } else { self.stack[len - 1 - n] };
+            self.stack.push(v);
+            true
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::VirtualMachine;
+    use super::{Fault, Immediate, RegisterFile};
+
+    #[test]
+    fn register_file_size() {
+        assert!(RegisterFile::default().is_valid());
+        assert!(RegisterFile::uniform(RegisterFile::MAX).is_valid());
+        assert!(RegisterFile::banked(0, 1).is_valid());
+        //--registers 0 and more than a register byte can name
+        assert!(!RegisterFile::uniform(0).is_valid());
+        assert!(!RegisterFile::banked(0, 0).is_valid());
+        assert!(!RegisterFile::uniform(RegisterFile::MAX + 1).is_valid());
+        assert!(!RegisterFile::banked(RegisterFile::MAX, 1).is_valid());
+    }
+
+    #[test]
+    fn banks() {
+        let registers = RegisterFile::banked(2, 2);
+        assert_eq!((0..4).map(|r| registers.is_float(r)).collect::<Vec<_>>(), vec![false, false, true, true]);
+        assert!(registers.accepts(1, false) && !registers.accepts(1, true));
+        assert!(registers.accepts(2, true) && !registers.accepts(2, false));
+        assert_eq!(registers.initial(1), Immediate::U8(0));
+        assert_eq!(registers.initial(2), Immediate::F64(0.0));
+        //without a float bank every register takes both
+        let uniform = RegisterFile::uniform(2);
+        assert!((0..2).all(|r| uniform.accepts(r, false) && uniform.accepts(r, true)));
+    }
+
+    //writing the wrong class of value faults and leaves the register as it was
+    #[test]
+    fn register_class() {
+        let mut vm = VirtualMachine::new(Vec::new(), 0).with_registers(RegisterFile::banked(1, 1));
+        assert!(!vm.set_reg(0, Immediate::F32(1.0)));
+        assert_eq!(vm.fault.take(), Some(Fault::RegisterClass));
+        assert!(!vm.set_reg(1, Immediate::I64(1)));
+        assert_eq!(vm.fault.take(), Some(Fault::RegisterClass));
+        assert_eq!((vm.reg[0].value(), vm.reg[1].value()), (Immediate::U8(0), Immediate::F64(0.0)));
+        assert!(vm.set_reg(0, Immediate::I64(1)) && vm.set_reg(1, Immediate::F32(1.0)));
+    }
+
+    //a register past the end of the file fails verification, float bank included
+    #[test]
+    fn register_past_count() {
+        let verify = |source: &str| {
+            let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+            VirtualMachine::new(code, 0).with_registers(RegisterFile::banked(2, 1)).verify().into_iter().map(|p| p.fault).collect::<Vec<_>>()
+        };
+        assert_eq!(verify("MOV R2, 1.0f64\nADD R0, R1"), vec![]);
+        assert_eq!(verify("MOV R3, 1.0f64"), vec![Fault::BadRegister]);
+        assert_eq!(verify("ADD R0, R3"), vec![Fault::BadRegister]);
+        assert_eq!(verify("ADD3 R3, R0, R1"), vec![Fault::BadRegister]);
+    }
+}
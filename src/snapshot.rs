@@ -0,0 +1,265 @@
+use std::convert::TryInto;
+use std::fs;
+use std::process;
+use crate::program::{decode_debug, encode_debug};
+use crate::reader::Reader;
+use crate::word::Word;
+use crate::{Immediate, RegisterFile, VirtualMachine};
+
+//Snapshots.
+//
+//The whole state of a paused machine, its ip, flags, registers, stack, heap and code, is written
+//with the program's debug info to a binary file that another process can read back and carry on from the same instruction. The
+//VM keeps return addresses on the stack, so the stack is the call stack too. Decode caches,
+//threaded code and the JIT are left out and rebuilt when the restored machine runs. The same
+//state always gives the same bytes.
+//
+//Layout, integers little endian:
+//
+//  "SVMS" and the format version as u16
+//  ip as u64, then a state byte: bit 0 flag_eq, bit 1 flag_gt, bit 2 paused
+//  integer and float register counts as u16, then every register
+//  stack length as u64 and the items from the bottom, then the same for the heap
+//  code length as u64 and the code
+//  debug section length as u64 and the section, as in a program file (src/program.rs)
+//
+//Version 1 snapshots end after the code and restore without debug info.
+//
+//Values are written as in the bytecode, a type tag and the value, and 0xff for an empty value.
+
+const MAGIC: &[u8; 4] = b"SVMS";
+const VERSION: u16 = 2;
+
+//items of a snapshot
+impl Reader<'_> {
+    //a length of items at least a byte each, which can't be more than the bytes left
+    fn length(&mut self) -> Result<usize, String> {
+        match self.u64()? {
+            n if n <= self.left() as u64 => Ok(n as usize),
+            _ => Err(self.ends_early()),
+        }
+    }
+
+    fn immediate(&mut self) -> Result<Immediate, String> {
+        Ok(match self.array::<1>()?[0] {
+            0 => Immediate::U8(u8::from_le_bytes(self.array()?)),
+            1 => Immediate::I8(i8::from_le_bytes(self.array()?)),
+            2 => Immediate::U16(u16::from_le_bytes(self.array()?)),
+            3 => Immediate::I16(i16::from_le_bytes(self.array()?)),
+            4 => Immediate::U32(u32::from_le_bytes(self.array()?)),
+            5 => Immediate::I32(i32::from_le_bytes(self.array()?)),
+            6 => Immediate::U64(u64::from_le_bytes(self.array()?)),
+            7 => Immediate::I64(i64::from_le_bytes(self.array()?)),
+            8 => Immediate::F32(f32::from_le_bytes(self.array()?)),
+            9 => Immediate::F64(f64::from_le_bytes(self.array()?)),
+            0xff => Immediate::None(),
+            tag => return Err(format!("unknown value type tag {} in snapshot", tag)),
+        })
+    }
+
+    fn immediates(&mut self) -> Result<Vec<Immediate>, String> {
+        let len = self.length()?;
+        (0..len).map(|_| self.immediate()).collect()
+    }
+}
+
+impl VirtualMachine {
+    //stops the machine once it has run `count` more instructions, leaving it paused
+    pub(crate) fn with_pause_after(mut self, count: u64) -> Self {
+        self.pause_after = Some(count);
+        self
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.ip as u64).to_le_bytes());
+        out.push(self.flag_eq as u8 | (self.flag_gt as u8) << 1 | (self.paused as u8) << 2);
+        out.extend_from_slice(&((self.registers.count - self.registers.floats) as u16).to_le_bytes());
+        out.extend_from_slice(&(self.registers.floats as u16).to_le_bytes());
+        for word in &self.reg {
+            word.value().encode(&mut out);
+        }
+        for values in [&self.stack, &self.data] {
+            out.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                value.encode(&mut out);
+            }
+        }
+        out.extend_from_slice(&(self.code.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.code);
+        let mut debug = Vec::new();
+        encode_debug(&self.debug, &mut debug);
+        out.extend_from_slice(&(debug.len() as u64).to_le_bytes());
+        out.extend_from_slice(&debug);
+        out
+    }
+
+    //a machine in the state `snapshot` saved, ready for `cpu` to carry on if it was paused
+    pub(crate) fn restore(snapshot: &[u8]) -> Result<VirtualMachine, String> {
+        let mut reader = Reader::new(snapshot, "snapshot ends early");
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err("not a smallvm snapshot".to_string());
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != 1 && version != VERSION {
+            return Err(format!("snapshot format version {} is not supported, expected 1 to {}", version, VERSION));
+        }
+        let ip = reader.u64()?;
+        let state = reader.array::<1>()?[0];
+        let integers = u16::from_le_bytes(reader.array()?) as usize;
+        let floats = u16::from_le_bytes(reader.array()?) as usize;
+        let registers = RegisterFile::banked(integers, floats);
+        if !registers.is_valid() {
+            return Err(format!("snapshot has {} registers, the register file holds 1 to {}", registers.count, RegisterFile::MAX));
+        }
+        let mut reg = Vec::with_capacity(registers.count);
+        for r in 0..registers.count {
+            let value = reader.immediate()?;
+            if !registers.accepts(r, matches!(value, Immediate::F32(_) | Immediate::F64(_))) {
+                return Err(format!("snapshot has a value in R{} that doesn't fit its register class", r));
+            }
+            reg.push(Word::from(value));
+        }
+        let stack = reader.immediates()?;
+        let data = reader.immediates()?;
+        let len = reader.length()?;
+        let code = reader.take(len)?.to_vec();
+        let debug = match version {
+            1 => Default::default(),
+            _ => {
+                let len = reader.length()?;
+                decode_debug(reader.take(len)?).map_err(|e| format!("snapshot {}", e))?
+            },
+        };
+        if reader.left() != 0 {
+            return Err("snapshot has bytes after the debug section".to_string());
+        }
+
+        let mut vm = VirtualMachine::new(code, data.len()).with_registers(registers).with_debug_info(debug);
+        vm.ip = ip.try_into().map_err(|_| format!("snapshot ip {} is out of range", ip))?;
+        vm.flag_eq = state & 1 != 0;
+        vm.flag_gt = state & 2 != 0;
+        vm.paused = state & 4 != 0;
+        vm.reg = reg;
+        vm.stack = stack;
+        vm.data = data;
+        Ok(vm)
+    }
+}
+
+//writes the state of `vm` to `path`
+pub fn save(vm: &VirtualMachine, path: &str) {
+    if let Err(e) = fs::write(path, vm.snapshot()) {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+//reads back a machine saved to `path`
+pub fn load(path: &str) -> VirtualMachine {
+    let restored = fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| VirtualMachine::restore(&bytes));
+    match restored {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::{RegisterFile, VirtualMachine};
+    use crate::program::encode_debug;
+    use super::MAGIC;
+
+    //stops half way through a loop with values in both banks, on the stack and in the heap
+    fn paused() -> VirtualMachine {
+        let (code, debug) = assemble("
+            MOV R0, 10u8
+            MOV R2, 1.5f64
+        top:
+            VPUSHR R0
+            VSTORER [3], R0
+            MUL R2, R2, 2.0f64
+            DJNZ R0, top
+            HALT
+        ", "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 8).with_registers(RegisterFile::banked(2, 1)).with_debug_info(debug).with_pause_after(17);
+        vm.trace = false;
+        vm.cpu();
+        assert!(vm.paused);
+        vm
+    }
+
+    fn error(snapshot: &[u8]) -> String {
+        match VirtualMachine::restore(snapshot) {
+            Ok(_) => panic!("snapshot was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = paused().snapshot();
+        let restored = VirtualMachine::restore(&snapshot).expect("snapshot restores");
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.debug, paused().debug);
+
+        //and carries on to the same end
+        let mut resumed = restored;
+        resumed.trace = false;
+        resumed.cpu();
+        let mut whole = paused();
+        whole.pause_after = None;
+        whole.cpu();
+        assert_eq!(format!("{:?} {:?} {:?}", resumed.reg, resumed.stack, resumed.data), format!("{:?} {:?} {:?}", whole.reg, whole.stack, whole.data));
+    }
+
+    //snapshots from before the debug section restore without one
+    #[test]
+    fn version_1() {
+        let vm = paused();
+        let mut snapshot = vm.snapshot();
+        snapshot[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&1u16.to_le_bytes());
+        let mut debug = Vec::new();
+        encode_debug(&vm.debug, &mut debug);
+        snapshot.truncate(snapshot.len() - debug.len() - 8);
+        let restored = VirtualMachine::restore(&snapshot).expect("version 1 snapshot restores");
+        assert!(restored.debug.lines.is_empty());
+        assert_eq!(restored.code, vm.code);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut snapshot = paused().snapshot();
+        snapshot[0] = b'X';
+        assert_eq!(error(&snapshot), "not a smallvm snapshot");
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut snapshot = paused().snapshot();
+        snapshot[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&99u16.to_le_bytes());
+        assert!(error(&snapshot).starts_with("snapshot format version 99 is not supported"));
+    }
+
+    #[test]
+    fn truncated() {
+        let snapshot = paused().snapshot();
+        for len in 0..snapshot.len() {
+            assert!(VirtualMachine::restore(&snapshot[..len]).is_err(), "accepted the first {} bytes", len);
+        }
+    }
+
+    //R1 holds an integer, which a float bank of two registers would have to take
+    #[test]
+    fn register_class_mismatch() {
+        let mut snapshot = paused().snapshot();
+        let counts = MAGIC.len() + 2 + 8 + 1;
+        snapshot[counts..counts + 4].copy_from_slice(&[1, 0, 2, 0]);
+        assert_eq!(error(&snapshot), "snapshot has a value in R1 that doesn't fit its register class");
+    }
+}
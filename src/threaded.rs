@@ -0,0 +1,416 @@
+use crate::{Address, BinaryOp, Fault, Immediate, Instruction, Offset, Register, VirtualMachine};
+use crate::word::Word;
+
+//Closure-threaded interpreter.
+//
+//Before the program runs every instruction is compiled to a closure with its operands captured,
+//so running it is a single indirect call instead of decoding bytes and matching the instruction.
+//Two common pairs are compiled to one superinstruction: a CMP followed by a conditional jump or
+//branch, and a stack form ALU instruction followed by a VPOP, which stores the result without
+//going through the stack. Jumps into the second instruction of a pair run its own closure.
+//Instructions without a closure of their own, and every instruction while tracing, run through
+//`execute` so the results and the trace are the same as the reference loop. A CWRITE drops the
+//closures covering the byte it writes, which are compiled again from the new bytes when reached.
+
+//runs one compiled instruction, failing with the offset of the instruction that failed
+type Handler = Box<dyn Fn(&mut VirtualMachine) -> Result<(), Address>>;
+
+struct Op {
+    run: Handler,
+    //offset after the last instruction in the op, ip is set to it before the op runs
+    next: Address,
+    //code offset written by a CWRITE
+    writes: Option<Address>,
+}
+
+//second operand of an ALU instruction or compare-and-branch
+#[derive(Copy, Clone)]
+pub(crate) enum Source {
+    Register(Register),
+    Word(Word),
+}
+
+impl Source {
+    fn read(self, vm: &VirtualMachine) -> Word {
+        match self {
+            Source::Register(reg) => vm.reg[reg],
+            Source::Word(word) => word,
+        }
+    }
+}
+
+//where a jump goes when it is taken
+#[derive(Copy, Clone)]
+enum Target {
+    Register(Register),
+    Address(Address),
+    Offset(Offset),
+}
+
+type Condition = fn(&VirtualMachine) -> bool;
+type Comparison = fn(&Immediate, &Immediate) -> bool;
+
+fn check(ok: bool, at: Address) -> Result<(), Address> {
+    if ok {
+        Ok(())
+    } else {
+        Err(at)
+    }
+}
+
+//register and embedded jumps and branches, with the flags they test
+fn jump(instr: &Instruction) -> Option<(Condition, Target)> {
+    let always: Condition = |_| true;
+    let equal: Condition = |vm| vm.flag_eq;
+    let not_equal: Condition = |vm| !vm.flag_eq;
+    let greater: Condition = |vm| vm.flag_gt;
+    let not_greater: Condition = |vm| !vm.flag_gt;
+    Some(match *instr {
+        Instruction::JMP(reg) => (always, Target::Register(reg)),
+        Instruction::JE(reg) => (equal, Target::Register(reg)),
+        Instruction::JNE(reg) => (not_equal, Target::Register(reg)),
+        Instruction::JG(reg) => (greater, Target::Register(reg)),
+        Instruction::JL(reg) => (not_greater, Target::Register(reg)),
+        Instruction::JMPA(addr) => (always, Target::Address(addr)),
+        Instruction::JEA(addr) => (equal, Target::Address(addr)),
+        Instruction::JNEA(addr) => (not_equal, Target::Address(addr)),
+        Instruction::JGA(addr) => (greater, Target::Address(addr)),
+        Instruction::JLEA(addr) => (not_greater, Target::Address(addr)),
+        Instruction::BRA(offset) => (always, Target::Offset(offset)),
+        Instruction::BEQ(offset) => (equal, Target::Offset(offset)),
+        Instruction::BNE(offset) => (not_equal, Target::Offset(offset)),
+        Instruction::BGT(offset) => (greater, Target::Offset(offset)),
+        Instruction::BLE(offset) => (not_greater, Target::Offset(offset)),
+        _ => return None,
+    })
+}
+
+//stack form ALU instructions: operation, left and right operand. DIV and MOD divide the second
+//register by the first, and SHR and SHL shift the immediate by the register
+pub(crate) fn stack_alu(instr: &Instruction) -> Option<(BinaryOp, Source, Source)> {
+    let r = Source::Register;
+    let w = |var: Immediate| Source::Word(Word::from(var));
+    Some(match *instr {
+        Instruction::ADD(reg1, reg2) => (BinaryOp::Add, r(reg1), r(reg2)),
+        Instruction::SUB(reg1, reg2) => (BinaryOp::Sub, r(reg1), r(reg2)),
+        Instruction::MUL(reg1, reg2) => (BinaryOp::Mul, r(reg1), r(reg2)),
+        Instruction::DIV(reg1, reg2) => (BinaryOp::Div, r(reg2), r(reg1)),
+        Instruction::MOD(reg1, reg2) => (BinaryOp::Mod, r(reg2), r(reg1)),
+        Instruction::AND(reg1, reg2) => (BinaryOp::And, r(reg1), r(reg2)),
+        Instruction::OR(reg1, reg2) => (BinaryOp::Or, r(reg1), r(reg2)),
+        Instruction::XOR(reg1, reg2) => (BinaryOp::Xor, r(reg1), r(reg2)),
+        Instruction::MIN(reg1, reg2) => (BinaryOp::Min, r(reg1), r(reg2)),
+        Instruction::MAX(reg1, reg2) => (BinaryOp::Max, r(reg1), r(reg2)),
+        Instruction::SHR(reg1, var) => (BinaryOp::Shr, w(var), r(reg1)),
+        Instruction::SHL(reg1, var) => (BinaryOp::Shl, w(var), r(reg1)),
+        Instruction::SAR(reg1, var) => (BinaryOp::Sar, w(var), r(reg1)),
+        Instruction::ROL(reg1, var) => (BinaryOp::Rol, w(var), r(reg1)),
+        Instruction::ROR(reg1, var) => (BinaryOp::Ror, w(var), r(reg1)),
+        Instruction::SHRR(reg1, reg2) => (BinaryOp::Shr, r(reg1), r(reg2)),
+        Instruction::SHLR(reg1, reg2) => (BinaryOp::Shl, r(reg1), r(reg2)),
+        Instruction::SARR(reg1, reg2) => (BinaryOp::Sar, r(reg1), r(reg2)),
+        Instruction::ROLR(reg1, reg2) => (BinaryOp::Rol, r(reg1), r(reg2)),
+        Instruction::RORR(reg1, reg2) => (BinaryOp::Ror, r(reg1), r(reg2)),
+        _ => return None,
+    })
+}
+
+//register destination ALU instructions: operation, destination, left register and right operand
+pub(crate) fn register_alu(instr: &Instruction) -> Option<(BinaryOp, Register, Register, Source)> {
+    let r = Source::Register;
+    let w = |var: Immediate| Source::Word(Word::from(var));
+    Some(match *instr {
+        Instruction::ADD3(dst, reg1, reg2) => (BinaryOp::Add, dst, reg1, r(reg2)),
+        Instruction::ADDI(dst, reg1, var) => (BinaryOp::Add, dst, reg1, w(var)),
+        Instruction::SUB3(dst, reg1, reg2) => (BinaryOp::Sub, dst, reg1, r(reg2)),
+        Instruction::SUBI(dst, reg1, var) => (BinaryOp::Sub, dst, reg1, w(var)),
+        Instruction::MUL3(dst, reg1, reg2) => (BinaryOp::Mul, dst, reg1, r(reg2)),
+        Instruction::MULI(dst, reg1, var) => (BinaryOp::Mul, dst, reg1, w(var)),
+        Instruction::DIV3(dst, reg1, reg2) => (BinaryOp::Div, dst, reg1, r(reg2)),
+        Instruction::DIVI(dst, reg1, var) => (BinaryOp::Div, dst, reg1, w(var)),
+        Instruction::MOD3(dst, reg1, reg2) => (BinaryOp::Mod, dst, reg1, r(reg2)),
+        Instruction::MODI(dst, reg1, var) => (BinaryOp::Mod, dst, reg1, w(var)),
+        Instruction::AND3(dst, reg1, reg2) => (BinaryOp::And, dst, reg1, r(reg2)),
+        Instruction::ANDI(dst, reg1, var) => (BinaryOp::And, dst, reg1, w(var)),
+        Instruction::OR3(dst, reg1, reg2) => (BinaryOp::Or, dst, reg1, r(reg2)),
+        Instruction::ORI(dst, reg1, var) => (BinaryOp::Or, dst, reg1, w(var)),
+        Instruction::XOR3(dst, reg1, reg2) => (BinaryOp::Xor, dst, reg1, r(reg2)),
+        Instruction::XORI(dst, reg1, var) => (BinaryOp::Xor, dst, reg1, w(var)),
+        Instruction::MIN3(dst, reg1, reg2) => (BinaryOp::Min, dst, reg1, r(reg2)),
+        Instruction::MINI(dst, reg1, var) => (BinaryOp::Min, dst, reg1, w(var)),
+        Instruction::MAX3(dst, reg1, reg2) => (BinaryOp::Max, dst, reg1, r(reg2)),
+        Instruction::MAXI(dst, reg1, var) => (BinaryOp::Max, dst, reg1, w(var)),
+        Instruction::SHR3(dst, reg1, reg2) => (BinaryOp::Shr, dst, reg1, r(reg2)),
+        Instruction::SHRI(dst, reg1, var) => (BinaryOp::Shr, dst, reg1, w(var)),
+        Instruction::SHL3(dst, reg1, reg2) => (BinaryOp::Shl, dst, reg1, r(reg2)),
+        Instruction::SHLI(dst, reg1, var) => (BinaryOp::Shl, dst, reg1, w(var)),
+        Instruction::SAR3(dst, reg1, reg2) => (BinaryOp::Sar, dst, reg1, r(reg2)),
+        Instruction::SARI(dst, reg1, var) => (BinaryOp::Sar, dst, reg1, w(var)),
+        Instruction::ROL3(dst, reg1, reg2) => (BinaryOp::Rol, dst, reg1, r(reg2)),
+        Instruction::ROLI(dst, reg1, var) => (BinaryOp::Rol, dst, reg1, w(var)),
+        Instruction::ROR3(dst, reg1, reg2) => (BinaryOp::Ror, dst, reg1, r(reg2)),
+        Instruction::RORI(dst, reg1, var) => (BinaryOp::Ror, dst, reg1, w(var)),
+        _ => return None,
+    })
+}
+
+//compare-and-branch instructions: condition, left register, right operand and offset
+fn compare_branch(instr: &Instruction) -> Option<(Comparison, Register, Source, Offset)> {
+    let r = Source::Register;
+    let w = |var: Immediate| Source::Word(Word::from(var));
+    let eq: Comparison = |a, b| a == b;
+    let ne: Comparison = |a, b| a != b;
+    let gt: Comparison = |a, b| a > b;
+    let lt: Comparison = |a, b| a < b;
+    Some(match *instr {
+        Instruction::CBEQ(reg1, reg2, offset) => (eq, reg1, r(reg2), offset),
+        Instruction::CBNE(reg1, reg2, offset) => (ne, reg1, r(reg2), offset),
+        Instruction::CBGT(reg1, reg2, offset) => (gt, reg1, r(reg2), offset),
+        Instruction::CBLT(reg1, reg2, offset) => (lt, reg1, r(reg2), offset),
+        Instruction::CBEQI(reg, var, offset) => (eq, reg, w(var), offset),
+        Instruction::CBNEI(reg, var, offset) => (ne, reg, w(var), offset),
+        Instruction::CBGTI(reg, var, offset) => (gt, reg, w(var), offset),
+        Instruction::CBLTI(reg, var, offset) => (lt, reg, w(var), offset),
+        _ => return None,
+    })
+}
+
+fn take(vm: &mut VirtualMachine, target: Target) -> bool {
+    match target {
+        Target::Register(reg) => vm.jump(vm.reg[reg].value()),
+        Target::Address(addr) => {
+            vm.ip = addr;
+            true
+        },
+        Target::Offset(offset) => vm.branch(offset),
+    }
+}
+
+fn compare(vm: &mut VirtualMachine, reg1: Register, reg2: Register) {
+    let v1 = vm.reg[reg1].value();
+    let v2 = vm.reg[reg2].value();
+    vm.flag_eq = v1 == v2;
+    vm.flag_gt = v1 > v2;
+}
+
+//compiles one instruction starting at `start`
+fn single(instr: Instruction, start: Address) -> Handler {
+    if let Some((holds, target)) = jump(&instr) {
+        return Box::new(move |vm| check(!holds(vm) || take(vm, target), start));
+    }
+    if let Some((op, dst, reg1, rhs)) = register_alu(&instr) {
+        return Box::new(move |vm| check(vm.store_binary(op, dst, vm.reg[reg1], rhs.read(vm)), start));
+    }
+    if let Some((op, lhs, rhs)) = stack_alu(&instr) {
+        return Box::new(move |vm| check(vm.push_binary(op, lhs.read(vm), rhs.read(vm)), start));
+    }
+    if let Some((holds, reg1, rhs, offset)) = compare_branch(&instr) {
+        return Box::new(move |vm| check(vm.compare_branch(vm.reg[reg1].value(), rhs.read(vm).value(), offset, holds), start));
+    }
+    match instr {
+        Instruction::NOP() => Box::new(|_| Ok(())),
+        Instruction::MOV(reg, var) => {
+            let word = Word::from(var);
+            Box::new(move |vm| check(vm.set_word(reg, word), start))
+        },
+        Instruction::MOVR(reg1, reg2) => Box::new(move |vm| check(vm.set_word(reg1, vm.reg[reg2]), start)),
+        Instruction::CMP(reg1, reg2) => Box::new(move |vm| {
+            compare(vm, reg1, reg2);
+            Ok(())
+        }),
+        Instruction::DJNZ(reg, offset) => Box::new(move |vm| check(vm.count_down(reg, offset), start)),
+        instr => Box::new(move |vm| check(vm.execute(instr.clone()), start)),
+    }
+}
+
+//compiles a pair of instructions into one, if it is one of the superinstructions
+fn fused(first: &Instruction, second: &Instruction, start: Address, second_start: Address) -> Option<Handler> {
+    if let (Instruction::CMP(reg1, reg2), Some((holds, target))) = (first, jump(second)) {
+        let (reg1, reg2) = (*reg1, *reg2);
+        return Some(Box::new(move |vm| {
+            compare(vm, reg1, reg2);
+            check(!holds(vm) || take(vm, target), second_start)
+        }));
+    }
+    if let (Some((op, lhs, rhs)), Instruction::VPOP(dst)) = (stack_alu(first), second) {
+        let dst = *dst;
+        return Some(Box::new(move |vm| match lhs.read(vm).binary(op, rhs.read(vm)) {
+            Ok(r) => check(vm.set_word(dst, r), second_start),
+            Err(fault) => check(vm.fault(fault), start),
+        }));
+    }
+    None
+}
+
+pub(crate) struct ThreadedCode {
+    //code offset to the op starting there
+    ops: Vec<Option<Op>>,
+}
+
+impl ThreadedCode {
+    //forgets every op covering `offset`
+    fn invalidate(&mut self, offset: Address) {
+        for start in 0..=offset.min(self.ops.len().saturating_sub(1)) {
+            if matches!(self.ops[start], Some(ref op) if op.next > offset) {
+                self.ops[start] = None;
+            }
+        }
+    }
+}
+
+impl VirtualMachine {
+    //runs programs with the closure-threaded interpreter instead of the decode loop
+    pub(crate) fn with_threaded_code(mut self) -> Self {
+        self.threaded = true;
+        self
+    }
+
+    //decodes the instruction at `start`, returning it and the offset after it
+    pub(crate) fn decode_at(&mut self, start: Address) -> Result<(Instruction, Address), Fault> {
+        let saved = self.ip;
+        self.ip = start;
+        let instr = self.decode();
+        let next = self.ip + 1;
+        self.ip = saved;
+        match self.fault.take() {
+            Some(fault) => Err(fault),
+            None => Ok((instr, next)),
+        }
+    }
+
+    //compiles the instruction at `start`, fused with the one after it when they form a pair
+    fn compile_at(&mut self, start: Address) -> Result<Op, Fault> {
+        let (instr, next) = self.decode_at(start)?;
+        let writes = match instr {
+            Instruction::CWRITE(addr, _) => Some(addr),
+            _ => None,
+        };
+        if !self.trace && next < self.code.len() {
+            if let Ok((second, after)) = self.decode_at(next) {
+                if let Some(run) = fused(&instr, &second, start, next) {
+                    return Ok(Op { run, next: after, writes });
+                }
+            }
+        }
+        let run: Handler = if self.trace {
+            Box::new(move |vm| {
+                vm.trace_location(start);
+                check(vm.execute(instr.clone()), start)
+            })
+        } else {
+            single(instr, start)
+        };
+        Ok(Op { run, next, writes })
+    }
+
+    //compiles every instruction from the start of the code
+    fn compile(&mut self) -> ThreadedCode {
+        let mut code = ThreadedCode { ops: (0..self.code.len()).map(|_| None).collect() };
+        let mut start = 0;
+        while start < self.code.len() {
+            match self.decode_at(start) {
+                Ok((_, next)) => {
+                    code.ops[start] = self.compile_at(start).ok();
+                    start = next;
+                },
+                Err(_) => start += 1,
+            }
+        }
+        code
+    }
+
+    //the cpu loop, running compiled ops
+    pub(crate) fn run_threaded(&mut self) {
+        let mut code = self.compile();
+        while self.ip < self.code.len() && self.is_executing {
+            let start = self.ip;
+            if code.ops[start].is_none() {
+                match self.compile_at(start) {
+                    Ok(op) => code.ops[start] = Some(op),
+                    Err(fault) => panic!("{} at ip:{:?}{}", fault, start, self.debug.locate(start)),
+                }
+            }
+            let op = code.ops[start].as_ref().unwrap();
+            let writes = op.writes;
+            self.ip = op.next;
+            if let Err(at) = (op.run)(self) {
+                match self.fault.take() {
+                    Some(fault) => panic!("{} at ip:{:?}{}", fault, at, self.debug.locate(at)),
+                    None => panic!("Failed to execute instruction at ip:{:?}{}", at, self.debug.locate(at)),
+                }
+            }
+            if let Some(addr) = writes {
+                code.invalidate(addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use crate::assembler::assemble;
+    use crate::VirtualMachine;
+
+    //the message a run faulted with, if it did, and the registers, stack and heap it left
+    fn run(source: &str, threaded: bool) -> (Option<String>, String) {
+        let (code, _) = assemble(source, "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 16);
+        if threaded {
+            vm = vm.with_threaded_code();
+        }
+        vm.trace = false;
+        let fault = panic::catch_unwind(AssertUnwindSafe(|| vm.cpu())).err().map(|e| match e.downcast::<String>() {
+            Ok(message) => *message,
+            Err(_) => "panic".to_string(),
+        });
+        (fault, format!("{:?} {:?} {:?}", vm.reg, vm.stack, vm.data))
+    }
+
+    fn same(source: &str) -> Option<String> {
+        let expected = run(source, false);
+        assert_eq!(run(source, true), expected);
+        expected.0
+    }
+
+    #[test]
+    fn programs() {
+        for source in [
+            include_str!("../programs/alu.asm"),
+            include_str!("../programs/arith.asm"),
+            include_str!("../programs/compare.asm"),
+            include_str!("../programs/peephole.asm"),
+            include_str!("../programs/stack.asm"),
+        ] {
+            assert_eq!(same(source), None);
+        }
+    }
+
+    //a stack ALU instruction fused with the VPOP after it fails with the ALU's fault
+    #[test]
+    fn fused_faults() {
+        let divide = "
+            MOV R0, 0u8
+            MOV R1, 7u8
+            DIV R0, R1
+            VPOP R2
+            HALT
+        ";
+        assert_eq!(same(divide).as_deref(), Some("Integer division by zero at ip:8"));
+        assert_eq!(same(&divide.replace("7u8", "7i8")).as_deref(), Some("Operand type mismatch at ip:8"));
+        assert_eq!(same(&divide.replace("DIV", "SHR").replace("0u8", "9u8").replace("7u8", "9u8")).as_deref(), Some("Shift count or bit index out of range at ip:8"));
+    }
+
+    //a compare fused with the jump after it fails at the jump
+    #[test]
+    fn fused_jump_fault() {
+        let fault = same("
+            MOV R0, 1u8
+            MOV R2, 1i8
+            CMP R0, R0
+            JE R2
+            HALT
+        ");
+        assert_eq!(fault.as_deref(), Some("Operand type mismatch at ip:11"));
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{code_location, code_target, Address, Fault, Immediate, Instruction, VirtualMachine, Word};
+use crate::verifier::{static_targets, Problem};
+
+//Static type checker.
+//
+//Abstract interpretation over the bytecode: every register, stack slot and heap cell holds the
+//`Immediate` variant it is known to have on all paths reaching an instruction. Paths are joined
+//at every jump target until nothing changes, then each reachable instruction is checked against
+//the types it will see.
+//
+//Instructions are not modelled one by one. Each one is run on a scratch machine filled with a
+//sample value of every known type, so the checker follows exactly what `execute` accepts. Slots
+//of unknown type are filled with each variant in turn; an instruction is reported only if it
+//fails whatever the unknown slots hold. Flags are not tracked, so both sides of a conditional
+//jump are followed. Jumps through a register or the stack are followed when the target is a
+//constant loaded by `MOV`, `VPUSH` or a call, and code after a call resumes with nothing known.
+//The program is checked as loaded, code written by `CWRITE` is not followed.
+
+//abstract contents of a register, stack slot or heap cell
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Value {
+    Const(Immediate),   //exact value, kept to resolve jump targets
+    Type(Immediate),    //only the variant is known, held as its sample value
+    Any,
+}
+
+//the sample value standing for every value of the same variant
+fn sample(v: Immediate) -> Immediate {
+    match v {
+        Immediate::None() => Immediate::None(),
+        Immediate::U8(_) => Immediate::U8(1),
+        Immediate::I8(_) => Immediate::I8(1),
+        Immediate::U16(_) => Immediate::U16(1),
+        Immediate::I16(_) => Immediate::I16(1),
+        Immediate::U32(_) => Immediate::U32(1),
+        Immediate::I32(_) => Immediate::I32(1),
+        Immediate::U64(_) => Immediate::U64(1),
+        Immediate::I64(_) => Immediate::I64(1),
+        Immediate::F32(_) => Immediate::F32(1.0),
+        Immediate::F64(_) => Immediate::F64(1.0),
+    }
+}
+
+const SAMPLES: [Immediate; 11] = [
+    Immediate::None(), Immediate::U8(1), Immediate::I8(1), Immediate::U16(1), Immediate::I16(1),
+    Immediate::U32(1), Immediate::I32(1), Immediate::U64(1), Immediate::I64(1),
+    Immediate::F32(1.0), Immediate::F64(1.0),
+];
+
+//slots below a stack of unknown depth, enough for the deepest PICK or ROLL
+const STACK_PADDING: usize = 257;
+
+impl Value {
+    fn kind(&self) -> Option<Immediate> {
+        match *self {
+            Value::Const(v) => Some(sample(v)),
+            Value::Type(v) => Some(v),
+            Value::Any => None,
+        }
+    }
+
+    fn join(self, other: Value) -> Value {
+        if self == other {
+            return self;
+        }
+        match (self.kind(), other.kind()) {
+            (Some(a), Some(b)) if a == b => Value::Type(a),
+            _ => Value::Any,
+        }
+    }
+
+    //the value a scratch machine holds for this slot, `fill` standing in for unknown types
+    fn concrete(&self, fill: Immediate) -> Immediate {
+        self.kind().unwrap_or(fill)
+    }
+
+    //the instruction a jump to this value goes to, as `jump` takes it
+    fn jump_target(&self) -> Option<Address> {
+        match *self {
+            Value::Const(Immediate::U8(v)) => Some(v as Address + 1),
+            Value::Const(Immediate::U16(v)) => Some(code_target(v)),
+            _ => None,
+        }
+    }
+}
+
+//an instruction and where control goes after it, for the control flow graph
+pub(crate) struct Flow {
+    pub(crate) start: Address,
+    pub(crate) next: Address,
+    pub(crate) instr: Instruction,
+    //None when no path reaches the instruction, empty when it stops or fails on every path.
+    //Targets past the end of the code leave the program
+    pub(crate) successors: Option<Vec<Address>>,
+    //a register or stack jump whose target isn't a known constant
+    pub(crate) unresolved: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    reg: Vec<Value>,
+    stack: Vec<Value>,
+    //false when only the top of the stack is tracked
+    stack_known: bool,
+    heap: Vec<Value>,
+}
+
+impl State {
+    //what is left after calling a function that can do anything
+    fn unknown(&self) -> State {
+        State { reg: vec![Value::Any; self.reg.len()], stack: Vec::new(), stack_known: false, heap: vec![Value::Any; self.heap.len()] }
+    }
+
+    fn has_unknown(&self) -> bool {
+        !self.stack_known || self.reg.iter().chain(&self.stack).chain(&self.heap).any(|v| *v == Value::Any)
+    }
+
+    //merges the state of another path, returning whether anything changed
+    fn join(&mut self, other: &State) -> bool {
+        let before = self.clone();
+        for (v, &o) in self.reg.iter_mut().zip(&other.reg) {
+            *v = v.join(o);
+        }
+        for (v, &o) in self.heap.iter_mut().zip(&other.heap) {
+            *v = v.join(o);
+        }
+        if !other.stack_known || self.stack.len() != other.stack.len() {
+            let depth = self.stack.len().min(other.stack.len());
+            self.stack.drain(..self.stack.len() - depth);
+            self.stack_known = false;
+        }
+        let skip = other.stack.len() - self.stack.len();
+        for (v, &o) in self.stack.iter_mut().zip(&other.stack[skip..]) {
+            *v = v.join(o);
+        }
+        *self != before
+    }
+}
+
+//abstract result of one slot after running the scratch machines, as (old, new) pairs from the
+//sample runs and the runs holding exact constants. Unchanged slots keep what was known, written
+//ones get the variant they were given in every run. `before` is None for pushed stack slots
+fn merge_slot(before: Option<Value>, runs: &[(Option<Immediate>, Immediate)], exact: &[(Option<Immediate>, Immediate)]) -> Value {
+    if let Some(before) = before {
+        if runs.iter().chain(exact).all(|(old, new)| *old == Some(*new)) {
+            return before;
+        }
+    }
+    let kind = sample(runs[0].1);
+    if runs.iter().all(|(_, new)| sample(*new) == kind) {
+        Value::Type(kind)
+    } else {
+        Value::Any
+    }
+}
+
+//machine contents after a scratch run
+struct Snapshot {
+    reg: Vec<Immediate>,
+    stack: Vec<Immediate>,
+    heap: Vec<Immediate>,
+}
+
+impl Snapshot {
+    fn of(vm: &VirtualMachine) -> Snapshot {
+        Snapshot { reg: vm.reg.iter().map(|w| w.value()).collect(), stack: vm.stack.clone(), heap: vm.data.clone() }
+    }
+}
+
+impl VirtualMachine {
+    //a machine holding sample values for `state`, with `fill` for every unknown slot. With `exact`
+    //constants keep their own value, which tells a slot holding a constant apart from one that
+    //was overwritten with the same sample
+    fn scratch(&self, state: &State, fill: Immediate, exact: bool, next: Address) -> VirtualMachine {
+        let concrete = |v: &Value| match *v {
+            Value::Const(c) if exact => c,
+            _ => v.concrete(fill),
+        };
+        let mut vm = VirtualMachine::new(self.code.clone(), self.data.len()).with_registers(self.registers);
+        vm.trace = false;
+        vm.ip = next;
+        for (r, v) in state.reg.iter().enumerate() {
+            let value = concrete(v);
+            if self.registers.accepts(r, matches!(value, Immediate::F32(_) | Immediate::F64(_))) {
+                vm.reg[r] = Word::from(value);
+            }
+        }
+        if !state.stack_known {
+            vm.stack = vec![fill; STACK_PADDING];
+        }
+        vm.stack.extend(state.stack.iter().map(concrete));
+        vm.data = state.heap.iter().map(concrete).collect();
+        vm
+    }
+
+    fn scratch_copy(&self) -> VirtualMachine {
+        let mut vm = VirtualMachine::new(self.code.clone(), 0).with_registers(self.registers);
+        vm.trace = false;
+        vm.ip = self.ip;
+        vm.reg = self.reg.clone();
+        vm.stack = self.stack.clone();
+        vm.data = self.data.clone();
+        vm
+    }
+
+    //runs one instruction on the abstract state, returning the state after it or the fault it
+    //stops with on every path
+    fn step(&self, instr: &Instruction, next: Address, state: &State) -> Result<State, Fault> {
+        if let Instruction::PRINTR(_) | Instruction::PRINTV(_) = instr {
+            return Ok(state.clone());
+        }
+        let fills: &[Immediate] = if state.has_unknown() { &SAMPLES } else { &SAMPLES[..1] };
+        let mut runs = Vec::new();
+        let mut exact_runs = Vec::new();
+        let mut fault = None;
+        for &fill in fills {
+            let before = self.scratch(state, fill, false, next);
+            let mut after = before.scratch_copy();
+            if !after.execute(instr.clone()) {
+                fault.get_or_insert(after.fault.unwrap_or(Fault::TypeMismatch));
+                continue;
+            }
+            runs.push((Snapshot::of(&before), Snapshot::of(&after)));
+            //a constant can make an instruction fail where its type alone does not, e.g. a zero
+            //divisor. Only failures of the sample run are reported
+            let before = self.scratch(state, fill, true, next);
+            let mut after = before.scratch_copy();
+            if after.execute(instr.clone()) {
+                exact_runs.push((Snapshot::of(&before), Snapshot::of(&after)));
+            }
+        }
+        if runs.is_empty() {
+            return Err(fault.unwrap_or(Fault::TypeMismatch));
+        }
+
+        let pairs = |runs: &[(Snapshot, Snapshot)], i: usize, of: fn(&Snapshot) -> &Vec<Immediate>| -> Vec<(Option<Immediate>, Immediate)> {
+            runs.iter().filter(|(_, a)| i < of(a).len()).map(|(b, a)| (of(b).get(i).copied(), of(a)[i])).collect()
+        };
+        let slot = |before: Option<Value>, i: usize, of: fn(&Snapshot) -> &Vec<Immediate>| {
+            merge_slot(before, &pairs(&runs, i, of), &pairs(&exact_runs, i, of))
+        };
+        let mut out = state.clone();
+        for r in 0..out.reg.len() {
+            out.reg[r] = slot(Some(state.reg[r]), r, |vm| &vm.reg);
+        }
+        for a in 0..out.heap.len() {
+            out.heap[a] = slot(Some(state.heap[a]), a, |vm| &vm.heap);
+        }
+        let depth = runs[0].1.stack.len();
+        if runs.iter().any(|(_, a)| a.stack.len() != depth) {
+            out.stack.clear();
+            out.stack_known = false;
+        } else {
+            let padding = if state.stack_known { 0 } else { STACK_PADDING };
+            let old = |i: usize| if i < padding { Some(Value::Any) } else { state.stack.get(i - padding).copied() };
+            let mut stack: Vec<Value> = (0..depth).map(|i| slot(old(i), i, |vm| &vm.stack)).collect();
+            if !state.stack_known {
+                let unknown = stack.iter().take_while(|v| **v == Value::Any).count();
+                stack.drain(..unknown);
+            }
+            out.stack = stack;
+        }
+
+        //values that can end up as jump targets keep their exact value
+        let top = state.stack.last().copied().unwrap_or(Value::Any);
+        let pushed = match *instr {
+            Instruction::MOV(reg, v) => {
+                out.reg[reg] = Value::Const(v);
+                None
+            },
+            Instruction::MOVR(reg1, reg2) => {
+                out.reg[reg1] = state.reg[reg2];
+                None
+            },
+            Instruction::VPOP(reg) => {
+                out.reg[reg] = top;
+                None
+            },
+            Instruction::VPUSH(v) => Some(Value::Const(v)),
+            Instruction::VPUSHR(reg) => Some(state.reg[reg]),
+            Instruction::DUP() => Some(top),
+            Instruction::CALL(_) | Instruction::CALLA(_) | Instruction::BSR(_) => Some(Value::Const(Immediate::U16(code_location(next)))),
+            _ => None,
+        };
+        if let (Some(value), Some(slot)) = (pushed, out.stack.last_mut()) {
+            *slot = value;
+        }
+        Ok(out)
+    }
+
+    //the instructions control can reach after `instr` runs in `state`, each with whether it
+    //resumes after a call, and whether a register or stack jump has a target that isn't known
+    fn successors(instr: &Instruction, next: Address, state: &State) -> (Vec<(Address, bool)>, bool) {
+        let top = state.stack.last().and_then(Value::jump_target);
+        let mut targets: Vec<Option<Address>> = static_targets(instr, next).into_iter().map(|t| Some(t as Address)).collect();
+        let falls_through = match *instr {
+            Instruction::JMP(reg) => {
+                targets.push(state.reg[reg].jump_target());
+                false
+            },
+            Instruction::JE(reg) | Instruction::JNE(reg) | Instruction::JG(reg) | Instruction::JL(reg) => {
+                targets.push(state.reg[reg].jump_target());
+                true
+            },
+            Instruction::CALL(reg) => {
+                targets.push(state.reg[reg].jump_target());
+                true
+            },
+            Instruction::SJMP() | Instruction::RET() => {
+                targets.push(top);
+                false
+            },
+            Instruction::SJE() | Instruction::SJNE() | Instruction::SJG() | Instruction::SJLE() => {
+                targets.push(top);
+                true
+            },
+            Instruction::JMPA(_) | Instruction::BRA(_) | Instruction::SWITCH(..) | Instruction::HALT() => false,
+            _ => true,
+        };
+        let call = matches!(instr, Instruction::CALL(_) | Instruction::CALLA(_) | Instruction::BSR(_));
+        let unresolved = targets.contains(&None);
+        let mut successors: Vec<(Address, bool)> = targets.into_iter().flatten().map(|t| (t, false)).collect();
+        if falls_through {
+            successors.push((next, call));
+        }
+        (successors, unresolved)
+    }
+
+    //decodes the program and finds the abstract state at every instruction reachable from ip
+    fn analyze(&mut self) -> (BTreeMap<Address, (Instruction, Address)>, HashMap<Address, State>) {
+        let saved_ip = self.ip;
+        let mut program = BTreeMap::new();
+        self.ip = 0;
+        while self.ip < self.code.len() {
+            let start = self.ip;
+            let instr = self.decode();
+            self.ip += 1;
+            program.insert(start, (instr, self.ip));
+        }
+        self.ip = saved_ip;
+
+        let initial = State {
+            reg: self.reg.iter().map(|&v| Value::Const(v.value())).collect(),
+            stack: self.stack.iter().map(|&v| Value::Const(v)).collect(),
+            stack_known: true,
+            heap: self.data.iter().map(|&v| Value::Const(v)).collect(),
+        };
+        //an ip outside the code, as in an empty program, reaches nothing
+        let mut states: HashMap<Address, State> = HashMap::new();
+        let mut worklist = Vec::new();
+        if program.contains_key(&self.ip) {
+            states.insert(self.ip, initial);
+            worklist.push(self.ip);
+        }
+        while let Some(at) = worklist.pop() {
+            let (instr, next) = match program.get(&at) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let state = states[&at].clone();
+            let out = match self.step(instr, *next, &state) {
+                Ok(out) => out,
+                Err(_) => continue,
+            };
+            for (target, after_call) in VirtualMachine::successors(instr, *next, &state).0 {
+                if !program.contains_key(&target) {
+                    continue;
+                }
+                let incoming = if after_call { out.unknown() } else { out.clone() };
+                let changed = match states.get_mut(&target) {
+                    Some(known) => known.join(&incoming),
+                    None => {
+                        states.insert(target, incoming);
+                        true
+                    },
+                };
+                if changed {
+                    worklist.push(target);
+                }
+            }
+        }
+        (program, states)
+    }
+
+    //reports every reachable instruction that fails on every path reaching it. Programs that fail
+    //verification are left to the verifier
+    pub(crate) fn typecheck(&mut self) -> Vec<Problem> {
+        if !self.verify().is_empty() {
+            return Vec::new();
+        }
+        let (program, states) = self.analyze();
+        let mut problems: Vec<Problem> = states.iter().filter_map(|(&at, state)| {
+            let (instr, next) = &program[&at];
+            self.step(instr, *next, state).err().map(|fault| Problem { offset: at, fault })
+        }).collect();
+        problems.sort_by_key(|p| p.offset);
+        problems
+    }
+
+    //every instruction of a verified program in order, with where control can go after it
+    pub(crate) fn flow(&mut self) -> Vec<Flow> {
+        let (program, states) = self.analyze();
+        program.into_iter().map(|(start, (instr, next))| match states.get(&start) {
+            Some(state) => {
+                let (successors, unresolved) = VirtualMachine::successors(&instr, next, state);
+                let fails = self.step(&instr, next, state).is_err();
+                let successors = if fails { Vec::new() } else { successors.into_iter().map(|(t, _)| t).collect() };
+                Flow { start, next, instr, successors: Some(successors), unresolved: unresolved && !fails }
+            },
+            None => Flow { start, next, instr, successors: None, unresolved: false },
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::VirtualMachine;
+
+    #[test]
+    fn empty_program() {
+        let mut vm = VirtualMachine::new(Vec::new(), 0);
+        assert!(vm.typecheck().is_empty());
+        assert!(vm.flow().is_empty());
+    }
+}
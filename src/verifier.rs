@@ -0,0 +1,195 @@
+use std::fmt;
+use crate::{Address, Fault, Instruction, Offset, VirtualMachine};
+
+//Bytecode verifier, run before the program executes.
+//
+//Decodes every instruction from the start of the code and reports each problem with the offset
+//of the instruction it belongs to: unknown opcodes and immediate tags, registers and heap
+//addresses out of range, code writes outside the code, instructions cut off by the end of the
+//code, and embedded jump, branch and switch targets that don't land on the start of an
+//instruction or the end of the code. Targets taken from a register or the stack are only known
+//when they run.
+
+#[derive(Debug)]
+pub struct Problem {
+    pub offset: Address,
+    pub fault: Fault,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at ip:{}", self.fault, self.offset)
+    }
+}
+
+//targets an instruction can jump to that are known without running it. `next` is the offset of
+//the following instruction, which relative branches count from
+pub(crate) fn static_targets(instr: &Instruction, next: Address) -> Vec<Offset> {
+    let relative = |offset: Offset| vec![next as Offset + offset];
+    match *instr {
+        Instruction::JMPA(addr)
+        | Instruction::JEA(addr)
+        | Instruction::JNEA(addr)
+        | Instruction::JGA(addr)
+        | Instruction::JLEA(addr)
+        | Instruction::CALLA(addr) => vec![addr as Offset],
+        Instruction::BRA(offset)
+        | Instruction::BEQ(offset)
+        | Instruction::BNE(offset)
+        | Instruction::BGT(offset)
+        | Instruction::BLE(offset)
+        | Instruction::BSR(offset) => relative(offset),
+        Instruction::CBEQ(_, _, offset)
+        | Instruction::CBNE(_, _, offset)
+        | Instruction::CBGT(_, _, offset)
+        | Instruction::CBLT(_, _, offset)
+        | Instruction::CBEQI(_, _, offset)
+        | Instruction::CBNEI(_, _, offset)
+        | Instruction::CBGTI(_, _, offset)
+        | Instruction::CBLTI(_, _, offset)
+        | Instruction::DJNZ(_, offset) => relative(offset),
+        Instruction::SWITCH(_, default, ref targets) => {
+            let mut all = vec![default as Offset];
+            all.extend(targets.iter().map(|&t| t as Offset));
+            all
+        },
+        _ => Vec::new(),
+    }
+}
+
+impl VirtualMachine {
+    //checks the whole program, returning every problem found ordered by offset
+    pub(crate) fn verify(&mut self) -> Vec<Problem> {
+        let saved_ip = self.ip;
+        let saved_fault = self.fault.take();
+        let mut problems = Vec::new();
+        let mut starts = vec![false; self.code.len()];
+        let mut jumps = Vec::new();
+
+        self.ip = 0;
+        while self.ip < self.code.len() {
+            let start = self.ip;
+            starts[start] = true;
+            let instr = self.decode();
+            self.ip += 1;
+            if let Some(fault) = self.fault.take() {
+                problems.push(Problem { offset: start, fault });
+            }
+            for target in static_targets(&instr, self.ip) {
+                jumps.push((start, target));
+            }
+            if let Instruction::CWRITE(addr, _) = instr {
+                if addr >= self.code.len() {
+                    problems.push(Problem { offset: start, fault: Fault::BadCodeAddress });
+                }
+            }
+        }
+
+        for (offset, target) in jumps {
+            //jumping to the end of the code halts, like running off it
+            if target < 0 {
+                problems.push(Problem { offset, fault: Fault::BadJumpTarget });
+            } else if target as Address != self.code.len() && !starts.get(target as Address).copied().unwrap_or(false) {
+                problems.push(Problem { offset, fault: Fault::MisalignedTarget });
+            }
+        }
+        problems.sort_by_key(|p| p.offset);
+
+        self.ip = saved_ip;
+        self.fault = saved_fault;
+        problems
+    }
+
+    //the verifier's report on the program with the source location of each problem, if it has any
+    pub(crate) fn verified(&mut self) -> Result<(), String> {
+        let problems = self.verify();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let report: Vec<String> = problems.iter().map(|p| format!("{}{}", p, self.debug.locate(p.offset))).collect();
+        Err(format!("Program failed verification:\n{}", report.join("\n")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::{Fault, VirtualMachine};
+
+    //the faults found in `code` and the offsets they were found at
+    fn problems(code: Vec<u8>) -> Vec<(usize, Fault)> {
+        VirtualMachine::new(code, 4).verify().into_iter().map(|p| (p.offset, p.fault)).collect()
+    }
+
+    fn assembled(source: &str) -> Vec<u8> {
+        assemble(source, "test.asm").expect("test program assembles").0
+    }
+
+    #[test]
+    fn valid_program() {
+        assert_eq!(problems(assembled(include_str!("../programs/compare.asm"))), vec![]);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_eq!(problems(vec![0, 200, 22]), vec![(1, Fault::UnknownOpcode)]);
+    }
+
+    #[test]
+    fn unknown_immediate() {
+        //MOV R0 with type tag 99
+        assert_eq!(problems(vec![1, 0, 99, 0, 22]), vec![(0, Fault::UnknownImmediate)]);
+    }
+
+    #[test]
+    fn register_and_heap_address() {
+        assert_eq!(problems(assembled("MOV R8, 1u8")), vec![(0, Fault::BadRegister)]);
+        assert_eq!(problems(assembled("VSTORE [4], 1u8")), vec![(0, Fault::BadHeapAddress)]);
+        assert_eq!(problems(assembled("VSTORE [3], 1u8")), vec![]);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut code = assembled("NOP\nMOV R0, 1u32");
+        code.pop();
+        assert_eq!(problems(code), vec![(1, Fault::Truncated)]);
+    }
+
+    #[test]
+    fn code_address() {
+        assert_eq!(problems(assembled("CWRITE 9u16, R0")), vec![(0, Fault::BadCodeAddress)]);
+    }
+
+    #[test]
+    fn jump_targets() {
+        //location 1 is the byte before offset 2, inside the MOV
+        assert_eq!(problems(assembled("MOV R0, 1u8\nJMP 1u16")), vec![(4, Fault::MisalignedTarget)]);
+        assert_eq!(problems(assembled("NOP\nBRA -5i16")), vec![(1, Fault::BadJumpTarget)]);
+        let switch = assembled("MOV R0, 0u8\nSWITCH R0, 1u16, 2u16");
+        assert_eq!(problems(switch), vec![(4, Fault::MisalignedTarget), (4, Fault::MisalignedTarget)]);
+    }
+
+    //a label after the last instruction is the end of the code, where a jump halts
+    #[test]
+    fn jump_to_end() {
+        for jump in ["JMP end", "BRA end", "CBEQI R0, 1u8, end"] {
+            let code = assembled(&format!("MOV R0, 1u8\n{}\nPRINTR R0\nend:", jump));
+            assert_eq!(problems(code.clone()), vec![], "{}", jump);
+            let mut vm = VirtualMachine::new(code, 4);
+            vm.trace = false;
+            vm.cpu();
+            assert_eq!(vm.ip, vm.code.len(), "{}", jump);
+        }
+        //one past the end is still outside
+        assert_eq!(problems(assembled("JMP 3u16")), vec![(0, Fault::MisalignedTarget)]);
+    }
+
+    //the report names the source of each problem
+    #[test]
+    fn report() {
+        let (code, debug) = assemble("NOP\nJMP 100u16", "test.asm").expect("test program assembles");
+        let mut vm = VirtualMachine::new(code, 4).with_debug_info(debug);
+        assert_eq!(vm.verified().unwrap_err(), "Program failed verification:\nJump target is not the start of an instruction at ip:1\n  --> test.asm:2:1");
+        assert_eq!(VirtualMachine::new(assembled("NOP"), 4).verified(), Ok(()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use crate::{BinaryOp, Fault, Immediate};
+
+//Compact register value.
+//
+//A word is the payload widened to 64 bits plus a one byte type tag. Integers are kept canonical,
+//zero extended when unsigned and sign extended when signed, so bitwise operations work on the
+//whole payload and arithmetic only has to truncate its result back to the width of the tag.
+//Floats keep their own bits, an f32 in the low half. A binary operation checks that both tags
+//match and then does a single 64-bit operation, instead of matching every pair of `Immediate`
+//variants. `Immediate` stays the value type everywhere else and converts to and from a word
+//with a shift or a sign extension.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum Tag {
+    None,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl Tag {
+    //bit width of an integer tag, 0 for floats and None
+    fn width(self) -> u32 {
+        match self {
+            Tag::U8 | Tag::I8 => 8,
+            Tag::U16 | Tag::I16 => 16,
+            Tag::U32 | Tag::I32 => 32,
+            Tag::U64 | Tag::I64 => 64,
+            _ => 0,
+        }
+    }
+
+    fn is_signed(self) -> bool {
+        matches!(self, Tag::I8 | Tag::I16 | Tag::I32 | Tag::I64)
+    }
+
+    //all ones in the low `width` bits
+    fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.width())
+    }
+
+    //truncates a 64-bit result to the tag's width and extends it back to canonical form
+    fn canonical(self, bits: u64) -> u64 {
+        match self {
+            Tag::U8 => bits as u8 as u64,
+            Tag::I8 => bits as i8 as u64,
+            Tag::U16 => bits as u16 as u64,
+            Tag::I16 => bits as i16 as u64,
+            Tag::U32 => bits as u32 as u64,
+            Tag::I32 => bits as i32 as u64,
+            _ => bits,
+        }
+    }
+}
+
+//laid out as the payload followed by the tag byte, which compiled code reads directly
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Word {
+    bits: u64,
+    tag: Tag,
+}
+
+impl From<Immediate> for Word {
+    fn from(v: Immediate) -> Word {
+        let (bits, tag) = match v {
+            Immediate::None() => (0, Tag::None),
+            Immediate::U8(v) => (v as u64, Tag::U8),
+            Immediate::I8(v) => (v as u64, Tag::I8),
+            Immediate::U16(v) => (v as u64, Tag::U16),
+            Immediate::I16(v) => (v as u64, Tag::I16),
+            Immediate::U32(v) => (v as u64, Tag::U32),
+            Immediate::I32(v) => (v as u64, Tag::I32),
+            Immediate::U64(v) => (v, Tag::U64),
+            Immediate::I64(v) => (v as u64, Tag::I64),
+            Immediate::F32(v) => (v.to_bits() as u64, Tag::F32),
+            Immediate::F64(v) => (v.to_bits(), Tag::F64),
+        };
+        Word { bits, tag }
+    }
+}
+
+impl fmt::Debug for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value().fmt(f)
+    }
+}
+
+impl Word {
+    pub fn value(self) -> Immediate {
+        let bits = self.bits;
+        match self.tag {
+            Tag::None => Immediate::None(),
+            Tag::U8 => Immediate::U8(bits as u8),
+            Tag::I8 => Immediate::I8(bits as i8),
+            Tag::U16 => Immediate::U16(bits as u16),
+            Tag::I16 => Immediate::I16(bits as i16),
+            Tag::U32 => Immediate::U32(bits as u32),
+            Tag::I32 => Immediate::I32(bits as i32),
+            Tag::U64 => Immediate::U64(bits),
+            Tag::I64 => Immediate::I64(bits as i64),
+            Tag::F32 => Immediate::F32(f32::from_bits(bits as u32)),
+            Tag::F64 => Immediate::F64(f64::from_bits(bits)),
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn bits(self) -> u64 {
+        self.bits
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn tag_byte(self) -> u8 {
+        self.tag as u8
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self.tag, Tag::F32 | Tag::F64)
+    }
+
+    //integer as a shift count, like `Immediate::as_count`
+    fn as_count(self) -> Result<u32, Fault> {
+        if self.tag.width() == 0 {
+            return Err(Fault::TypeMismatch);
+        }
+        if (self.tag.is_signed() && (self.bits as i64) < 0) || self.bits > u32::MAX as u64 {
+            return Err(Fault::ShiftCount);
+        }
+        Ok(self.bits as u32)
+    }
+
+    fn with_bits(self, bits: u64) -> Word {
+        Word { bits: self.tag.canonical(bits), tag: self.tag }
+    }
+
+    //applies a two operand ALU operation, or gives the fault it stops with: a type mismatch when
+    //the operand types don't fit the operation, integer division by zero or an out of range shift
+    pub fn binary(self, op: BinaryOp, rhs: Word) -> Result<Word, Fault> {
+        match op {
+            BinaryOp::Shr | BinaryOp::Shl | BinaryOp::Sar | BinaryOp::Rol | BinaryOp::Ror => return self.shift(op, rhs.as_count()?),
+            _ => {},
+        }
+        if self.tag != rhs.tag {
+            return Err(Fault::TypeMismatch);
+        }
+        match self.tag {
+            Tag::None => match op {
+                BinaryOp::Add | BinaryOp::Sub => Ok(self),
+                _ => Err(Fault::TypeMismatch),
+            },
+            Tag::F32 => {
+                let (a, b) = (f32::from_bits(self.bits as u32), f32::from_bits(rhs.bits as u32));
+                let r = match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Mod => a % b,
+                    BinaryOp::Min => a.min(b),
+                    BinaryOp::Max => a.max(b),
+                    _ => return Err(Fault::TypeMismatch),
+                };
+                Ok(Word { bits: r.to_bits() as u64, tag: Tag::F32 })
+            },
+            Tag::F64 => {
+                let (a, b) = (f64::from_bits(self.bits), f64::from_bits(rhs.bits));
+                let r = match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Mod => a % b,
+                    BinaryOp::Min => a.min(b),
+                    BinaryOp::Max => a.max(b),
+                    _ => return Err(Fault::TypeMismatch),
+                };
+                Ok(Word { bits: r.to_bits(), tag: Tag::F64 })
+            },
+            tag => {
+                let (a, b) = (self.bits, rhs.bits);
+                let signed = tag.is_signed();
+                let r = match op {
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::And => a & b,
+                    BinaryOp::Or => a | b,
+                    BinaryOp::Xor => a ^ b,
+                    BinaryOp::Div | BinaryOp::Mod if b == 0 => return Err(Fault::DivideByZero),
+                    BinaryOp::Div if signed => (a as i64).wrapping_div(b as i64) as u64,
+                    BinaryOp::Mod if signed => (a as i64).wrapping_rem(b as i64) as u64,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Mod => a % b,
+                    BinaryOp::Min if signed => (a as i64).min(b as i64) as u64,
+                    BinaryOp::Max if signed => (a as i64).max(b as i64) as u64,
+                    BinaryOp::Min => a.min(b),
+                    BinaryOp::Max => a.max(b),
+                    _ => return Err(Fault::TypeMismatch),
+                };
+                Ok(self.with_bits(r))
+            },
+        }
+    }
+
+    //shifts and rotates within the width of the tag. Shifts by the width or more fail, rotates
+    //wrap their count
+    fn shift(self, op: BinaryOp, amount: u32) -> Result<Word, Fault> {
+        let width = self.tag.width();
+        if width == 0 {
+            return Err(Fault::TypeMismatch);
+        }
+        let unsigned = self.bits & self.tag.mask();
+        let r = match op {
+            BinaryOp::Shr | BinaryOp::Shl | BinaryOp::Sar if amount >= width => return Err(Fault::ShiftCount),
+            BinaryOp::Shr => unsigned >> amount,
+            BinaryOp::Shl => unsigned << amount,
+            BinaryOp::Sar if self.tag.is_signed() => ((self.bits as i64) >> amount) as u64,
+            BinaryOp::Sar => unsigned >> amount,
+            BinaryOp::Rol | BinaryOp::Ror => {
+                let n = if op == BinaryOp::Rol { amount % width } else { (width - amount % width) % width };
+                if n == 0 {
+                    unsigned
+                } else {
+                    (unsigned << n) | (unsigned >> (width - n))
+                }
+            },
+            _ => return Err(Fault::TypeMismatch),
+        };
+        Ok(self.with_bits(r))
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+//Checks over every program in programs/, run through the smallvm binary.
+
+fn programs() -> Vec<PathBuf> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/programs");
+    let mut programs: Vec<PathBuf> = fs::read_dir(dir).expect("programs/ is readable")
+        .map(|entry| entry.expect("programs/ is readable").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+//runs smallvm with `option` on every program at once, failing with the output of those it
+//fails on
+fn check_all(option: &'static str) {
+    let runs: Vec<_> = programs().into_iter().map(|path| thread::spawn(move || {
+        let output = Command::new(env!("CARGO_BIN_EXE_smallvm")).arg(option).arg(&path).output().expect("smallvm runs");
+        (path, output)
+    })).collect();
+    let mut failed = Vec::new();
+    for run in runs {
+        let (path, output) = run.join().expect("check thread finishes");
+        if !output.status.success() {
+            failed.push(format!("{}:\n{}{}", path.display(), String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)));
+        }
+    }
+    assert!(failed.is_empty(), "{} failed on\n{}", option, failed.join("\n"));
+}
+
+//every program translated to Rust and built with rustc prints and fails like the interpreter
+#[test]
+fn translations_match() {
+    if !Command::new("rustc").arg("--version").output().is_ok_and(|output| output.status.success()) {
+        eprintln!("rustc not found, skipping the translation check");
+        return;
+    }
+    check_all("--aot-test");
+}
+
+//every program optimized prints the same and fails at the same original instruction
+#[test]
+fn optimizations_match() {
+    check_all("--optimize-test");
+}
+
+//what smallvm printed running `args`, without the thread ids in panic messages
+fn run(args: &[&std::ffi::OsStr]) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_smallvm")).args(args).output().expect("smallvm runs");
+    let stderr = String::from_utf8_lossy(&output.stderr).lines().filter(|line| !line.contains(" panicked at ")).collect::<Vec<_>>().join("\n");
+    (String::from_utf8_lossy(&output.stdout).into_owned(), stderr)
+}
+
+//every program written to a program file traces, pauses and fails at the same source lines as
+//its source
+#[test]
+fn program_files_keep_debug_info() {
+    let dir = std::env::temp_dir().join(format!("smallvm-emit-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("temporary directory is writable");
+    let runs: Vec<_> = programs().into_iter().map(|path| {
+        let file = dir.join(path.file_stem().expect("programs have names")).with_extension("svm");
+        thread::spawn(move || {
+            let emitted = Command::new(env!("CARGO_BIN_EXE_smallvm")).arg("--emit").arg(&file).arg(&path).status().expect("smallvm runs");
+            assert!(emitted.success(), "{} wasn't written", path.display());
+            let pause = ["--pause-after".as_ref(), "1000".as_ref()];
+            assert_eq!(run(&[&pause[..], &[file.as_os_str()]].concat()), run(&[&pause[..], &[path.as_os_str()]].concat()), "{}", path.display());
+        })
+    }).collect();
+    let results: Vec<_> = runs.into_iter().map(|run| run.join()).collect();
+    let _ = fs::remove_dir_all(&dir);
+    assert!(results.iter().all(Result::is_ok), "a program file ran differently from its source");
+}